@@ -0,0 +1,186 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::tree::{Node, TreeNode};
+
+/// A way of scoring trees, for choosing between equivalent forms of one. Lower costs are better.
+///
+/// Costs are built up from the leaves, so a model only says what a subtree costs given the costs of its root's
+/// children. That lets the e-graph find the cheapest term of a class without trying every term, as long as a subtree
+/// never costs less than any of its children.
+pub trait CostModel<L: TreeNode, I: TreeNode> {
+
+    /// The cost of the subtree rooted at `node`, whose children cost `children`.
+    fn node_cost(&self, node: &Node<L, I>, children: &[usize]) -> usize;
+
+    /// The cost of the tree whose tokens are `tokens`.
+    fn cost(&self, tokens: &[Node<L, I>]) -> usize {
+        let mut costs: Vec<usize> = vec![];
+        // Children are costed before their parents, so work back from the last token
+        for token in tokens.iter().rev() {
+            let children: Vec<usize> = costs.split_off(costs.len().saturating_sub(token.arity())).into_iter().rev().collect();
+            costs.push(self.node_cost(token, &children));
+        }
+        costs.last().copied().unwrap_or(0)
+    }
+
+    /// The order of two trees of equal cost, where `Less` prefers `a`. All trees of equal cost are equally good unless
+    /// a model says otherwise.
+    fn tie_break(&self, _a: &[Node<L, I>], _b: &[Node<L, I>]) -> Ordering {
+        Ordering::Equal
+    }
+
+    /// The order of two trees by cost, and then by [`CostModel::tie_break`].
+    fn compare(&self, a: &[Node<L, I>], b: &[Node<L, I>]) -> Ordering {
+        self.cost(a).cmp(&self.cost(b)).then_with(|| self.tie_break(a, b))
+    }
+}
+
+/// Costs a tree by its number of nodes, the same as [`crate::Tree::simplicity`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NodeCount;
+
+impl<L: TreeNode, I: TreeNode> CostModel<L, I> for NodeCount {
+    fn node_cost(&self, _node: &Node<L, I>, children: &[usize]) -> usize {
+        children.iter().fold(1, |total, child| total.saturating_add(*child))
+    }
+}
+
+/// Costs a tree by its depth, so that flatter trees are preferred. A lone leaf has a depth of one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Depth;
+
+impl<L: TreeNode, I: TreeNode> CostModel<L, I> for Depth {
+    fn node_cost(&self, _node: &Node<L, I>, children: &[usize]) -> usize {
+        children.iter().max().map_or(1, |deepest| deepest.saturating_add(1))
+    }
+}
+
+/// Costs a tree by the total weight of its nodes, where each operator and leaf can be given its own weight.
+///
+/// Operators are identified by their `to_string` representation and leaves by their uid. Anything without a weight of
+/// its own weighs the default for its kind, which starts off as one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Weighted {
+    operators: HashMap<String, usize>,
+    leaves: HashMap<String, usize>,
+    default_operator: usize,
+    default_leaf: usize,
+}
+
+impl Default for Weighted {
+    fn default() -> Self {
+        Self {
+            operators: HashMap::new(),
+            leaves: HashMap::new(),
+            default_operator: 1,
+            default_leaf: 1,
+        }
+    }
+}
+
+impl Weighted {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn operator<I: TreeNode>(mut self, operator: &I, weight: usize) -> Self {
+        self.operators.insert(operator.to_string(), weight);
+        self
+    }
+
+    pub fn leaf<L: TreeNode>(mut self, leaf: &L, weight: usize) -> Self {
+        self.leaves.insert(leaf.uid(), weight);
+        self
+    }
+
+    /// The weight of every operator not given one of its own.
+    pub fn default_operator(mut self, weight: usize) -> Self {
+        self.default_operator = weight;
+        self
+    }
+
+    /// The weight of every leaf not given one of its own.
+    pub fn default_leaf(mut self, weight: usize) -> Self {
+        self.default_leaf = weight;
+        self
+    }
+
+    fn weight<L: TreeNode, I: TreeNode>(&self, node: &Node<L, I>) -> usize {
+        match node {
+            Node::Leaf(l) => self.leaves.get(&l.uid()).copied().unwrap_or(self.default_leaf),
+            Node::Internal(o) => self.operators.get(&o.to_string()).copied().unwrap_or(self.default_operator),
+        }
+    }
+}
+
+impl<L: TreeNode, I: TreeNode> CostModel<L, I> for Weighted {
+    fn node_cost(&self, node: &Node<L, I>, children: &[usize]) -> usize {
+        children.iter().fold(self.weight(node), |total, child| total.saturating_add(*child))
+    }
+}
+
+/// Costs trees as the model it wraps does, but breaks ties by preferring the tree whose labels, read in pre-order,
+/// come first alphabetically.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Alphabetical<C>(pub C);
+
+impl<L: TreeNode, I: TreeNode, C: CostModel<L, I>> CostModel<L, I> for Alphabetical<C> {
+    fn node_cost(&self, node: &Node<L, I>, children: &[usize]) -> usize {
+        self.0.node_cost(node, children)
+    }
+
+    fn tie_break(&self, a: &[Node<L, I>], b: &[Node<L, I>]) -> Ordering {
+        let label = |token: &Node<L, I>| match token {
+            Node::Leaf(l) => l.uid(),
+            Node::Internal(o) => o.to_string(),
+        };
+        self.0.tie_break(a, b).then_with(|| a.iter().map(label).cmp(b.iter().map(label)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cmp::Ordering;
+
+    use crate::{Tree, Node};
+    use crate::test_utils::{Equation, Element, Operator, leaf};
+
+    use super::{Alphabetical, CostModel, Depth, NodeCount, Weighted};
+
+    #[test]
+    fn test_cost_models() {
+        // a*b + a*c and a*(b + c)
+        let expanded = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b"),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("c"),
+        ]);
+        let factored = Equation::new(vec![
+            Node::Internal(Operator::MULTIPLY), leaf("a"),
+            Node::Internal(Operator::ADD), leaf("b"), leaf("c"),
+        ]);
+
+        assert_eq!(NodeCount.cost(expanded.tokens()), expanded.simplicity());
+        assert_eq!(NodeCount.cost(factored.tokens()), 5);
+        assert_eq!(Depth.cost(expanded.tokens()), 3);
+        assert_eq!(Depth.cost(&[leaf("a")]), 1);
+        assert_eq!(NodeCount.compare(factored.tokens(), expanded.tokens()), Ordering::Less);
+
+        let weighted = Weighted::new()
+            .operator(&Operator::MULTIPLY, 10)
+            .leaf(&Element { label: b"a".to_vec() }, 0)
+            .default_leaf(2);
+        assert_eq!(weighted.cost(expanded.tokens()), 1 + 10 + 2 + 10 + 2);
+        assert_eq!(weighted.cost(factored.tokens()), 10 + 1 + 2 + 2);
+
+        // b + a and a + b cost the same, but only one comes first alphabetically
+        let forwards = [Node::Internal(Operator::ADD), leaf("a"), leaf("b")];
+        let backwards = [Node::Internal(Operator::ADD), leaf("b"), leaf("a")];
+        assert_eq!(NodeCount.compare(&backwards, &forwards), Ordering::Equal);
+        assert_eq!(Alphabetical(NodeCount).compare(&backwards, &forwards), Ordering::Greater);
+        assert_eq!(Alphabetical(NodeCount).compare(&forwards, &forwards), Ordering::Equal);
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::HashSet;
+
+use crate::hash_cons::HashCons;
+use crate::match_pattern::Rule;
+use crate::tree::{Node, Tree, TreeNode, TreeParseError, same_token};
+
+/// A single step of an edit script turning one tree into another. Nodes are given by their token index in the tree
+/// they belong to, before any edits are made.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edit {
+    /// Remove the node at this token of the first tree, putting its children in its place
+    Delete(usize),
+    /// Add the node at this token of the second tree
+    Insert(usize),
+    /// Replace the node at the first token of the first tree with the node at the second token of the second tree
+    Relabel(usize, usize),
+}
+
+/// The nodes of a tree in post-order, as Zhang and Shasha's algorithm needs them. Positions count from 1, leaving 0 to
+/// stand for the empty forest.
+struct PostOrder {
+    /// The token index of the node at each position
+    tokens: Vec<usize>,
+    /// The position of the leftmost leaf below the node at each position
+    leftmost: Vec<usize>,
+    /// The positions of the nodes with no left sibling on their path to the root, bar the root itself, in order
+    keyroots: Vec<usize>,
+}
+
+impl PostOrder {
+
+    fn new<L: TreeNode, I: TreeNode>(tokens: &[Node<L, I>]) -> Self {
+        let mut order = vec![0];
+        let mut positions = vec![0; tokens.len()];
+        // Open nodes and the number of children they are still waiting for
+        let mut open: Vec<(usize, usize)> = vec![];
+
+        for (index, token) in tokens.iter().enumerate() {
+            open.push((index, token.arity()));
+            while let Some(&(done, 0)) = open.last() {
+                open.pop();
+                positions[done] = order.len();
+                order.push(done);
+                if let Some((_, remaining)) = open.last_mut() {
+                    *remaining -= 1;
+                }
+            }
+        }
+
+        // The first child of a node is always the token after it
+        let leftmost: Vec<usize> = order.iter()
+            .map(|&index| {
+                let mut leaf = index;
+                while tokens[leaf].arity() > 0 {
+                    leaf += 1;
+                }
+                positions[leaf]
+            })
+            .collect();
+
+        let mut seen = HashSet::new();
+        let mut keyroots: Vec<usize> = (1..order.len()).rev().filter(|&p| seen.insert(leftmost[p])).collect();
+        keyroots.reverse();
+
+        Self {
+            tokens: order,
+            leftmost,
+            keyroots,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.tokens.len() - 1
+    }
+}
+
+/// Zhang and Shasha's tree edit distance, with every insertion, deletion and relabelling costing one.
+struct ZhangShasha<'t, L: TreeNode, I: TreeNode> {
+    a: &'t [Node<L, I>],
+    b: &'t [Node<L, I>],
+    a_order: PostOrder,
+    b_order: PostOrder,
+    /// The distance between the subtrees at each pair of positions
+    distances: Vec<Vec<usize>>,
+}
+
+impl<'t, L: TreeNode, I: TreeNode> ZhangShasha<'t, L, I> {
+
+    fn new(a: &'t [Node<L, I>], b: &'t [Node<L, I>]) -> Self {
+        let a_order = PostOrder::new(a);
+        let b_order = PostOrder::new(b);
+        let distances = vec![vec![0; b_order.len() + 1]; a_order.len() + 1];
+
+        let mut zhang_shasha = Self { a, b, a_order, b_order, distances };
+        for i in zhang_shasha.a_order.keyroots.clone() {
+            for j in zhang_shasha.b_order.keyroots.clone() {
+                zhang_shasha.forest_distances(i, j);
+            }
+        }
+        zhang_shasha
+    }
+
+    fn distance(&self) -> usize {
+        self.distances[self.a_order.len()][self.b_order.len()]
+    }
+
+    fn relabel_cost(&self, i: usize, j: usize) -> usize {
+        match same_token(&self.a[self.a_order.tokens[i]], &self.b[self.b_order.tokens[j]]) {
+            true => 0,
+            false => 1,
+        }
+    }
+
+    /// The distances between every prefix of the forests under `i` and `j`, each starting from its leftmost leaf, and
+    /// record the distance between each pair of subtrees along the way whose leftmost leaves are those of `i` and `j`.
+    fn forest_distances(&mut self, i: usize, j: usize) -> Vec<Vec<usize>> {
+        let (i_offset, j_offset) = (self.a_order.leftmost[i] - 1, self.b_order.leftmost[j] - 1);
+        let (rows, columns) = (i - i_offset, j - j_offset);
+
+        let mut forest = vec![vec![0; columns + 1]; rows + 1];
+        for (x, row) in forest.iter_mut().enumerate() {
+            row[0] = x;
+        }
+        for (y, distance) in forest[0].iter_mut().enumerate() {
+            *distance = y;
+        }
+
+        for x in 1..=rows {
+            for y in 1..=columns {
+                let (i1, j1) = (x + i_offset, y + j_offset);
+                let edit = (forest[x - 1][y] + 1).min(forest[x][y - 1] + 1);
+
+                if self.a_order.leftmost[i1] == self.a_order.leftmost[i] && self.b_order.leftmost[j1] == self.b_order.leftmost[j] {
+                    forest[x][y] = edit.min(forest[x - 1][y - 1] + self.relabel_cost(i1, j1));
+                    self.distances[i1][j1] = forest[x][y];
+                } else {
+                    let (p, q) = (self.a_order.leftmost[i1] - 1 - i_offset, self.b_order.leftmost[j1] - 1 - j_offset);
+                    forest[x][y] = edit.min(forest[p][q] + self.distances[i1][j1]);
+                }
+            }
+        }
+        forest
+    }
+
+    /// Work back through the forest distances under `i` and `j` to find the edits making up their distance.
+    fn script(&mut self, i: usize, j: usize, edits: &mut Vec<Edit>) {
+        let forest = self.forest_distances(i, j);
+        let (i_offset, j_offset) = (self.a_order.leftmost[i] - 1, self.b_order.leftmost[j] - 1);
+        let (mut x, mut y) = (i - i_offset, j - j_offset);
+
+        while x > 0 || y > 0 {
+            if x > 0 && y > 0 {
+                let (i1, j1) = (x + i_offset, y + j_offset);
+                if self.a_order.leftmost[i1] == self.a_order.leftmost[i] && self.b_order.leftmost[j1] == self.b_order.leftmost[j] {
+                    let cost = self.relabel_cost(i1, j1);
+                    if forest[x][y] == forest[x - 1][y - 1] + cost {
+                        if cost > 0 {
+                            edits.push(Edit::Relabel(self.a_order.tokens[i1], self.b_order.tokens[j1]));
+                        }
+                        x -= 1;
+                        y -= 1;
+                        continue
+                    }
+                } else {
+                    let (p, q) = (self.a_order.leftmost[i1] - 1 - i_offset, self.b_order.leftmost[j1] - 1 - j_offset);
+                    if forest[x][y] == forest[p][q] + self.distances[i1][j1] {
+                        self.script(i1, j1, edits);
+                        x = p;
+                        y = q;
+                        continue
+                    }
+                }
+            }
+
+            if x > 0 && forest[x][y] == forest[x - 1][y] + 1 {
+                edits.push(Edit::Delete(self.a_order.tokens[x + i_offset]));
+                x -= 1;
+            } else {
+                edits.push(Edit::Insert(self.b_order.tokens[y + j_offset]));
+                y -= 1;
+            }
+        }
+    }
+}
+
+/// The least number of node insertions, deletions and relabellings which turn `a` into `b`.
+pub fn edit_distance<T: Tree>(a: &T, b: &T) -> Result<usize, TreeParseError> {
+    a.validate()?;
+    b.validate()?;
+    Ok(ZhangShasha::new(a.tokens(), b.tokens()).distance())
+}
+
+/// A shortest edit script turning `a` into `b`, whose length is their [`edit_distance`].
+///
+/// Deletions and relabellings come first, in pre-order of `a`, followed by insertions in pre-order of `b`. Nodes which
+/// are kept as they are don't appear.
+pub fn edit_script<T: Tree>(a: &T, b: &T) -> Result<Vec<Edit>, TreeParseError> {
+    a.validate()?;
+    b.validate()?;
+    let mut zhang_shasha = ZhangShasha::new(a.tokens(), b.tokens());
+    let (i, j) = (zhang_shasha.a_order.len(), zhang_shasha.b_order.len());
+
+    let mut edits = vec![];
+    zhang_shasha.script(i, j, &mut edits);
+    edits.sort_by_key(|edit| match edit {
+        Edit::Delete(index) | Edit::Relabel(index, _) => (0, *index),
+        Edit::Insert(index) => (1, *index),
+    });
+    Ok(edits)
+}
+
+/// The token indices of the smallest pair of corresponding subtrees of `a` and `b` which hold every difference between
+/// them, or `None` if the trees are equal.
+///
+/// Starting from the roots, the search moves down into a pair of children for as long as the nodes agree and only
+/// that one pair of their children differs.
+pub fn differing_subtrees<T: Tree>(a: &T, b: &T) -> Result<Option<(usize, usize)>, TreeParseError> {
+    let mut terms = HashCons::new();
+    let a_ids = terms.intern_subtrees(a)?;
+    let b_ids = terms.intern_subtrees(b)?;
+    if a_ids[0] == b_ids[0] {
+        return Ok(None);
+    }
+
+    let (mut i, mut j) = (0, 0);
+    loop {
+        let (a_node, b_node) = (&a.tokens()[i], &b.tokens()[j]);
+        if !same_token(a_node, b_node) || a_node.arity() != b_node.arity() {
+            return Ok(Some((i, j)));
+        }
+
+        // Both trees are valid, so every node has its children
+        let a_children = a.children(i).unwrap_or_default();
+        let b_children = b.children(j).unwrap_or_default();
+        let mut differing = a_children.into_iter().zip(b_children).filter(|(x, y)| a_ids[*x] != b_ids[*y]);
+        match (differing.next(), differing.next()) {
+            (Some((x, y)), None) => (i, j) = (x, y),
+            _ => return Ok(Some((i, j))),
+        }
+    }
+}
+
+/// The rules which rewrite `a` into `b` in a single step, as a hint for which identity could bridge the two.
+///
+/// Rules are only tried at the smallest subtree of `a` holding every difference from `b`, found by
+/// [`differing_subtrees`], and at its ancestors, which a rewrite modulo associativity may need.
+pub fn bridging_rules<'r, L: TreeNode + Clone, I: TreeNode + Clone, T: Tree<L=L, I=I>>(
+        a: &T,
+        b: &T,
+        rules: &'r [Rule<L, I>],
+    ) -> Result<Vec<&'r Rule<L, I>>, TreeParseError> {
+
+    let Some((mut index, _)) = differing_subtrees(a, b)? else {
+        return Ok(vec![]);
+    };
+    let mut starts = vec![index];
+    while let Some(parent) = a.parent(index) {
+        starts.push(parent);
+        index = parent;
+    }
+
+    let mut terms = HashCons::new();
+    let target = terms.intern(b)?;
+    Ok(rules.iter()
+        .filter(|rule| starts.iter().any(|start| {
+            rule.rewrites(a.tokens(), Some(*start))
+                .any(|(_, tokens)| terms.intern_tokens(&tokens).and_then(|ids| ids.first().copied()) == Some(target))
+        }))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{Tree, Node, Rule};
+    use crate::test_utils::{Equation, Operator, Identity, leaf, wildcard, literal};
+
+    use super::{bridging_rules, differing_subtrees, edit_distance, edit_script, Edit};
+
+    fn leaf_tree(label: &str) -> Equation {
+        Equation::new(vec![leaf(label)])
+    }
+
+    #[test]
+    fn test_edit_distance_and_script() {
+        // a * (b + c)
+        let a = Equation::new(vec![
+            Node::Internal(Operator::MULTIPLY), leaf("a"),
+            Node::Internal(Operator::ADD), leaf("b"), leaf("c"),
+        ]);
+        // a * (b - inv(d))
+        let b = Equation::new(vec![
+            Node::Internal(Operator::MULTIPLY), leaf("a"),
+            Node::Internal(Operator::SUBTRACT), leaf("b"), Node::Internal(Operator::INVERSE), leaf("d"),
+        ]);
+
+        assert_eq!(edit_distance(&a, &a), Ok(0));
+        assert_eq!(edit_script(&a, &a), Ok(vec![]));
+
+        assert_eq!(edit_distance(&a, &b), Ok(3));
+        assert_eq!(edit_distance(&b, &a), Ok(3));
+        assert_eq!(edit_script(&a, &b), Ok(vec![Edit::Relabel(2, 2), Edit::Relabel(4, 4), Edit::Insert(5)]));
+        assert_eq!(edit_script(&b, &a), Ok(vec![Edit::Relabel(2, 2), Edit::Relabel(4, 4), Edit::Delete(5)]));
+
+        // Dropping a whole subtree deletes each of its nodes
+        let product = Equation::new(vec![Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("c")]);
+        assert_eq!(edit_script(&a, &product), Ok(vec![Edit::Delete(2), Edit::Delete(3)]));
+        assert_eq!(edit_distance(&leaf_tree("a"), &a), Ok(4));
+
+        let truncated = Equation::new(vec![Node::Internal(Operator::ADD), leaf("a")]);
+        assert!(edit_distance(&a, &truncated).is_err());
+    }
+
+    #[test]
+    fn test_differing_subtrees_and_bridging_rules() {
+        // (a * b) + (c * d) and (a * b) + (d * c)
+        let a = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b"),
+            Node::Internal(Operator::MULTIPLY), leaf("c"), leaf("d"),
+        ]);
+        let b = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b"),
+            Node::Internal(Operator::MULTIPLY), leaf("d"), leaf("c"),
+        ]);
+
+        assert_eq!(differing_subtrees(&a, &a), Ok(None));
+        assert_eq!(differing_subtrees(&a, &b), Ok(Some((4, 4))));
+        assert_eq!(differing_subtrees(&a, &leaf_tree("a")), Ok(Some((0, 0))));
+
+        let commutativity = Rule::compile(
+            Identity::new(vec![Node::Internal(Operator::MULTIPLY), wildcard("x"), wildcard("y")]),
+            Identity::new(vec![Node::Internal(Operator::MULTIPLY), wildcard("y"), wildcard("x")]),
+        ).unwrap();
+        let doubling = Rule::compile(
+            Identity::new(vec![Node::Internal(Operator::MULTIPLY), wildcard("x"), wildcard("y")]),
+            Identity::new(vec![Node::Internal(Operator::ADD), wildcard("x"), wildcard("x")]),
+        ).unwrap();
+        // Only reaches b when applied at an ancestor of the differing subtree
+        let swap_right = Rule::compile(
+            Identity::new(vec![Node::Internal(Operator::ADD), wildcard("x"), wildcard("y")]),
+            Identity::new(vec![
+                Node::Internal(Operator::ADD), wildcard("x"), Node::Internal(Operator::MULTIPLY), literal("d"), literal("c"),
+            ]),
+        ).unwrap();
+
+        let rules = [commutativity, doubling, swap_right];
+        let bridges = bridging_rules(&a, &b, &rules).unwrap();
+        assert_eq!(bridges.len(), 2);
+        assert!(std::ptr::eq(bridges[0], &rules[0]));
+        assert!(std::ptr::eq(bridges[1], &rules[2]));
+        assert!(bridging_rules(&a, &a, &rules).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+
+use crate::PatternLeaf;
+use crate::location::{Location, subtree_end};
+use crate::match_pattern::Rule;
+use crate::tree::{Node, Tree, TreeNode};
+
+/// A single step along a pattern in pre-order.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    /// A wildcard, which skips a whole subtree
+    Star,
+    /// A leaf wildcard, which skips a single leaf
+    AnyLeaf,
+    /// An operator wildcard, which skips only the label of an internal node with this arity
+    AnyInternal(usize),
+    Leaf(String),
+    /// An internal node's label and arity
+    Internal(String, usize),
+}
+
+#[derive(Clone, Debug)]
+struct Trie<V> {
+    children: HashMap<Key, Trie<V>>,
+    /// Values of the patterns ending here, with the order they were inserted in
+    values: Vec<(usize, V)>,
+}
+
+impl<V> Default for Trie<V> {
+    fn default() -> Self {
+        Self {
+            children: HashMap::new(),
+            values: vec![],
+        }
+    }
+}
+
+/// An index over many patterns, for finding which of them might match a tree without trying each one.
+///
+/// Patterns are stored in a trie keyed by their tokens in pre-order, with each kind of wildcard collapsed to one key, so
+/// a query walks the tree once for all the patterns at the same time. Candidates are a superset of the actual matches:
+/// repeated wildcards and guards aren't checked, so each candidate still has to be matched properly.
+#[derive(Clone, Debug)]
+pub struct DiscriminationTree<V> {
+    root: Trie<V>,
+    len: usize,
+}
+
+impl<V> Default for DiscriminationTree<V> {
+    fn default() -> Self {
+        Self {
+            root: Trie::default(),
+            len: 0,
+        }
+    }
+}
+
+fn pattern_key<L: TreeNode, I: TreeNode>(token: &Node<PatternLeaf<L>, I>) -> Key {
+    match token {
+        Node::Leaf(PatternLeaf::Subtree(_) | PatternLeaf::Sequence(_)) => Key::Star,
+        Node::Leaf(PatternLeaf::Leaf(_)) => Key::AnyLeaf,
+        Node::Leaf(PatternLeaf::Operator(_, arity)) => Key::AnyInternal(*arity),
+        Node::Leaf(PatternLeaf::Literal(l)) => Key::Leaf(l.uid()),
+        Node::Internal(o) => Key::Internal(o.to_string(), o.arity()),
+    }
+}
+
+fn tree_key<L: TreeNode, I: TreeNode>(token: &Node<L, I>) -> Key {
+    match token {
+        Node::Leaf(l) => Key::Leaf(l.uid()),
+        Node::Internal(o) => Key::Internal(o.to_string(), o.arity()),
+    }
+}
+
+impl<V> DiscriminationTree<V> {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of patterns in the index.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Index `pattern`, which is reported as `value` wherever it might match.
+    pub fn insert<L: TreeNode, I: TreeNode>(&mut self, pattern: &[Node<PatternLeaf<L>, I>], value: V) {
+        self.insert_keys(pattern.iter().map(pattern_key).collect(), value);
+    }
+
+    /// Index the pattern of `rule`.
+    ///
+    /// Modulo associativity and commutativity the children of the pattern's root can be rearranged, so only the root
+    /// is indexed when the rule's theory isn't syntactic. A sequence wildcard can take any number of arguments, so a
+    /// pattern with one is a candidate everywhere.
+    pub fn insert_rule<L: TreeNode + Clone, I: TreeNode + Clone>(&mut self, rule: &Rule<L, I>, value: V) {
+        let pattern = rule.pattern();
+        if pattern.iter().any(|token| matches!(token, Node::Leaf(PatternLeaf::Sequence(_)))) {
+            return self.insert_keys(vec![Key::Star], value);
+        }
+        if rule.theory().is_syntactic() {
+            return self.insert(pattern, value);
+        }
+
+        let mut keys = vec![];
+        if let Some(root) = pattern.first() {
+            keys.push(pattern_key(root));
+            keys.extend((0..root.arity()).map(|_| Key::Star));
+        }
+        self.insert_keys(keys, value);
+    }
+
+    fn insert_keys(&mut self, keys: Vec<Key>, value: V) {
+        let mut trie = &mut self.root;
+        for key in keys {
+            trie = trie.children.entry(key).or_default();
+        }
+        trie.values.push((self.len, value));
+        self.len += 1;
+    }
+
+    /// The values of every pattern which might match the subtree of `tree` at `location`, in the order they were
+    /// inserted.
+    pub fn candidates_at<T: Tree>(&self, tree: &T, location: &Location) -> Vec<&V> {
+        let tokens = tree.tokens();
+        let end = match subtree_end(tokens, location.index) {
+            Some(end) => end,
+            None => return vec![],
+        };
+
+        let mut found = vec![];
+        Self::walk(&self.root, tokens, location.index, end, &mut found);
+        found.sort_by_key(|(order, _)| *order);
+        found.into_iter().map(|(_, value)| value).collect()
+    }
+
+    /// The values of every pattern which might match somewhere in `tree`, alongside where. Candidates are ordered by
+    /// location in pre-order, then by the order they were inserted in.
+    pub fn candidates<T: Tree>(&self, tree: &T) -> Vec<(&V, Location)> {
+        let tokens = tree.tokens();
+
+        (0..tokens.len())
+            .filter_map(|index| Location::of_index(tokens, index))
+            .flat_map(|location| {
+                self.candidates_at(tree, &location).into_iter().map(move |value| (value, location.clone()))
+            })
+            .collect()
+    }
+
+    /// Follow every path through `trie` consistent with `tokens[cursor..end]`, collecting the values where both end.
+    fn walk<'t, L: TreeNode, I: TreeNode>(
+            trie: &'t Trie<V>,
+            tokens: &[Node<L, I>],
+            cursor: usize,
+            end: usize,
+            found: &mut Vec<(usize, &'t V)>,
+        ) {
+
+        if cursor == end {
+            found.extend(trie.values.iter().map(|(order, value)| (*order, value)));
+            return
+        }
+
+        if let Some(next) = trie.children.get(&tree_key(&tokens[cursor])) {
+            Self::walk(next, tokens, cursor + 1, end, found);
+        }
+        let any = match &tokens[cursor] {
+            Node::Leaf(_) => Key::AnyLeaf,
+            Node::Internal(o) => Key::AnyInternal(o.arity()),
+        };
+        if let Some(next) = trie.children.get(&any) {
+            Self::walk(next, tokens, cursor + 1, end, found);
+        }
+        if let (Some(next), Some(skipped)) = (trie.children.get(&Key::Star), subtree_end(tokens, cursor)) {
+            Self::walk(next, tokens, skipped, end, found);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{Tree, Node, PatternLeaf, Rule, Theory, Guard, Location};
+    use crate::test_utils::{Equation, Element, Operator, Identity, leaf, wildcard, literal};
+
+    use super::DiscriminationTree;
+
+    #[test]
+    fn test_candidates_are_exactly_the_linear_matches() {
+        // a*(b+c) + a*(b+c)
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), Node::Internal(Operator::ADD), leaf("b"), leaf("c"),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), Node::Internal(Operator::ADD), leaf("b"), leaf("c"),
+        ]);
+
+        let patterns = [
+            // x*(y+z)
+            vec![Node::Internal(Operator::MULTIPLY), wildcard("x"), Node::Internal(Operator::ADD), wildcard("y"), wildcard("z")],
+            // x+y
+            vec![Node::Internal(Operator::ADD), wildcard("x"), wildcard("y")],
+            // b
+            vec![literal("b")],
+            // x-y
+            vec![Node::Internal(Operator::SUBTRACT), wildcard("x"), wildcard("y")],
+            // x
+            vec![wildcard("x")],
+            // a*x
+            vec![Node::Internal(Operator::MULTIPLY), literal("a"), wildcard("x")],
+            // x o y, for any operator o
+            vec![Node::Leaf(PatternLeaf::Operator("o".to_string(), 2)), wildcard("x"), wildcard("y")],
+            // l*x, for any leaf l
+            vec![Node::Internal(Operator::MULTIPLY), Node::Leaf(PatternLeaf::Leaf("l".to_string())), wildcard("x")],
+        ];
+
+        let mut index = DiscriminationTree::new();
+        let rules: Vec<Rule<Element, Operator>> = patterns.iter().map(|pattern| {
+            Rule::compile(Identity::new(pattern.clone()), Identity::new(pattern.clone())).unwrap()
+        }).collect();
+        for (i, rule) in rules.iter().enumerate() {
+            index.insert_rule(rule, i);
+        }
+        assert_eq!(index.len(), patterns.len());
+
+        for (i, rule) in rules.iter().enumerate() {
+            let expected: Vec<Location> = rule.apply_all(&tree).unwrap().into_iter().map(|(_, location)| location).collect();
+            let found: Vec<Location> = index.candidates(&tree)
+                .into_iter()
+                .filter(|(value, _)| **value == i)
+                .map(|(_, location)| location)
+                .collect();
+            assert_eq!(found, expected);
+        }
+
+        let root = index.candidates_at(&tree, &Location::root());
+        assert_eq!(root, vec![&1, &4, &6]);
+        let first_product = Location::of_path(tree.tokens(), &[0]).unwrap();
+        assert_eq!(index.candidates_at(&tree, &first_product), vec![&0, &4, &5, &6, &7]);
+    }
+
+    #[test]
+    fn test_candidates_are_a_superset_modulo_theory() {
+        // c*(b*a)
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::MULTIPLY), leaf("c"),
+            Node::Internal(Operator::MULTIPLY), leaf("b"), leaf("a"),
+        ]);
+        // a*b*c, grouped the other way
+        let pattern = Identity::new(vec![
+            Node::Internal(Operator::MULTIPLY),
+            Node::Internal(Operator::MULTIPLY), literal("a"), literal("b"),
+            literal("c"),
+        ]);
+        let theory = Theory::new().associative(&Operator::MULTIPLY).commutative(&Operator::MULTIPLY);
+
+        let syntactic = Rule::compile(pattern.clone(), pattern.clone()).unwrap();
+        let ac = Rule::compile_with(pattern.clone(), pattern, theory, Guard::new()).unwrap();
+
+        let mut index = DiscriminationTree::new();
+        index.insert_rule(&syntactic, "syntactic");
+        index.insert_rule(&ac, "ac");
+
+        assert!(syntactic.apply_all(&tree).unwrap().is_empty());
+        assert_eq!(ac.apply_all(&tree).unwrap().first().map(|(_, location)| location.clone()), Some(Location::root()));
+
+        let candidates = index.candidates(&tree);
+        assert!(candidates.iter().all(|(value, _)| **value == "ac"));
+        assert!(candidates.iter().any(|(_, location)| *location == Location::root()));
+    }
+}
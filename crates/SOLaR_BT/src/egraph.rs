@@ -0,0 +1,663 @@
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Entry;
+
+use crate::PatternLeaf;
+use crate::cost::{CostModel, NodeCount};
+use crate::guard::Substitution;
+use crate::match_pattern::{ReplacementError, Rule};
+use crate::tree::{Node, Tree, TreeNode, TreeParseError};
+
+/// The identifier of an equivalence class in an [`EGraph`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Id(usize);
+
+/// A single node of the e-graph, whose children are classes rather than trees.
+#[derive(Clone, Debug)]
+struct ENode<L: TreeNode, I: TreeNode> {
+    node: Node<L, I>,
+    children: Vec<Id>,
+}
+
+/// What makes two e-nodes the same: their label and their (canonical) children.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Leaf(String),
+    Internal(String, Vec<Id>),
+}
+
+/// Limits on how long [`EGraph::run`] keeps rewriting for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// The most rounds of applying every rule at every class
+    pub iterations: usize,
+    /// The most e-nodes the graph may grow to
+    pub nodes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            iterations: 30,
+            nodes: 10_000,
+        }
+    }
+}
+
+/// Why [`EGraph::run`] stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// No rule could add anything new, so every equivalent term reachable with the rules is in the graph
+    Saturated,
+    IterationLimit,
+    NodeLimit,
+}
+
+/// What a wildcard is bound to: a class, or for an operator wildcard the operator itself.
+#[derive(Clone, Debug)]
+enum Bound<I> {
+    Class(Id),
+    Operator(I),
+}
+
+/// Wildcard names from a rule's pattern, paired with what they were bound to.
+type EBindings<'p, I> = Vec<(&'p str, Bound<I>)>;
+
+/// An equality saturation engine.
+///
+/// Trees are added to the graph, and rules are applied by adding their right hand side to the class of whatever their
+/// left hand side matched, so no term is ever lost. Once the graph is saturated, or the limits are hit, the cheapest
+/// term equivalent to a tree can be extracted.
+///
+/// Matching in the e-graph is syntactic, so rules' theories aren't used. Add associativity and commutativity as rules
+/// instead: the graph holds every form they produce at once, so they can't loop. Guards are checked against the
+/// smallest term of each bound class.
+#[derive(Clone, Debug)]
+pub struct EGraph<L: TreeNode, I: TreeNode> {
+    /// Union-find over class ids
+    unions: Vec<Id>,
+    /// The nodes of each class, keyed by canonical id
+    classes: BTreeMap<Id, Vec<ENode<L, I>>>,
+    /// The class of every node, keyed by its canonical form
+    memo: HashMap<Key, Id>,
+}
+
+impl<L: TreeNode, I: TreeNode> Default for EGraph<L, I> {
+    fn default() -> Self {
+        Self {
+            unions: vec![],
+            classes: BTreeMap::new(),
+            memo: HashMap::new(),
+        }
+    }
+}
+
+/// A cost model summing a weight per node.
+struct Weights<F>(F);
+
+impl<L: TreeNode, I: TreeNode, F: Fn(&Node<L, I>) -> usize> CostModel<L, I> for Weights<F> {
+    fn node_cost(&self, node: &Node<L, I>, children: &[usize]) -> usize {
+        children.iter().fold((self.0)(node), |total, child| total.saturating_add(*child))
+    }
+}
+
+fn key<L: TreeNode, I: TreeNode>(node: &Node<L, I>, children: Vec<Id>) -> Key {
+    match node {
+        Node::Leaf(l) => Key::Leaf(l.uid()),
+        Node::Internal(o) => Key::Internal(o.to_string(), children),
+    }
+}
+
+impl<L: TreeNode + Clone, I: TreeNode + Clone> EGraph<L, I> {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The canonical id of the class `id` belongs to.
+    pub fn find(&self, mut id: Id) -> Id {
+        while self.unions[id.0] != id {
+            id = self.unions[id.0];
+        }
+        id
+    }
+
+    /// The number of distinct e-nodes in the graph.
+    pub fn node_count(&self) -> usize {
+        self.memo.len()
+    }
+
+    /// The number of equivalence classes in the graph.
+    pub fn class_count(&self) -> usize {
+        self.classes.len()
+    }
+
+    /// Add `tree` to the graph, returning the class of its root.
+    pub fn add_tree<T: Tree<L=L, I=I>>(&mut self, tree: &T) -> Result<Id, TreeParseError> {
+        tree.validate()?;
+        let mut ids = vec![];
+        // Children are added before their parents, so work back from the last token
+        for token in tree.tokens().iter().rev() {
+            let children = match token {
+                Node::Internal(o) => ids.split_off(ids.len() - o.arity()).into_iter().rev().collect(),
+                Node::Leaf(_) => vec![],
+            };
+            ids.push(self.add(token.clone(), children));
+        }
+        Ok(ids[0])
+    }
+
+    /// The class of `tree`, if it's in the graph.
+    pub fn lookup<T: Tree<L=L, I=I>>(&self, tree: &T) -> Option<Id> {
+        tree.validate().ok()?;
+        let mut ids = vec![];
+        for token in tree.tokens().iter().rev() {
+            let children = match token {
+                Node::Internal(o) => ids.split_off(ids.len() - o.arity()).into_iter().rev().collect(),
+                Node::Leaf(_) => vec![],
+            };
+            ids.push(self.find(*self.memo.get(&key(token, children))?));
+        }
+        ids.pop()
+    }
+
+    /// True if `a` and `b` have been shown to be equivalent.
+    pub fn equivalent(&self, a: Id, b: Id) -> bool {
+        self.find(a) == self.find(b)
+    }
+
+    fn add(&mut self, node: Node<L, I>, children: Vec<Id>) -> Id {
+        let children: Vec<Id> = children.into_iter().map(|child| self.find(child)).collect();
+        let key = key(&node, children.clone());
+        if let Some(id) = self.memo.get(&key) {
+            return self.find(*id);
+        }
+
+        let id = Id(self.unions.len());
+        self.unions.push(id);
+        self.classes.insert(id, vec![ENode { node, children }]);
+        self.memo.insert(key, id);
+        id
+    }
+
+    /// Merge the classes of `a` and `b`, returning false if they were already the same class.
+    ///
+    /// Call [`EGraph::rebuild`] afterwards to merge any parents which have become equivalent as a result.
+    pub fn union(&mut self, a: Id, b: Id) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+
+        // Keep the lower id as the root, so class ids are stable regardless of the order of unions
+        let (root, other) = if a < b { (a, b) } else { (b, a) };
+        self.unions[other.0] = root;
+        let mut nodes = self.classes.remove(&other).unwrap_or_default();
+        if let Some(class) = self.classes.get_mut(&root) {
+            class.append(&mut nodes);
+        }
+        true
+    }
+
+    /// Restore congruence after unions: nodes whose children are now equivalent are merged, repeatedly until nothing
+    /// changes.
+    pub fn rebuild(&mut self) {
+        loop {
+            let mut memo: HashMap<Key, Id> = HashMap::new();
+            let mut merges = vec![];
+
+            for (id, nodes) in self.classes.iter() {
+                for enode in nodes {
+                    let children = enode.children.iter().map(|child| self.find(*child)).collect();
+                    match memo.entry(key(&enode.node, children)) {
+                        Entry::Occupied(existing) if existing.get() != id => merges.push((*existing.get(), *id)),
+                        Entry::Occupied(_) => {},
+                        Entry::Vacant(entry) => {
+                            entry.insert(*id);
+                        },
+                    }
+                }
+            }
+
+            if merges.is_empty() {
+                self.memo = memo;
+                break
+            }
+            for (a, b) in merges {
+                self.union(a, b);
+            }
+        }
+
+        // Canonicalise the children of every node and drop the duplicates
+        let unions = &self.unions;
+        let find = |mut id: Id| {
+            while unions[id.0] != id {
+                id = unions[id.0];
+            }
+            id
+        };
+        for nodes in self.classes.values_mut() {
+            let mut seen = vec![];
+            nodes.retain_mut(|enode| {
+                enode.children.iter_mut().for_each(|child| *child = find(*child));
+                let key = key(&enode.node, enode.children.clone());
+                if seen.contains(&key) {
+                    return false;
+                }
+                seen.push(key);
+                true
+            });
+        }
+    }
+
+    /// Apply `rules` until the graph is saturated or `limits` are hit.
+    ///
+    /// Fails with [`ReplacementError::UnsupportedSequence`] before rewriting anything if a rule's pattern has a sequence
+    /// wildcard.
+    pub fn run(&mut self, rules: &[Rule<L, I>], limits: &Limits) -> Result<StopReason, ReplacementError> {
+        let sequence = rules.iter().flat_map(|rule| rule.pattern()).find_map(|token| match token {
+            Node::Leaf(PatternLeaf::Sequence(name)) => Some(name),
+            _ => None,
+        });
+        if let Some(name) = sequence {
+            return Err(ReplacementError::UnsupportedSequence(name.clone()));
+        }
+
+        for _ in 0..limits.iterations {
+            let before = (self.node_count(), self.class_count());
+
+            // Find every match before changing anything, so that each round sees the same graph
+            let mut matches = vec![];
+            for rule in rules {
+                for class in self.classes.keys() {
+                    for bindings in self.ematch(rule.pattern(), *class) {
+                        if self.guard_holds(rule, &bindings) {
+                            matches.push((rule, *class, bindings));
+                        }
+                    }
+                }
+            }
+
+            for (rule, class, bindings) in matches {
+                if let Some(id) = self.instantiate(rule.replacement(), &bindings) {
+                    self.union(class, id);
+                }
+                if self.node_count() > limits.nodes {
+                    self.rebuild();
+                    return Ok(StopReason::NodeLimit);
+                }
+            }
+            self.rebuild();
+
+            if (self.node_count(), self.class_count()) == before {
+                return Ok(StopReason::Saturated);
+            }
+        }
+        Ok(StopReason::IterationLimit)
+    }
+
+    /// Every way of matching `pattern` against a term in `class`.
+    fn ematch<'p>(&self, pattern: &'p [Node<PatternLeaf<L>, I>], class: Id) -> Vec<EBindings<'p, I>> {
+        self.ematch_at(pattern, 0, class, vec![])
+            .into_iter()
+            .map(|(_, bindings)| bindings)
+            .collect()
+    }
+
+    /// Match the subpattern starting at token `at` against `class`, extending `bindings`. Each match is returned with
+    /// the end of the subpattern.
+    fn ematch_at<'p>(
+            &self,
+            pattern: &'p [Node<PatternLeaf<L>, I>],
+            at: usize,
+            class: Id,
+            bindings: EBindings<'p, I>,
+        ) -> Vec<(usize, EBindings<'p, I>)> {
+
+        let class = self.find(class);
+        let nodes = match self.classes.get(&class) {
+            Some(nodes) => nodes,
+            None => return vec![],
+        };
+
+        match &pattern[at] {
+            Node::Leaf(PatternLeaf::Subtree(name)) => self.bind_class(name, class, bindings).map(|b| (at + 1, b)).into_iter().collect(),
+            // Turned away by `run`, as nodes in the graph aren't flattened
+            Node::Leaf(PatternLeaf::Sequence(_)) => vec![],
+            Node::Leaf(PatternLeaf::Leaf(name)) => {
+                if !nodes.iter().any(|enode| matches!(enode.node, Node::Leaf(_))) {
+                    return vec![];
+                }
+                self.bind_class(name, class, bindings).map(|b| (at + 1, b)).into_iter().collect()
+            },
+            Node::Leaf(PatternLeaf::Literal(p)) => {
+                let found = nodes.iter().any(|enode| matches!(&enode.node, Node::Leaf(l) if l.uid() == p.uid()));
+                if found { vec![(at + 1, bindings)] } else { vec![] }
+            },
+            Node::Leaf(PatternLeaf::Operator(name, arity)) => {
+                let mut results = vec![];
+                for enode in nodes {
+                    let mut bindings = bindings.clone();
+                    match (&enode.node, bindings.iter().find(|(bound, _)| bound == name)) {
+                        (Node::Internal(o), _) if enode.children.len() != *arity || o.arity() != *arity => continue,
+                        (Node::Internal(o), Some((_, Bound::Operator(bound)))) if o.to_string() == bound.to_string() => {},
+                        (Node::Internal(o), None) => bindings.push((name, Bound::Operator(o.clone()))),
+                        _ => continue,
+                    }
+                    results.append(&mut self.ematch_children(pattern, at + 1, &enode.children, bindings));
+                }
+                results
+            },
+            Node::Internal(p) => {
+                let mut results = vec![];
+                for enode in nodes {
+                    match &enode.node {
+                        Node::Internal(o) if o.to_string() == p.to_string() && enode.children.len() == p.arity() => {},
+                        _ => continue,
+                    }
+                    results.append(&mut self.ematch_children(pattern, at + 1, &enode.children, bindings.clone()));
+                }
+                results
+            },
+        }
+    }
+
+    /// Bind the wildcard `name` to `class`, unless it's already bound to something else.
+    fn bind_class<'p>(&self, name: &'p str, class: Id, mut bindings: EBindings<'p, I>) -> Option<EBindings<'p, I>> {
+        match bindings.iter().find(|(bound, _)| *bound == name) {
+            Some((_, Bound::Class(bound))) if self.find(*bound) == class => Some(bindings),
+            Some(_) => None,
+            None => {
+                bindings.push((name, Bound::Class(class)));
+                Some(bindings)
+            },
+        }
+    }
+
+    /// Match the subpatterns starting at token `at` against each of `children` in turn.
+    fn ematch_children<'p>(
+            &self,
+            pattern: &'p [Node<PatternLeaf<L>, I>],
+            at: usize,
+            children: &[Id],
+            bindings: EBindings<'p, I>,
+        ) -> Vec<(usize, EBindings<'p, I>)> {
+
+        let mut states = vec![(at, bindings)];
+        for child in children {
+            states = states
+                .into_iter()
+                .flat_map(|(position, bindings)| self.ematch_at(pattern, position, *child, bindings))
+                .collect();
+        }
+        states
+    }
+
+    fn guard_holds(&self, rule: &Rule<L, I>, bindings: &EBindings<I>) -> bool {
+        if rule.guard().is_empty() {
+            return true;
+        }
+
+        let terms: Vec<(&str, Vec<Node<L, I>>)> = bindings
+            .iter()
+            .filter_map(|(name, bound)| match bound {
+                Bound::Class(class) => Some((*name, self.extract_tokens(*class, &NodeCount)?.0)),
+                Bound::Operator(o) => Some((*name, vec![Node::Internal(o.clone())])),
+            })
+            .collect();
+        rule.guard().holds(&Substitution::new(
+            terms.iter().map(|(name, tokens)| (*name, tokens.as_slice())).collect()
+        ))
+    }
+
+    /// Add the replacement with each wildcard standing for whatever it was bound to.
+    ///
+    /// Rules are checked when they're compiled, so every wildcard in the replacement should be bound.
+    fn instantiate(&mut self, replacement: &[Node<PatternLeaf<L>, I>], bindings: &EBindings<I>) -> Option<Id> {
+        let mut ids = vec![];
+        for token in replacement.iter().rev() {
+            let id = match token {
+                Node::Leaf(PatternLeaf::Subtree(name) | PatternLeaf::Leaf(name) | PatternLeaf::Sequence(name)) => match bindings.iter().find(|(bound, _)| bound == name)? {
+                    (_, Bound::Class(class)) => *class,
+                    (_, Bound::Operator(_)) => return None,
+                },
+                Node::Leaf(PatternLeaf::Operator(name, arity)) => match bindings.iter().find(|(bound, _)| bound == name)? {
+                    (_, Bound::Operator(o)) => {
+                        let children = ids.split_off(ids.len() - arity).into_iter().rev().collect();
+                        self.add(Node::Internal(o.clone()), children)
+                    },
+                    (_, Bound::Class(_)) => return None,
+                },
+                Node::Leaf(PatternLeaf::Literal(l)) => self.add(Node::Leaf(l.clone()), vec![]),
+                Node::Internal(o) => {
+                    let children = ids.split_off(ids.len() - o.arity()).into_iter().rev().collect();
+                    self.add(Node::Internal(o.clone()), children)
+                },
+            };
+            ids.push(id);
+        }
+        ids.pop()
+    }
+
+    /// The cheapest term in the class of `root`, where the cost of a term is the sum of `cost` over its nodes.
+    ///
+    /// Returns the term alongside its cost, or `None` if `root` isn't in the graph.
+    pub fn extract<T: Tree<L=L, I=I>>(&self, root: Id, cost: impl Fn(&Node<L, I>) -> usize) -> Option<(T, usize)> {
+        self.extract_with(root, &Weights(cost))
+    }
+
+    /// The cheapest term in the class of `root` by `model`. Ties between terms of equal cost aren't broken.
+    ///
+    /// Returns the term alongside its cost, or `None` if `root` isn't in the graph.
+    pub fn extract_with<T: Tree<L=L, I=I>>(&self, root: Id, model: &impl CostModel<L, I>) -> Option<(T, usize)> {
+        self.extract_tokens(root, model).map(|(tokens, cost)| (T::new(tokens), cost))
+    }
+
+    /// The term in the class of `root` with the fewest nodes, i.e. the best by [`Tree::simplicity`].
+    pub fn extract_simplest<T: Tree<L=L, I=I>>(&self, root: Id) -> Option<T> {
+        self.extract_with(root, &NodeCount).map(|(tree, _)| tree)
+    }
+
+    fn extract_tokens(&self, root: Id, model: &impl CostModel<L, I>) -> Option<(Vec<Node<L, I>>, usize)> {
+        if root.0 >= self.unions.len() {
+            return None;
+        }
+
+        // The cheapest node of each class found so far, relaxed until nothing improves
+        let mut best: HashMap<Id, (usize, &ENode<L, I>)> = HashMap::new();
+        loop {
+            let mut changed = false;
+            for (id, nodes) in self.classes.iter() {
+                for enode in nodes {
+                    let total = enode.children.iter()
+                        .map(|child| best.get(&self.find(*child)).map(|(c, _)| *c))
+                        .collect::<Option<Vec<usize>>>()
+                        .map(|children| model.node_cost(&enode.node, &children));
+                    match (total, best.get(id)) {
+                        (Some(total), Some((current, _))) if total >= *current => {},
+                        (Some(total), _) => {
+                            best.insert(*id, (total, enode));
+                            changed = true;
+                        },
+                        (None, _) => {},
+                    }
+                }
+            }
+            if !changed {
+                break
+            }
+        }
+
+        let (total, _) = best.get(&self.find(root))?;
+        let mut tokens = vec![];
+        let mut stack = vec![self.find(root)];
+        while let Some(id) = stack.pop() {
+            let (_, enode) = best.get(&self.find(id))?;
+            tokens.push(enode.node.clone());
+            stack.extend(enode.children.iter().rev());
+        }
+        Some((tokens, *total))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{Tree, Node, PatternLeaf, Rule, Guard, Condition, Theory, CostModel, Depth, ReplacementError};
+    use crate::test_utils::{Equation, Element, Operator, Identity, leaf, wildcard, rule, commutativity, associativity, compile};
+
+    use super::{EGraph, Limits, StopReason};
+
+    #[test]
+    fn test_saturates_modulo_associativity_and_commutativity() {
+        // ((a*b)*c)*d
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::MULTIPLY),
+            Node::Internal(Operator::MULTIPLY),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b"),
+            leaf("c"),
+            leaf("d"),
+        ]);
+        // d*(c*(b*a))
+        let reversed = Equation::new(vec![
+            Node::Internal(Operator::MULTIPLY), leaf("d"),
+            Node::Internal(Operator::MULTIPLY), leaf("c"),
+            Node::Internal(Operator::MULTIPLY), leaf("b"), leaf("a"),
+        ]);
+
+        let mut egraph = EGraph::new();
+        let root = egraph.add_tree(&tree).unwrap();
+        let rules = [compile(commutativity(Operator::MULTIPLY)), compile(associativity(Operator::MULTIPLY))];
+
+        assert_eq!(egraph.run(&rules, &Limits::default()), Ok(StopReason::Saturated));
+        assert!(egraph.lookup(&reversed).is_some_and(|id| egraph.equivalent(id, root)));
+        // Every product of a non-empty subset of {a, b, c, d} has its own class, and no others
+        assert_eq!(egraph.class_count(), 15);
+    }
+
+    #[test]
+    fn test_extracts_simplest_term() {
+        // b*a + a*c
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), leaf("b"), leaf("a"),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("c"),
+        ]);
+        let distributivity = rule(
+            vec![
+                Node::Internal(Operator::ADD),
+                Node::Internal(Operator::MULTIPLY), wildcard("x"), wildcard("y"),
+                Node::Internal(Operator::MULTIPLY), wildcard("x"), wildcard("z"),
+            ],
+            vec![
+                Node::Internal(Operator::MULTIPLY), wildcard("x"),
+                Node::Internal(Operator::ADD), wildcard("y"), wildcard("z"),
+            ],
+        );
+
+        let mut egraph = EGraph::new();
+        let root = egraph.add_tree(&tree).unwrap();
+        egraph.run(&[compile(commutativity(Operator::MULTIPLY)), distributivity], &Limits::default()).unwrap();
+
+        let simplest: Equation = egraph.extract_simplest(root).unwrap();
+        assert_eq!(simplest.simplicity(), 5);
+        assert!(egraph.lookup(&simplest).is_some_and(|id| egraph.equivalent(id, root)));
+
+        let (cheapest, cost): (Equation, usize) = egraph.extract(root, |node| match node {
+            Node::Internal(Operator::MULTIPLY) => 10,
+            _ => 1,
+        }).unwrap();
+        assert_eq!(cheapest, simplest);
+        assert_eq!(cost, 14);
+
+        let (shallowest, depth): (Equation, usize) = egraph.extract_with(root, &Depth).unwrap();
+        assert_eq!(depth, Depth.cost(shallowest.tokens()));
+        assert!(depth <= Depth.cost(simplest.tokens()));
+    }
+
+    #[test]
+    fn test_limits_and_guards() {
+        // a+b
+        let tree = Equation::new(vec![Node::Internal(Operator::ADD), leaf("a"), leaf("b")]);
+        // Each round adds another inverse to the second operand, so this never saturates
+        let grow = rule(
+            vec![Node::Internal(Operator::ADD), wildcard("x"), wildcard("y")],
+            vec![Node::Internal(Operator::ADD), wildcard("x"), Node::Internal(Operator::INVERSE), wildcard("y")],
+        );
+
+        let rules = [grow];
+        let mut egraph = EGraph::new();
+        egraph.add_tree(&tree).unwrap();
+        assert_eq!(egraph.run(&rules, &Limits { iterations: 3, nodes: 10_000 }), Ok(StopReason::IterationLimit));
+        assert_eq!(egraph.run(&rules, &Limits { iterations: 100, nodes: 50 }), Ok(StopReason::NodeLimit));
+
+        // Only swap when that sorts the operands
+        let ordered = Rule::compile_with(
+            Identity::new(vec![Node::Internal(Operator::ADD), wildcard("x"), wildcard("y")]),
+            Identity::new(vec![Node::Internal(Operator::ADD), wildcard("y"), wildcard("x")]),
+            Theory::new(),
+            Guard::new().when(Condition::Ordered("y".to_string(), "x".to_string())),
+        ).unwrap();
+        let mut egraph = EGraph::new();
+        let root = egraph.add_tree(&tree).unwrap();
+        assert_eq!(egraph.run(&[ordered], &Limits::default()), Ok(StopReason::Saturated));
+        assert_eq!(egraph.node_count(), 3);
+        assert_eq!(egraph.find(root), root);
+    }
+
+    #[test]
+    fn test_operator_and_leaf_wildcards() {
+        let operator = |name: &str| Node::Leaf(PatternLeaf::Operator(name.to_string(), 2));
+        let leaf_wildcard = |name: &str| Node::Leaf(PatternLeaf::Leaf(name.to_string()));
+        let zero = || Node::Leaf(PatternLeaf::Literal(Element { label: b"0".to_vec() }));
+
+        // (a - a) * (b + c - (b + c))
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::MULTIPLY),
+            Node::Internal(Operator::SUBTRACT), leaf("a"), leaf("a"),
+            Node::Internal(Operator::SUBTRACT),
+            Node::Internal(Operator::ADD), leaf("b"), leaf("c"),
+            Node::Internal(Operator::ADD), leaf("b"), leaf("c"),
+        ]);
+        // x o y = y o x, for every operator o
+        let commute = rule(
+            vec![operator("o"), wildcard("x"), wildcard("y")],
+            vec![operator("o"), wildcard("y"), wildcard("x")],
+        );
+        // l - l = 0, for leaves only
+        let cancel = rule(vec![Node::Internal(Operator::SUBTRACT), leaf_wildcard("l"), leaf_wildcard("l")], vec![zero()]);
+
+        let mut egraph = EGraph::new();
+        let root = egraph.add_tree(&tree).unwrap();
+        assert_eq!(egraph.run(&[commute, cancel], &Limits::default()), Ok(StopReason::Saturated));
+
+        // (c + b - (b + c)) * 0
+        let rearranged = Equation::new(vec![
+            Node::Internal(Operator::MULTIPLY),
+            Node::Internal(Operator::SUBTRACT),
+            Node::Internal(Operator::ADD), leaf("c"), leaf("b"),
+            Node::Internal(Operator::ADD), leaf("b"), leaf("c"),
+            leaf("0"),
+        ]);
+        assert!(egraph.lookup(&rearranged).is_some_and(|id| egraph.equivalent(id, root)));
+
+        let sum = egraph.lookup(&Equation::new(vec![Node::Internal(Operator::ADD), leaf("b"), leaf("c")])).unwrap();
+        let zero = egraph.lookup(&Equation::new(vec![leaf("0")])).unwrap();
+        assert!(!egraph.equivalent(sum, zero));
+    }
+
+    #[test]
+    fn test_sequence_wildcards_are_rejected() {
+        let sequence = |name: &str| Node::Leaf(PatternLeaf::Sequence(name.to_string()));
+        // xs + ys = ys + xs, whose sequences would each only ever stand for one child of a node in the graph
+        let swap = rule(
+            vec![Node::Internal(Operator::ADD), sequence("xs"), sequence("ys")],
+            vec![Node::Internal(Operator::ADD), sequence("ys"), sequence("xs")],
+        );
+
+        let mut egraph = EGraph::new();
+        egraph.add_tree(&Equation::new(vec![Node::Internal(Operator::ADD), leaf("a"), leaf("b")])).unwrap();
+        let rules = [compile(commutativity(Operator::MULTIPLY)), swap];
+        assert_eq!(egraph.run(&rules, &Limits::default()), Err(ReplacementError::UnsupportedSequence("xs".to_string())));
+        assert_eq!(egraph.node_count(), 3);
+    }
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::tree::{Node, TreeNode};
+
+/// The subtrees bound to each wildcard by a single match, as seen by guard conditions.
+pub struct Substitution<'a, L: TreeNode, I: TreeNode> {
+    bindings: Vec<(&'a str, &'a [Node<L, I>])>,
+}
+
+impl<'a, L: TreeNode, I: TreeNode> Substitution<'a, L, I> {
+
+    pub(crate) fn new(bindings: Vec<(&'a str, &'a [Node<L, I>])>) -> Self {
+        Self { bindings }
+    }
+
+    /// The tokens of the subtree bound to `wildcard`, if the pattern binds it.
+    pub fn get(&self, wildcard: &str) -> Option<&'a [Node<L, I>]> {
+        self.bindings.iter().find(|(name, _)| *name == wildcard).map(|(_, tokens)| *tokens)
+    }
+}
+
+/// An arbitrary predicate over a substitution.
+pub type Predicate<L, I> = Arc<dyn Fn(&Substitution<L, I>) -> bool + Send + Sync>;
+
+/// A side condition on the subtrees bound by a pattern, which must hold for a match to be rewritten.
+///
+/// Conditions naming a wildcard compare the bound subtrees syntactically, even when matching modulo a theory.
+pub enum Condition<L: TreeNode, I: TreeNode> {
+    /// The wildcard is bound to a single leaf
+    IsLeaf(String),
+    /// The wildcard is bound to anything other than this leaf
+    IsNot(String, L),
+    /// The two wildcards are bound to different subtrees
+    Distinct(String, String),
+    /// The first wildcard's subtree orders strictly before the second's, comparing node labels in pre-order. Useful
+    /// for only applying commutativity in one direction.
+    Ordered(String, String),
+    /// The wildcard is bound to a subtree with fewer than this many nodes
+    SmallerThan(String, usize),
+    /// Any other predicate over the substitution
+    Custom(Predicate<L, I>),
+}
+
+fn labels<L: TreeNode, I: TreeNode>(tokens: &[Node<L, I>]) -> Vec<String> {
+    tokens.iter().map(|token| match token {
+        Node::Leaf(l) => l.uid(),
+        Node::Internal(o) => o.to_string(),
+    }).collect()
+}
+
+impl<L: TreeNode, I: TreeNode> Condition<L, I> {
+
+    /// The wildcards this condition refers to. Custom conditions aren't inspected.
+    pub fn wildcards(&self) -> Vec<&str> {
+        match self {
+            Condition::IsLeaf(x) | Condition::IsNot(x, _) | Condition::SmallerThan(x, _) => vec![x],
+            Condition::Distinct(x, y) | Condition::Ordered(x, y) => vec![x, y],
+            Condition::Custom(_) => vec![],
+        }
+    }
+
+    pub fn holds(&self, substitution: &Substitution<L, I>) -> bool {
+        let bound = |name: &str| substitution.get(name).unwrap_or(&[]);
+        match self {
+            Condition::IsLeaf(x) => matches!(bound(x), [Node::Leaf(_)]),
+            Condition::IsNot(x, leaf) => !matches!(bound(x), [Node::Leaf(l)] if l.uid() == leaf.uid()),
+            Condition::Distinct(x, y) => labels(bound(x)) != labels(bound(y)),
+            Condition::Ordered(x, y) => labels(bound(x)) < labels(bound(y)),
+            Condition::SmallerThan(x, size) => bound(x).len() < *size,
+            Condition::Custom(predicate) => predicate(substitution),
+        }
+    }
+}
+
+impl<L: TreeNode + Clone, I: TreeNode> Clone for Condition<L, I> {
+    fn clone(&self) -> Self {
+        match self {
+            Condition::IsLeaf(x) => Condition::IsLeaf(x.clone()),
+            Condition::IsNot(x, leaf) => Condition::IsNot(x.clone(), leaf.clone()),
+            Condition::Distinct(x, y) => Condition::Distinct(x.clone(), y.clone()),
+            Condition::Ordered(x, y) => Condition::Ordered(x.clone(), y.clone()),
+            Condition::SmallerThan(x, size) => Condition::SmallerThan(x.clone(), *size),
+            Condition::Custom(predicate) => Condition::Custom(predicate.clone()),
+        }
+    }
+}
+
+impl<L: TreeNode + fmt::Debug, I: TreeNode> fmt::Debug for Condition<L, I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::IsLeaf(x) => f.debug_tuple("IsLeaf").field(x).finish(),
+            Condition::IsNot(x, leaf) => f.debug_tuple("IsNot").field(x).field(leaf).finish(),
+            Condition::Distinct(x, y) => f.debug_tuple("Distinct").field(x).field(y).finish(),
+            Condition::Ordered(x, y) => f.debug_tuple("Ordered").field(x).field(y).finish(),
+            Condition::SmallerThan(x, size) => f.debug_tuple("SmallerThan").field(x).field(size).finish(),
+            Condition::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// A conjunction of conditions guarding a rewrite. An empty guard always holds.
+#[derive(Clone, Debug)]
+pub struct Guard<L: TreeNode, I: TreeNode> {
+    conditions: Vec<Condition<L, I>>,
+}
+
+impl<L: TreeNode, I: TreeNode> Default for Guard<L, I> {
+    fn default() -> Self {
+        Self { conditions: vec![] }
+    }
+}
+
+impl<L: TreeNode, I: TreeNode> Guard<L, I> {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Additionally require `condition` to hold.
+    pub fn when(mut self, condition: Condition<L, I>) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+
+    pub fn conditions(&self) -> &[Condition<L, I>] {
+        &self.conditions
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conditions.is_empty()
+    }
+
+    pub fn holds(&self, substitution: &Substitution<L, I>) -> bool {
+        self.conditions.iter().all(|condition| condition.holds(substitution))
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use crate::tree::{Node, Tree, TreeNode, TreeParseError};
+
+/// The identifier of a subtree interned in a [`HashCons`]. Two subtrees interned in the same store have the same id
+/// exactly when they are structurally equal, so comparing them is constant time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TermId(usize);
+
+/// What makes two subtrees the same: their root's label and the ids of their children.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Leaf(String, Vec<TermId>),
+    Internal(String, Vec<TermId>),
+}
+
+/// A hash-consing store, which gives every distinct subtree it sees a [`TermId`].
+///
+/// Leaves are compared by their uid and internal nodes by their label, as everywhere else trees are compared. Each
+/// subtree is only stored once, in terms of its children's ids, so interning a tree takes time linear in its size no
+/// matter how many trees the store already holds.
+#[derive(Clone, Debug, Default)]
+pub struct HashCons {
+    memo: HashMap<Key, TermId>,
+}
+
+impl HashCons {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of distinct subtrees in the store.
+    pub fn len(&self) -> usize {
+        self.memo.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.memo.is_empty()
+    }
+
+    /// Intern `tree`, returning the id of its root.
+    pub fn intern<T: Tree>(&mut self, tree: &T) -> Result<TermId, TreeParseError> {
+        Ok(self.intern_subtrees(tree)?[0])
+    }
+
+    /// Intern every subtree of `tree`, returning the id of the subtree rooted at each token.
+    pub fn intern_subtrees<T: Tree>(&mut self, tree: &T) -> Result<Vec<TermId>, TreeParseError> {
+        tree.validate()?;
+        // A valid tree always has enough children on the stack
+        Ok(self.intern_tokens(tree.tokens()).unwrap())
+    }
+
+    /// Intern every subtree of a sequence of whole trees, returning the id of the subtree rooted at each token, or
+    /// `None` if some node is missing children.
+    pub(crate) fn intern_tokens<L: TreeNode, I: TreeNode>(&mut self, tokens: &[Node<L, I>]) -> Option<Vec<TermId>> {
+        let mut ids = vec![TermId(0); tokens.len()];
+        let mut stack: Vec<TermId> = vec![];
+
+        // Children are interned before their parents, so work back from the last token
+        for (index, token) in tokens.iter().enumerate().rev() {
+            let first = stack.len().checked_sub(token.arity())?;
+            let children = stack.split_off(first).into_iter().rev().collect();
+            let key = match token {
+                Node::Leaf(l) => Key::Leaf(l.uid(), children),
+                Node::Internal(o) => Key::Internal(o.to_string(), children),
+            };
+
+            let next = TermId(self.memo.len());
+            let id = *self.memo.entry(key).or_insert(next);
+            ids[index] = id;
+            stack.push(id);
+        }
+
+        Some(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{Tree, Node, TreeParseError};
+    use crate::test_utils::{Equation, Operator, leaf};
+
+    use super::HashCons;
+
+    #[test]
+    fn test_structural_equality() {
+        let mut terms = HashCons::new();
+
+        // (a * b) + (a * b)
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b"),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b"),
+        ]);
+        let ids = terms.intern_subtrees(&tree).unwrap();
+        assert_eq!(ids[1], ids[4]);
+        assert_eq!(ids[2], ids[5]);
+        assert_ne!(ids[2], ids[3]);
+        assert_ne!(ids[0], ids[1]);
+        // a, b, a * b and the whole tree
+        assert_eq!(terms.len(), 4);
+
+        // Equal trees get the same id, and only new subtrees are added
+        let product = Equation::new(vec![Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b")]);
+        assert_eq!(terms.intern(&product), Ok(ids[1]));
+        assert_eq!(terms.intern(&tree), Ok(ids[0]));
+        assert_eq!(terms.len(), 4);
+
+        // Order matters, as does the operator
+        let swapped = Equation::new(vec![Node::Internal(Operator::MULTIPLY), leaf("b"), leaf("a")]);
+        let sum = Equation::new(vec![Node::Internal(Operator::ADD), leaf("a"), leaf("b")]);
+        assert_ne!(terms.intern(&swapped), Ok(ids[1]));
+        assert_ne!(terms.intern(&sum), Ok(ids[1]));
+        assert_eq!(terms.len(), 6);
+
+        // Labels which run together are still told apart
+        let split = Equation::new(vec![Node::Internal(Operator::ADD), leaf("ab"), leaf("c")]);
+        let joined = Equation::new(vec![Node::Internal(Operator::ADD), leaf("a"), leaf("bc")]);
+        assert_ne!(terms.intern(&split), terms.intern(&joined));
+
+        let truncated = Equation::new(vec![Node::Internal(Operator::ADD), leaf("a")]);
+        assert_eq!(terms.intern(&truncated), Err(TreeParseError::Truncated { index: 0 }));
+    }
+}
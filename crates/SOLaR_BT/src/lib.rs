@@ -1,11 +1,36 @@
 mod tree;
+mod location;
 mod pattern;
 mod match_pattern;
+mod theory;
+mod guard;
+mod egraph;
+mod discrimination_tree;
+mod unify;
+mod strategy;
+mod zipper;
+mod sexpr;
+mod hash_cons;
+mod diff;
+mod cost;
 
 #[cfg(test)]
 mod test_utils;
 
 // Public exports
-pub use tree::{TreeNode, Tree, Node, ParsableTreeNode};
+pub use tree::{TreeNode, Tree, Node, ParsableTreeNode, TreeParseError};
 pub use pattern::PatternLeaf;
-pub use match_pattern::{replace_identity, try_replace_identity, ReplacementError};
\ No newline at end of file
+pub use location::Location;
+pub use zipper::Zipper;
+pub use sexpr::{to_sexpr, from_sexpr, SExprParseError};
+pub use hash_cons::{HashCons, TermId};
+pub use cost::{CostModel, NodeCount, Depth, Weighted, Alphabetical};
+pub use diff::{edit_distance, edit_script, differing_subtrees, bridging_rules, Edit};
+pub use theory::{Theory, OperatorProperties};
+pub use guard::{Guard, Condition, Predicate, Substitution};
+pub use match_pattern::{replace_identity, try_replace_identity, try_replace_identity_many, Rule, ReplacementError};
+pub use match_pattern::regex;
+pub use egraph::{EGraph, Id, Limits, StopReason};
+pub use discrimination_tree::DiscriminationTree;
+pub use unify::{unify, specialisation, rename_apart, overlaps, Unifier, UnificationError};
+pub use strategy::{Strategy, StrategyError};
\ No newline at end of file
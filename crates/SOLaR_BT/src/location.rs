@@ -0,0 +1,151 @@
+use crate::tree::{Node, TreeNode};
+
+/// Where a subtree sits in a tree, both as the pre-order index of its root token and as the child indices leading to
+/// it from the root of the tree.
+///
+/// The root of the tree is at index 0 with an empty path.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Location {
+    pub index: usize,
+    pub path: Vec<usize>,
+}
+
+impl Location {
+
+    /// The root of any tree.
+    pub fn root() -> Self {
+        Self::default()
+    }
+
+    /// Locate the subtree rooted at the token `index`, or `None` if it's out of bounds.
+    pub fn of_index<L: TreeNode, I: TreeNode>(tokens: &[Node<L, I>], index: usize) -> Option<Self> {
+        if index >= tokens.len() {
+            return None;
+        }
+
+        // Open internal nodes and the number of their children seen so far
+        let mut open: Vec<(usize, usize)> = vec![];
+
+        for (i, token) in tokens.iter().enumerate().take(index + 1) {
+            // Close off nodes whose children have all been seen, then count this token as the next child
+            while let Some((arity, seen)) = open.last() {
+                if seen < arity {
+                    break
+                }
+                open.pop();
+            }
+            if let Some((_, seen)) = open.last_mut() {
+                *seen += 1;
+            }
+
+            if i == index {
+                break
+            }
+            if token.arity() > 0 {
+                open.push((token.arity(), 0));
+            }
+        }
+
+        Some(Self {
+            index,
+            path: open.iter().map(|(_, seen)| seen - 1).collect(),
+        })
+    }
+
+    /// Locate the subtree reached by following the child indices in `path` from the root, or `None` if there is no
+    /// such subtree.
+    pub fn of_path<L: TreeNode, I: TreeNode>(tokens: &[Node<L, I>], path: &[usize]) -> Option<Self> {
+        let mut index = 0;
+        for &child in path {
+            index = *children(tokens, index)?.get(child)?;
+        }
+
+        tokens.get(index)?;
+        Some(Self {
+            index,
+            path: path.to_vec(),
+        })
+    }
+
+    /// The number of child steps from the root.
+    pub fn depth(&self) -> usize {
+        self.path.len()
+    }
+}
+
+/// The index one past the last token of the subtree starting at `start`, or `None` if the tokens run out first.
+pub(crate) fn subtree_end<L: TreeNode, I: TreeNode>(tokens: &[Node<L, I>], start: usize) -> Option<usize> {
+    let mut remaining = 1;
+    let mut index = start;
+    while remaining > 0 {
+        remaining += tokens.get(index)?.arity();
+        remaining -= 1;
+        index += 1;
+    }
+    Some(index)
+}
+
+/// The starts of the children of the node at `index`, in order, or `None` if the tokens run out first.
+pub(crate) fn children<L: TreeNode, I: TreeNode>(tokens: &[Node<L, I>], index: usize) -> Option<Vec<usize>> {
+    let mut starts = Vec::with_capacity(tokens.get(index)?.arity());
+    let mut child = index + 1;
+    for _ in 0..tokens[index].arity() {
+        starts.push(child);
+        child = subtree_end(tokens, child)?;
+    }
+    Some(starts)
+}
+
+/// The start of the parent of the node at `index`, or `None` for the root.
+pub(crate) fn parent<L: TreeNode, I: TreeNode>(tokens: &[Node<L, I>], index: usize) -> Option<usize> {
+    let location = Location::of_index(tokens, index)?;
+    let (_, path) = location.path.split_last()?;
+    Location::of_path(tokens, path).map(|parent| parent.index)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::Node;
+    use crate::test_utils::{Operator, leaf};
+
+    use super::Location;
+
+    #[test]
+    fn test_index_and_path_agree() {
+        // (a + if(b, c, inv(d))) * e
+        let tokens = vec![
+            Node::Internal(Operator::MULTIPLY),
+            Node::Internal(Operator::ADD),
+            leaf("a"),
+            Node::Internal(Operator::CONDITIONAL),
+            leaf("b"),
+            leaf("c"),
+            Node::Internal(Operator::INVERSE),
+            leaf("d"),
+            leaf("e"),
+        ];
+
+        let paths: [&[usize]; 9] = [&[], &[0], &[0, 0], &[0, 1], &[0, 1, 0], &[0, 1, 1], &[0, 1, 2], &[0, 1, 2, 0], &[1]];
+
+        for (index, path) in paths.iter().enumerate() {
+            let location = Location { index, path: path.to_vec() };
+            assert_eq!(Location::of_index(&tokens, index), Some(location.clone()));
+            assert_eq!(Location::of_path(&tokens, path), Some(location));
+        }
+
+        assert_eq!(Location::of_index(&tokens, 9), None);
+        assert_eq!(Location::of_path(&tokens, &[2]), None);
+        assert_eq!(Location::of_path(&tokens, &[0, 0, 0]), None);
+        assert_eq!(Location::of_path(&tokens, &[0, 1, 3]), None);
+        assert_eq!(Location::root(), Location::of_index(&tokens, 0).unwrap());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let location = Location { index: 3, path: vec![1, 0] };
+        assert_eq!(serde_json::from_str::<Location>(&serde_json::to_string(&location).unwrap()).unwrap(), location);
+    }
+}
@@ -1,127 +1,87 @@
-use onig::{Regex, RegexOptions, Syntax};
-use handlebars::Handlebars;
-use serde_json::Value;
-use crate::PatternLeaf;
+use std::fmt;
 
-use self::regex::{tree_to_regex, pattern_to_regex, tree_from_regex, replacement_to_regex};
-use crate::tree::{ParsableTreeNode, Tree};
+use crate::location::Location;
+use crate::tree::TreeParseError;
+use regex::RegexParseError;
 
-use onig::Captures;
+pub mod regex;
+mod native;
+mod ac;
+mod rule;
 
-mod regex;
+pub use native::{replace_identity, try_replace_identity, try_replace_identity_many};
+pub use rule::Rule;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ReplacementError {
-
+    /// The replacement or guard refers to a wildcard which the pattern never binds
+    UnknownWildcard(String),
+    /// The wildcard is used as more than one of a subtree or leaf wildcard, a sequence wildcard and an operator
+    /// wildcard, or as operator wildcards with different arities
+    WildcardKindMismatch(String),
+    /// The tree being rewritten isn't a single well formed tree
+    InvalidTree(TreeParseError),
+    /// The location's index and path don't both lead to the same subtree of the tree being rewritten
+    InvalidLocation(Location),
+    /// The pattern isn't a single well formed tree
+    InvalidPattern(TreeParseError),
+    /// The replacement isn't a single well formed tree
+    InvalidReplacement(TreeParseError),
+    /// The pattern has the sequence wildcard, which an [`EGraph`](crate::EGraph) can't match, as its nodes always have
+    /// the same number of children
+    UnsupportedSequence(String),
+    /// The regex matcher produced a string which couldn't be parsed back into a tree
+    Regex(RegexParseError),
+    /// The regex matcher's own machinery failed, e.g. the generated regex didn't compile
+    RegexBackend(String),
 }
 
-pub fn replace_identity<
-        L: ParsableTreeNode,
-        I: ParsableTreeNode,
-        T: Tree<L=L, I=I>,
-        P: Tree<L=PatternLeaf<L>, I=I>,
-    >(
-        tree: &T,
-        identity: (&P, &P),
-        position: usize,
-        ) -> Result<Option<T>, ReplacementError> {
-
-    let expression_string = tree_to_regex(tree).unwrap();
-    let (pattern_string, wildcards) = pattern_to_regex(identity.0).unwrap();
-    let replacement_string = replacement_to_regex(identity.1, &wildcards).unwrap();
-
-    let re = Regex::with_options(
-            &pattern_string,
-    RegexOptions::REGEX_OPTION_DONT_CAPTURE_GROUP,
-    Syntax::default(),
-    ).unwrap();
-
-    let mut captures_iter = re.captures_iter(&expression_string);
-
-    match captures_iter.nth(position - 1) {
-        Some(caps) => {
-            let new_tree_string = _replace_capture(&caps, &expression_string, &replacement_string);
-            let new_tree = tree_from_regex(&new_tree_string).unwrap();
-            Ok(Some(new_tree))
-        },
-        None => Ok(None)
+impl fmt::Display for ReplacementError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplacementError::UnknownWildcard(name) => write!(f, "wildcard `{name}` is not bound by the pattern"),
+            ReplacementError::WildcardKindMismatch(name) => write!(f, "wildcard `{name}` is used as different kinds of wildcard"),
+            ReplacementError::InvalidTree(e) => write!(f, "invalid tree: {e}"),
+            ReplacementError::InvalidLocation(location) => {
+                write!(f, "no subtree is at both index {} and path {:?}", location.index, location.path)
+            },
+            ReplacementError::InvalidPattern(e) => write!(f, "invalid pattern: {e}"),
+            ReplacementError::InvalidReplacement(e) => write!(f, "invalid replacement: {e}"),
+            ReplacementError::UnsupportedSequence(name) => write!(f, "sequence wildcard `{name}` can't be matched in an e-graph"),
+            ReplacementError::Regex(e) => write!(f, "unable to parse rewritten tree: {e}"),
+            ReplacementError::RegexBackend(message) => write!(f, "regex matcher failed: {message}"),
+        }
     }
 }
 
-pub fn try_replace_identity<
-        L: ParsableTreeNode,
-        I: ParsableTreeNode,
-        T: Tree<L=L, I=I>,
-        P: Tree<L=PatternLeaf<L>, I=I>,
-    >(
-        tree: &T,
-        identity: (&P, &P),
-    ) -> Result<Vec<(T, usize)>, &'static str> {
-
-    let expression_string = tree_to_regex(tree).unwrap();
-    let (pattern_string, wildcards) = pattern_to_regex(identity.0).unwrap();
-    let replacement_string = replacement_to_regex(identity.1, &wildcards).unwrap();
-
-    let re = Regex::new(
-        &pattern_string,
-    ).unwrap();
-
-    let captures_iter: Vec<Captures> = re.captures_iter(&expression_string).collect();
-    let mut new_tree_strings = vec![];
-    
-    for caps in captures_iter {
-        new_tree_strings.push(
-                (
-                    _replace_capture(&caps, &expression_string, &replacement_string),
-                    caps.offset(),
-                )
-        );
-    };
-
-    let new_trees: Vec<(T, usize)> = new_tree_strings.iter().map(|s| (tree_from_regex(&s.0).unwrap(), s.1)).collect();
-    Ok(new_trees)
+impl std::error::Error for ReplacementError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReplacementError::InvalidTree(e) | ReplacementError::InvalidPattern(e) | ReplacementError::InvalidReplacement(e) => Some(e),
+            ReplacementError::Regex(e) => Some(e),
+            ReplacementError::UnknownWildcard(_)
+                | ReplacementError::WildcardKindMismatch(_)
+                | ReplacementError::InvalidLocation(_)
+                | ReplacementError::UnsupportedSequence(_)
+                | ReplacementError::RegexBackend(_) => None,
+        }
+    }
 }
 
-
-fn _replace_capture(
-        caps: &Captures,
-        expression_string: &str,
-        replacement_string: &str,
-    ) -> String {
-    let (offset, end) = caps.pos(1).unwrap();
-    let mut new_tree_string = String::new();
-    new_tree_string.push_str(
-            &expression_string[..offset]
-    );
-    let mut data = String::new();
-    data.push_str("{\n");
-    for (i, cap) in caps.iter().skip(2).enumerate(){
-        data.push_str(&format!(r#""g{}": "{}""#, i, cap.unwrap()));
-        if i < caps.len() - 3 {
-            data.push(',');
-        }
-        data.push('\n');
+impl From<RegexParseError> for ReplacementError {
+    fn from(e: RegexParseError) -> Self {
+        ReplacementError::Regex(e)
     }
-    data.push('}');
-    let reg = Handlebars::new();
-    let val: Value = serde_json::from_str(&data).unwrap();
-    new_tree_string.push_str(
-            &reg.render_template(replacement_string, &val).unwrap()
-    );
-    new_tree_string.push_str(
-            &expression_string[end..]
-    );
-    new_tree_string
 }
 
 
  #[cfg(test)]
 mod tests {
 
-    use crate::{Tree, Node, PatternLeaf};
-    use crate::test_utils::{Equation, Element, Operator, Identity};
+    use crate::{Tree, Node, PatternLeaf, Theory, Guard, Condition, Location, TreeParseError, Strategy, EGraph, DiscriminationTree};
+    use crate::test_utils::{Equation, Element, Operator, Identity, leaf, wildcard, literal, commutativity};
 
-    use super::{replace_identity, try_replace_identity};
+    use super::{regex, replace_identity, try_replace_identity, try_replace_identity_many, Rule, ReplacementError};
 
     #[test]
     fn test_replace_identity() {
@@ -158,7 +118,7 @@ mod tests {
             Node::Leaf(PatternLeaf::Subtree("z".to_string())),
         ]);
 
-        let after = replace_identity(&tree, (&pattern, &replacement), 1).unwrap().unwrap();
+        let after = replace_identity(&tree, (&pattern, &replacement), &Location::root(), &Theory::new(), &Guard::new()).unwrap().unwrap();
         assert_eq!(
             after,
             Equation::new(vec![
@@ -216,7 +176,7 @@ mod tests {
             Node::Leaf(PatternLeaf::Subtree("z".to_string())),
         ]);
 
-        let after = try_replace_identity(&tree, (&pattern, &replacement)).unwrap();
+        let after = try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &Guard::new()).unwrap();
         assert_eq!(
             after[0].0,
             Equation::new(vec![
@@ -295,7 +255,7 @@ mod tests {
         ]);
 
         let after: Vec<Equation> = 
-            try_replace_identity(&tree, (&pattern, &replacement))
+            try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &Guard::new())
                 .unwrap()
                 .into_iter()
                 .map(|(a, _)|a)
@@ -370,4 +330,733 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_native_and_regex_matchers_agree() {
+
+        // (a*c + a*d) + (b*c + b*d)
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY),
+            leaf("a"),
+            leaf("c"),
+            Node::Internal(Operator::MULTIPLY),
+            leaf("a"),
+            leaf("d"),
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY),
+            leaf("b"),
+            leaf("c"),
+            Node::Internal(Operator::MULTIPLY),
+            leaf("b"),
+            leaf("d"),
+        ]);
+
+        let identities = vec![
+            // x*y + x*z = x*(y + z)
+            (
+                Identity::new(vec![
+                    Node::Internal(Operator::ADD),
+                    Node::Internal(Operator::MULTIPLY),
+                    wildcard("x"),
+                    wildcard("y"),
+                    Node::Internal(Operator::MULTIPLY),
+                    wildcard("x"),
+                    wildcard("z"),
+                ]),
+                Identity::new(vec![
+                    Node::Internal(Operator::MULTIPLY),
+                    wildcard("x"),
+                    Node::Internal(Operator::ADD),
+                    wildcard("y"),
+                    wildcard("z"),
+                ]),
+            ),
+            // x*y = y*x
+            (
+                Identity::new(vec![
+                    Node::Internal(Operator::MULTIPLY),
+                    wildcard("x"),
+                    wildcard("y"),
+                ]),
+                Identity::new(vec![
+                    Node::Internal(Operator::MULTIPLY),
+                    wildcard("y"),
+                    wildcard("x"),
+                ]),
+            ),
+            // a*x = x
+            (
+                Identity::new(vec![
+                    Node::Internal(Operator::MULTIPLY),
+                    Node::Leaf(PatternLeaf::Literal(Element {
+                        label: b"a".to_vec(),
+                    })),
+                    wildcard("x"),
+                ]),
+                Identity::new(vec![
+                    wildcard("x"),
+                ]),
+            ),
+        ];
+
+        for (pattern, replacement) in &identities {
+            let native: Vec<(Equation, Location)> = try_replace_identity(&tree, (pattern, replacement), &Theory::new(), &Guard::new()).unwrap();
+            let regex: Vec<(Equation, Location)> = regex::try_replace_identity(&tree, (pattern, replacement)).unwrap();
+            assert!(!native.is_empty());
+            assert_eq!(native, regex);
+
+            for index in 0..tree.tokens().len() {
+                let location = Location::of_index(tree.tokens(), index).unwrap();
+                let native: Option<Equation> = replace_identity(&tree, (pattern, replacement), &location, &Theory::new(), &Guard::new()).unwrap();
+                let regex: Option<Equation> = regex::replace_identity(&tree, (pattern, replacement), &location).unwrap();
+                assert_eq!(native, regex);
+            }
+        }
+
+        // Applying them all at once, across threads, gives the same results in the same order
+        let pairs: Vec<(&Identity, &Identity)> = identities.iter().map(|(pattern, replacement)| (pattern, replacement)).collect();
+        let one_by_one: Vec<Vec<(Equation, Location)>> = pairs.iter()
+            .map(|identity| try_replace_identity(&tree, *identity, &Theory::new(), &Guard::new()).unwrap())
+            .collect();
+        assert_eq!(try_replace_identity_many(&tree, &pairs, &Theory::new(), &Guard::new()), Ok(one_by_one));
+        assert_eq!(try_replace_identity_many::<_, _, _, Identity>(&tree, &[], &Theory::new(), &Guard::new()), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_native_and_regex_matchers_agree_on_nullary_operators() {
+        // pi + a, where pi is an operator with no children rather than a leaf
+        let tree = Equation::new(vec![Node::Internal(Operator::ADD), Node::Internal(Operator::PI), leaf("a")]);
+        let pattern = Identity::new(vec![Node::Internal(Operator::ADD), wildcard("x"), wildcard("y")]);
+        let replacement = Identity::new(vec![Node::Internal(Operator::ADD), wildcard("y"), wildcard("x")]);
+
+        let swapped = Equation::new(vec![Node::Internal(Operator::ADD), leaf("a"), Node::Internal(Operator::PI)]);
+        let native: Vec<(Equation, Location)> = try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &Guard::new()).unwrap();
+        assert_eq!(native, vec![(swapped, Location::root())]);
+        assert_eq!(regex::try_replace_identity(&tree, (&pattern, &replacement)).unwrap(), native);
+
+        // Every token, including the nullary operator, is found at the same place by both
+        for index in 0..tree.tokens().len() {
+            let location = Location::of_index(tree.tokens(), index).unwrap();
+            let native: Option<Equation> = replace_identity(&tree, (&pattern, &replacement), &location, &Theory::new(), &Guard::new()).unwrap();
+            assert_eq!(regex::replace_identity(&tree, (&pattern, &replacement), &location).unwrap(), native);
+        }
+    }
+
+    #[test]
+    fn test_operators_match_by_arity() {
+        // x + y = y + x, where + is binary
+        let pattern = Identity::new(vec![Node::Internal(Operator::ADD), wildcard("x"), wildcard("y")]);
+        let replacement = Identity::new(vec![Node::Internal(Operator::ADD), wildcard("y"), wildcard("x")]);
+
+        // +(a, b, c) + d, where only the outer + is binary
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::SUM(3)), leaf("a"), leaf("b"), leaf("c"),
+            leaf("d"),
+        ]);
+
+        let native: Vec<(Equation, Location)> = try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &Guard::new()).unwrap();
+        assert_eq!(native, regex::try_replace_identity(&tree, (&pattern, &replacement)).unwrap());
+        assert_eq!(native, vec![(
+            Equation::new(vec![
+                Node::Internal(Operator::ADD), leaf("d"),
+                Node::Internal(Operator::SUM(3)), leaf("a"), leaf("b"), leaf("c"),
+            ]),
+            Location::root(),
+        )]);
+
+        // Neither matcher matches the variadic + on its own, whether or not + is commutative
+        let sum = Equation::new(vec![Node::Internal(Operator::SUM(3)), leaf("a"), leaf("b"), leaf("c")]);
+        assert!(try_replace_identity::<Element, Operator, Equation, Identity>(&sum, (&pattern, &replacement), &Theory::new(), &Guard::new()).unwrap().is_empty());
+        assert!(regex::try_replace_identity::<Element, Operator, Equation, Identity>(&sum, (&pattern, &replacement)).unwrap().is_empty());
+        let theory = Theory::new().commutative(&Operator::ADD);
+        assert!(try_replace_identity::<Element, Operator, Equation, Identity>(&sum, (&pattern, &replacement), &theory, &Guard::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rewriting_is_thread_safe() {
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<Rule<Element, Operator>>();
+        assert_send_sync::<Guard<Element, Operator>>();
+        assert_send_sync::<Strategy<Element, Operator>>();
+        assert_send_sync::<EGraph<Element, Operator>>();
+        assert_send_sync::<DiscriminationTree<Rule<Element, Operator>>>();
+    }
+
+    #[test]
+    fn test_replacement_with_unbound_wildcard() {
+
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            leaf("a"),
+            leaf("b"),
+        ]);
+
+        let pattern = Identity::new(vec![
+            Node::Internal(Operator::ADD),
+            wildcard("x"),
+            wildcard("y"),
+        ]);
+
+        let replacement = Identity::new(vec![
+            Node::Internal(Operator::ADD),
+            wildcard("y"),
+            wildcard("z"),
+        ]);
+
+        assert!(matches!(
+            replace_identity(&tree, (&pattern, &replacement), &Location::root(), &Theory::new(), &Guard::new()),
+            Err(ReplacementError::UnknownWildcard(name)) if name == "z"
+        ));
+    }
+
+    #[test]
+    fn test_malformed_trees_are_errors() {
+
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            leaf("a"),
+            leaf("b"),
+        ]);
+        // a + (b * ?)
+        let truncated = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            leaf("a"),
+            Node::Internal(Operator::MULTIPLY),
+            leaf("b"),
+        ]);
+
+        let pattern = Identity::new(vec![
+            Node::Internal(Operator::ADD),
+            wildcard("x"),
+            wildcard("y"),
+        ]);
+        let short_pattern = Identity::new(vec![
+            Node::Internal(Operator::ADD),
+            wildcard("x"),
+        ]);
+        let long_replacement = Identity::new(vec![
+            wildcard("x"),
+            wildcard("y"),
+        ]);
+
+        let invalid_tree = ReplacementError::InvalidTree(TreeParseError::Truncated { index: 2 });
+        assert_eq!(try_replace_identity(&truncated, (&pattern, &pattern), &Theory::new(), &Guard::new()), Err(invalid_tree.clone()));
+        assert_eq!(regex::try_replace_identity(&truncated, (&pattern, &pattern)), Err(invalid_tree));
+
+        let invalid_pattern = ReplacementError::InvalidPattern(TreeParseError::Truncated { index: 0 });
+        assert_eq!(try_replace_identity(&tree, (&short_pattern, &short_pattern), &Theory::new(), &Guard::new()), Err(invalid_pattern.clone()));
+        assert_eq!(regex::try_replace_identity(&tree, (&short_pattern, &short_pattern)), Err(invalid_pattern));
+
+        let invalid_replacement = ReplacementError::InvalidReplacement(TreeParseError::TrailingTokens { index: 1 });
+        assert_eq!(
+            replace_identity(&tree, (&pattern, &long_replacement), &Location::root(), &Theory::new(), &Guard::new()),
+            Err(invalid_replacement.clone())
+        );
+        assert_eq!(regex::replace_identity(&tree, (&pattern, &long_replacement), &Location::root()), Err(invalid_replacement));
+    }
+
+    #[test]
+    fn test_regex_matcher_with_arbitrary_labels() {
+
+        // (x1 - Y) . (f(x) ^ x1)
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::COMPOSE),
+            Node::Internal(Operator::SUBTRACT),
+            leaf("x1"),
+            leaf("Y"),
+            Node::Internal(Operator::POWER),
+            leaf("f(x)"),
+            leaf("x1"),
+        ]);
+
+        // x - y = y / x
+        let pattern = Identity::new(vec![
+            Node::Internal(Operator::SUBTRACT),
+            wildcard("x"),
+            wildcard("y"),
+        ]);
+        let replacement = Identity::new(vec![
+            Node::Internal(Operator::DIVIDE),
+            wildcard("y"),
+            wildcard("x"),
+        ]);
+
+        let expected = Equation::new(vec![
+            Node::Internal(Operator::COMPOSE),
+            Node::Internal(Operator::DIVIDE),
+            leaf("Y"),
+            leaf("x1"),
+            Node::Internal(Operator::POWER),
+            leaf("f(x)"),
+            leaf("x1"),
+        ]);
+
+        let regex: Vec<(Equation, Location)> = regex::try_replace_identity(&tree, (&pattern, &replacement)).unwrap();
+        assert_eq!(regex.len(), 1);
+        assert_eq!(regex[0].0, expected);
+        assert_eq!(regex, try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &Guard::new()).unwrap());
+
+        // A lone wildcard matches each subtree exactly once, and never part of a label
+        let anything = Identity::new(vec![
+            wildcard("x"),
+        ]);
+        let regex: Vec<(Equation, Location)> = regex::try_replace_identity(&tree, (&anything, &anything)).unwrap();
+        assert_eq!(regex.len(), tree.tokens().len());
+        assert_eq!(regex, try_replace_identity(&tree, (&anything, &anything), &Theory::new(), &Guard::new()).unwrap());
+
+        // Literal leaves only match whole labels
+        let literal = Identity::new(vec![
+            Node::Leaf(PatternLeaf::Literal(Element {
+                label: b"x".to_vec(),
+            })),
+        ]);
+        assert!(regex::try_replace_identity::<_, _, Equation, _>(&tree, (&literal, &literal)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unary_and_ternary_operators() {
+
+        // b * (inv(a) * a)
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::MULTIPLY),
+            leaf("b"),
+            Node::Internal(Operator::MULTIPLY),
+            Node::Internal(Operator::INVERSE),
+            leaf("a"),
+            leaf("a"),
+        ]);
+
+        // inv(x) * x = if(x, I, x)
+        let pattern = Identity::new(vec![
+            Node::Internal(Operator::MULTIPLY),
+            Node::Internal(Operator::INVERSE),
+            wildcard("x"),
+            wildcard("x"),
+        ]);
+        let replacement = Identity::new(vec![
+            Node::Internal(Operator::CONDITIONAL),
+            wildcard("x"),
+            Node::Leaf(PatternLeaf::Literal(Element {
+                label: b"I".to_vec(),
+            })),
+            wildcard("x"),
+        ]);
+
+        let expected = Equation::new(vec![
+            Node::Internal(Operator::MULTIPLY),
+            leaf("b"),
+            Node::Internal(Operator::CONDITIONAL),
+            leaf("a"),
+            leaf("I"),
+            leaf("a"),
+        ]);
+
+        let native: Vec<(Equation, Location)> = try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &Guard::new()).unwrap();
+        assert_eq!(native.len(), 1);
+        assert_eq!(native[0].0, expected);
+        assert_eq!(native, regex::try_replace_identity(&tree, (&pattern, &replacement)).unwrap());
+
+        // Wildcards bind whole unary subtrees as well
+        let anything = Identity::new(vec![
+            Node::Internal(Operator::MULTIPLY),
+            wildcard("x"),
+            wildcard("y"),
+        ]);
+        let swapped = Identity::new(vec![
+            Node::Internal(Operator::MULTIPLY),
+            wildcard("y"),
+            wildcard("x"),
+        ]);
+        let native: Vec<(Equation, Location)> = try_replace_identity(&tree, (&anything, &swapped), &Theory::new(), &Guard::new()).unwrap();
+        assert_eq!(native.len(), 2);
+        assert_eq!(native, regex::try_replace_identity(&tree, (&anything, &swapped)).unwrap());
+    }
+
+    fn results(trees: Vec<(Equation, Location)>) -> Vec<Vec<Node<Element, Operator>>> {
+        trees.into_iter().map(|(tree, _)| tree.tokens().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_matching_modulo_associativity_and_commutativity() {
+        let theory = Theory::new().associative(&Operator::MULTIPLY).commutative(&Operator::MULTIPLY);
+
+        // c*a = d
+        let pattern = Identity::new(vec![Node::Internal(Operator::MULTIPLY), literal("c"), literal("a")]);
+        let replacement = Identity::new(vec![literal("d")]);
+
+        // a*(b*c) and (a*b)*c both contain c*a modulo AC, leaving d*b
+        for tree in [
+            Equation::new(vec![Node::Internal(Operator::MULTIPLY), leaf("a"), Node::Internal(Operator::MULTIPLY), leaf("b"), leaf("c")]),
+            Equation::new(vec![Node::Internal(Operator::MULTIPLY), Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b"), leaf("c")]),
+        ] {
+            let after = try_replace_identity(&tree, (&pattern, &replacement), &theory, &Guard::new()).unwrap();
+            assert_eq!(results(after), vec![vec![Node::Internal(Operator::MULTIPLY), leaf("d"), leaf("b")]]);
+            assert!(try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &Guard::new()).unwrap().is_empty());
+
+            let root: Equation = replace_identity(&tree, (&pattern, &replacement), &Location::root(), &theory, &Guard::new()).unwrap().unwrap();
+            assert_eq!(root.tokens().to_vec(), vec![Node::Internal(Operator::MULTIPLY), leaf("d"), leaf("b")]);
+            let inner = Location::of_path(tree.tokens(), &[1]).unwrap();
+            assert!(replace_identity(&tree, (&pattern, &replacement), &inner, &theory, &Guard::new()).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_matching_modulo_commutativity() {
+        let theory = Theory::new().commutative(&Operator::MULTIPLY).commutative(&Operator::ADD);
+
+        // x*(y+z) = x*y + x*z
+        let pattern = Identity::new(vec![
+            Node::Internal(Operator::MULTIPLY),
+            wildcard("x"),
+            Node::Internal(Operator::ADD),
+            wildcard("y"),
+            wildcard("z"),
+        ]);
+        let replacement = Identity::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY),
+            wildcard("x"),
+            wildcard("y"),
+            Node::Internal(Operator::MULTIPLY),
+            wildcard("x"),
+            wildcard("z"),
+        ]);
+
+        // (b+c)*a
+        let tree = Equation::new(vec![Node::Internal(Operator::MULTIPLY), Node::Internal(Operator::ADD), leaf("b"), leaf("c"), leaf("a")]);
+
+        let after = results(try_replace_identity(&tree, (&pattern, &replacement), &theory, &Guard::new()).unwrap());
+        let expand = |y: &str, z: &str| vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf(y),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf(z),
+        ];
+        assert_eq!(after.len(), 2);
+        assert!(after.contains(&expand("b", "c")));
+        assert!(after.contains(&expand("c", "b")));
+
+        // A repeated wildcard matches subtrees which are only equal modulo the theory: a*b + b*a
+        let doubled = Identity::new(vec![Node::Internal(Operator::ADD), wildcard("x"), wildcard("x")]);
+        let once = Identity::new(vec![wildcard("x")]);
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b"),
+            Node::Internal(Operator::MULTIPLY), leaf("b"), leaf("a"),
+        ]);
+        assert_eq!(try_replace_identity(&tree, (&doubled, &once), &theory, &Guard::new()).unwrap().len(), 1);
+        assert!(try_replace_identity(&tree, (&doubled, &once), &Theory::new(), &Guard::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_matching_a_long_chain_is_lazy_and_bounded() {
+        let theory = Theory::new().associative(&Operator::ADD).commutative(&Operator::ADD);
+        let (pattern, replacement) = commutativity(Operator::ADD);
+
+        // a0 + a1 + ... + a19, which x+y matches in about 3^20 ways
+        let mut tokens = vec![Node::Internal(Operator::ADD); 19];
+        tokens.extend((0..20).map(|i| leaf(&format!("a{i}"))));
+        let tree = Equation::new(tokens);
+
+        assert!(replace_identity(&tree, (&pattern, &replacement), &Location::root(), &theory, &Guard::new()).unwrap().is_some());
+
+        let rewrites = try_replace_identity(&tree, (&pattern, &replacement), &theory.clone().limit_matches(50), &Guard::new()).unwrap();
+        assert_eq!(rewrites.len(), 50);
+        assert!(rewrites.iter().all(|(_, location)| *location == Location::root()));
+        let distinct: Vec<&Equation> = rewrites.iter().map(|(tree, _)| tree).collect();
+        assert!(distinct.iter().enumerate().all(|(i, tree)| !distinct[..i].contains(tree)));
+    }
+
+    #[test]
+    fn test_theory_on_unused_operators_is_syntactic() {
+        // Forces the theory-aware matcher, without declaring anything about the operators in play
+        let theory = Theory::new().associative(&Operator::DIVIDE).commutative(&Operator::DIVIDE);
+
+        // x*y + x*z = x*(y + z)
+        let pattern = Identity::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), wildcard("x"), wildcard("y"),
+            Node::Internal(Operator::MULTIPLY), wildcard("x"), wildcard("z"),
+        ]);
+        let replacement = Identity::new(vec![
+            Node::Internal(Operator::MULTIPLY), wildcard("x"),
+            Node::Internal(Operator::ADD), wildcard("y"), wildcard("z"),
+        ]);
+
+        // a*b + a*(a*b + a*c)
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b"),
+            Node::Internal(Operator::MULTIPLY), leaf("a"),
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b"),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("c"),
+        ]);
+
+        let syntactic: Vec<(Equation, Location)> = try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &Guard::new()).unwrap();
+        assert_eq!(syntactic.len(), 2);
+        assert_eq!(syntactic, try_replace_identity(&tree, (&pattern, &replacement), &theory, &Guard::new()).unwrap());
+    }
+
+    #[test]
+    fn test_guarded_replacement() {
+        // x+y = y+x, only when y < x
+        let pattern = Identity::new(vec![Node::Internal(Operator::ADD), wildcard("x"), wildcard("y")]);
+        let replacement = Identity::new(vec![Node::Internal(Operator::ADD), wildcard("y"), wildcard("x")]);
+        let ordered = Guard::new().when(Condition::Ordered("y".to_string(), "x".to_string()));
+
+        let sorted = Equation::new(vec![Node::Internal(Operator::ADD), leaf("a"), leaf("b")]);
+        let unsorted = Equation::new(vec![Node::Internal(Operator::ADD), leaf("b"), leaf("a")]);
+
+        assert!(try_replace_identity(&sorted, (&pattern, &replacement), &Theory::new(), &ordered).unwrap().is_empty());
+        assert_eq!(
+            results(try_replace_identity(&unsorted, (&pattern, &replacement), &Theory::new(), &ordered).unwrap()),
+            vec![sorted.tokens().to_vec()],
+        );
+        let first: Option<Equation> = replace_identity(&sorted, (&pattern, &replacement), &Location::root(), &Theory::new(), &ordered).unwrap();
+        assert!(first.is_none());
+
+        // x*x = x, for leaves other than 0: (a*a) + (0*0) + ((a*b)*(a*b))
+        let pattern = Identity::new(vec![Node::Internal(Operator::MULTIPLY), wildcard("x"), wildcard("x")]);
+        let replacement = Identity::new(vec![wildcard("x")]);
+        let guard = Guard::new()
+            .when(Condition::IsLeaf("x".to_string()))
+            .when(Condition::IsNot("x".to_string(), Element { label: b"0".to_vec() }));
+
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("a"),
+            Node::Internal(Operator::MULTIPLY), leaf("0"), leaf("0"),
+            Node::Internal(Operator::MULTIPLY),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b"),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b"),
+        ]);
+
+        assert_eq!(try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &Guard::new()).unwrap().len(), 3);
+        let after = results(try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &guard).unwrap());
+        assert_eq!(after.len(), 1);
+        assert_eq!(after[0][2], leaf("a"));
+
+        // Custom conditions see the bound subtrees
+        let small = Guard::new().when(Condition::Custom(std::sync::Arc::new(|substitution| {
+            substitution.get("x").is_some_and(|x| x.len() > 1)
+        })));
+        assert_eq!(try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &small).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_guarded_replacement_modulo_theory() {
+        let theory = Theory::new().associative(&Operator::ADD).commutative(&Operator::ADD);
+
+        // x+y = y+x, only when y < x, inside the chain a+(c+b)
+        let pattern = Identity::new(vec![Node::Internal(Operator::ADD), wildcard("x"), wildcard("y")]);
+        let replacement = Identity::new(vec![Node::Internal(Operator::ADD), wildcard("y"), wildcard("x")]);
+        let tree = Equation::new(vec![Node::Internal(Operator::ADD), leaf("a"), Node::Internal(Operator::ADD), leaf("c"), leaf("b")]);
+
+        let unguarded = try_replace_identity(&tree, (&pattern, &replacement), &theory, &Guard::new()).unwrap();
+        let ordered = Guard::new().when(Condition::Ordered("y".to_string(), "x".to_string()));
+        let guarded = try_replace_identity(&tree, (&pattern, &replacement), &theory, &ordered).unwrap();
+
+        assert!(!guarded.is_empty());
+        assert!(guarded.len() < unguarded.len());
+    }
+
+    #[test]
+    fn test_guard_with_unbound_wildcard() {
+        let pattern = Identity::new(vec![Node::Internal(Operator::ADD), wildcard("x"), wildcard("y")]);
+        let replacement = Identity::new(vec![wildcard("x")]);
+        let guard = Guard::new().when(Condition::Distinct("x".to_string(), "z".to_string()));
+        let tree = Equation::new(vec![Node::Internal(Operator::ADD), leaf("a"), leaf("b")]);
+
+        assert!(matches!(
+            replace_identity(&tree, (&pattern, &replacement), &Location::root(), &Theory::new(), &guard),
+            Err(ReplacementError::UnknownWildcard(name)) if name == "z"
+        ));
+        assert!(try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &guard).is_err());
+    }
+
+    #[test]
+    fn test_match_locations() {
+        // x*y = y*x
+        let pattern = Identity::new(vec![Node::Internal(Operator::MULTIPLY), wildcard("x"), wildcard("y")]);
+        let replacement = Identity::new(vec![Node::Internal(Operator::MULTIPLY), wildcard("y"), wildcard("x")]);
+
+        // a*b + c*d
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b"),
+            Node::Internal(Operator::MULTIPLY), leaf("c"), leaf("d"),
+        ]);
+
+        let locations: Vec<Location> = try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &Guard::new())
+            .unwrap()
+            .into_iter()
+            .map(|(_, location)| location)
+            .collect();
+        assert_eq!(locations, vec![
+            Location { index: 1, path: vec![0] },
+            Location { index: 4, path: vec![1] },
+        ]);
+
+        let after: Equation = replace_identity(&tree, (&pattern, &replacement), &locations[1], &Theory::new(), &Guard::new()).unwrap().unwrap();
+        assert_eq!(after, Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b"),
+            Node::Internal(Operator::MULTIPLY), leaf("d"), leaf("c"),
+        ]));
+
+        // A location whose index and path disagree, or which is outside the tree, is rejected rather than read by index
+        for location in [Location { index: 1, path: vec![5, 5] }, Location { index: 4, path: vec![0] }, Location { index: 7, path: vec![] }] {
+            let invalid = Err(ReplacementError::InvalidLocation(location.clone()));
+            assert_eq!(replace_identity(&tree, (&pattern, &replacement), &location, &Theory::new(), &Guard::new()), invalid);
+            assert_eq!(regex::replace_identity(&tree, (&pattern, &replacement), &location), invalid);
+            assert_eq!(Rule::compile(pattern.clone(), replacement.clone()).unwrap().apply_each_at(&tree, &location), invalid.map(|_| vec![]));
+        }
+    }
+
+    #[test]
+    fn test_operator_and_leaf_wildcards() {
+        let operator = |name: &str| Node::Leaf(PatternLeaf::Operator(name.to_string(), 2));
+        let leaf_wildcard = |name: &str| Node::Leaf(PatternLeaf::Leaf(name.to_string()));
+
+        // (a + (b + c)) - (d * (e * f))
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::SUBTRACT),
+            Node::Internal(Operator::ADD), leaf("a"), Node::Internal(Operator::ADD), leaf("b"), leaf("c"),
+            Node::Internal(Operator::MULTIPLY), leaf("d"), Node::Internal(Operator::MULTIPLY), leaf("e"), leaf("f"),
+        ]);
+
+        // x o (y o z) = (x o y) o z, for any operator o
+        let associativity = (
+            Identity::new(vec![operator("o"), wildcard("x"), operator("o"), wildcard("y"), wildcard("z")]),
+            Identity::new(vec![operator("o"), operator("o"), wildcard("x"), wildcard("y"), wildcard("z")]),
+        );
+        // l o x = x o l, for any leaf l
+        let swap_leaf = (
+            Identity::new(vec![operator("o"), leaf_wildcard("l"), wildcard("x")]),
+            Identity::new(vec![operator("o"), wildcard("x"), leaf_wildcard("l")]),
+        );
+
+        let expected_paths: [&[&[usize]]; 2] = [&[&[0], &[1]], &[&[0], &[0, 1], &[1], &[1, 1]]];
+        for ((pattern, replacement), paths) in [&associativity, &swap_leaf].into_iter().zip(expected_paths) {
+            let native: Vec<(Equation, Location)> = try_replace_identity(&tree, (pattern, replacement), &Theory::new(), &Guard::new()).unwrap();
+            let regex: Vec<(Equation, Location)> = regex::try_replace_identity(&tree, (pattern, replacement)).unwrap();
+            assert_eq!(native, regex);
+
+            let locations: Vec<Location> = native.iter().map(|(_, location)| location.clone()).collect();
+            let expected: Vec<Location> = paths.iter().map(|path| Location::of_path(tree.tokens(), path).unwrap()).collect();
+            assert_eq!(locations, expected);
+        }
+
+        let after: Equation = replace_identity(&tree, (&associativity.0, &associativity.1), &Location::of_path(tree.tokens(), &[1]).unwrap(), &Theory::new(), &Guard::new()).unwrap().unwrap();
+        assert_eq!(after, Equation::new(vec![
+            Node::Internal(Operator::SUBTRACT),
+            Node::Internal(Operator::ADD), leaf("a"), Node::Internal(Operator::ADD), leaf("b"), leaf("c"),
+            Node::Internal(Operator::MULTIPLY), Node::Internal(Operator::MULTIPLY), leaf("d"), leaf("e"), leaf("f"),
+        ]));
+
+        // Modulo associativity the bound operator's chain is flattened, so the leaf can be swapped past the whole rest
+        let theory = Theory::new().associative(&Operator::ADD).commutative(&Operator::ADD);
+        let rewrites: Vec<Equation> = try_replace_identity(&tree, (&swap_leaf.0, &swap_leaf.1), &theory, &Guard::new())
+            .unwrap()
+            .into_iter()
+            .map(|(tree, _)| tree)
+            .collect();
+        assert!(rewrites.contains(&Equation::new(vec![
+            Node::Internal(Operator::SUBTRACT),
+            Node::Internal(Operator::ADD), Node::Internal(Operator::ADD), leaf("b"), leaf("c"), leaf("a"),
+            Node::Internal(Operator::MULTIPLY), leaf("d"), Node::Internal(Operator::MULTIPLY), leaf("e"), leaf("f"),
+        ])));
+
+        // An operator wildcard can't also stand for a subtree, or for operators of a different arity
+        let (pattern, _) = &associativity;
+        for replacement in [Identity::new(vec![wildcard("o")]), Identity::new(vec![Node::Leaf(PatternLeaf::Operator("o".to_string(), 1)), wildcard("x")])] {
+            assert!(matches!(
+                try_replace_identity(&tree, (pattern, &replacement), &Theory::new(), &Guard::new()),
+                Err(ReplacementError::WildcardKindMismatch(name)) if name == "o"
+            ));
+        }
+    }
+
+    #[test]
+    fn test_sequence_wildcards() {
+        let sequence = |name: &str| Node::Leaf(PatternLeaf::Sequence(name.to_string()));
+        let zero = || Node::Leaf(PatternLeaf::Literal(Element { label: b"0".to_vec() }));
+        let theory = Theory::new().associative(&Operator::ADD).commutative(&Operator::ADD);
+
+        // x + 0 + ...rest = x + ...rest
+        let pattern = Identity::new(vec![Node::Internal(Operator::ADD), wildcard("x"), Node::Internal(Operator::ADD), zero(), sequence("rest")]);
+        let replacement = Identity::new(vec![Node::Internal(Operator::ADD), wildcard("x"), sequence("rest")]);
+
+        // a + (0 + (b + c))
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::ADD), leaf("a"),
+            Node::Internal(Operator::ADD), leaf("0"),
+            Node::Internal(Operator::ADD), leaf("b"), leaf("c"),
+        ]);
+        let rewrites = try_replace_identity(&tree, (&pattern, &replacement), &theory, &Guard::new()).unwrap();
+        assert!(!rewrites.is_empty());
+        for (rewritten, location) in &rewrites {
+            assert_eq!(*location, Location::root());
+            assert_eq!(rewritten.tokens().len(), 5);
+            assert!(!rewritten.tokens().contains(&leaf("0")));
+        }
+        assert!(rewrites.iter().any(|(rewritten, _)| *rewritten == Equation::new(vec![
+            Node::Internal(Operator::ADD), Node::Internal(Operator::ADD), leaf("a"), leaf("b"), leaf("c"),
+        ])));
+
+        // With nothing left for the sequence, the sum collapses to its one remaining argument
+        let tree = Equation::new(vec![Node::Internal(Operator::SUBTRACT), Node::Internal(Operator::ADD), leaf("0"), leaf("a"), leaf("b")]);
+        let rewrites: Vec<(Equation, Location)> = try_replace_identity(&tree, (&pattern, &replacement), &theory, &Guard::new()).unwrap();
+        assert_eq!(rewrites, vec![(
+            Equation::new(vec![Node::Internal(Operator::SUBTRACT), leaf("a"), leaf("b")]),
+            Location::of_path(tree.tokens(), &[0]).unwrap(),
+        )]);
+
+        // The regex matcher can't flatten chains, so it turns sequences away rather than matching them differently
+        assert!(matches!(
+            regex::try_replace_identity::<Element, Operator, Equation, Identity>(&tree, (&pattern, &replacement)),
+            Err(ReplacementError::RegexBackend(_))
+        ));
+
+        // A sequence wildcard can't also stand for a single subtree
+        let replacement = Identity::new(vec![wildcard("rest")]);
+        assert!(matches!(
+            try_replace_identity(&tree, (&pattern, &replacement), &theory, &Guard::new()),
+            Err(ReplacementError::WildcardKindMismatch(name)) if name == "rest"
+        ));
+    }
+
+    #[test]
+    fn test_sequence_wildcards_on_variadic_nodes() {
+        let sequence = |name: &str| Node::Leaf(PatternLeaf::Sequence(name.to_string()));
+        let zero = || Node::Leaf(PatternLeaf::Literal(Element { label: b"0".to_vec() }));
+
+        // +(x, 0, ...rest) = +(x, ...rest), with no theory, so the sum is rebuilt at its new arity rather than as a chain
+        let pattern = Identity::new(vec![Node::Internal(Operator::SUM(3)), wildcard("x"), zero(), sequence("rest")]);
+        let replacement = Identity::new(vec![Node::Internal(Operator::ADD), wildcard("x"), sequence("rest")]);
+
+        let tree = Equation::new(vec![Node::Internal(Operator::SUM(4)), leaf("a"), leaf("0"), leaf("b"), leaf("c")]);
+        let rewrites = try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &Guard::new()).unwrap();
+        assert_eq!(rewrites, vec![(
+            Equation::new(vec![Node::Internal(Operator::SUM(3)), leaf("a"), leaf("b"), leaf("c")]),
+            Location::root(),
+        )]);
+
+        let tree = Equation::new(vec![Node::Internal(Operator::SUM(3)), leaf("a"), leaf("0"), leaf("b")]);
+        let rewrites = try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &Guard::new()).unwrap();
+        assert_eq!(rewrites, vec![(
+            Equation::new(vec![Node::Internal(Operator::ADD), leaf("a"), leaf("b")]),
+            Location::root(),
+        )]);
+
+        // An operator which can't be resized still can't take the sequence
+        let replacement = Identity::new(vec![Node::Internal(Operator::SUBTRACT), wildcard("x"), sequence("rest")]);
+        let tree = Equation::new(vec![Node::Internal(Operator::SUM(4)), leaf("a"), leaf("0"), leaf("b"), leaf("c")]);
+        let rewrites = try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &Guard::new()).unwrap();
+        assert!(rewrites.is_empty());
+    }
 }
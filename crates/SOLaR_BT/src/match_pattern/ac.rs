@@ -0,0 +1,673 @@
+use std::collections::HashSet;
+use std::iter;
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::PatternLeaf;
+use crate::guard::{self, Guard};
+use crate::theory::Theory;
+use crate::tree::{Node, TreeNode};
+use super::native::has_sequences;
+use super::regex::node_label;
+
+/// A subtree, with chains of an associative operator flattened into a single node with many arguments.
+///
+/// The arguments are shared, so that substitutions can keep hold of them while matches are produced lazily.
+#[derive(Clone, Debug)]
+pub(super) struct Term {
+    range: Range<usize>,
+    args: Vec<Arc<Term>>,
+}
+
+pub(super) fn flatten<X: TreeNode, I: TreeNode>(tokens: &[Node<X, I>], start: usize, theory: &Theory) -> Option<Term> {
+    let token = tokens.get(start)?;
+    // Operator wildcards stand for no operator in particular, so their chains are never flattened
+    let associative = match token {
+        Node::Internal(o) => theory.properties(o).associative,
+        Node::Leaf(_) => false,
+    };
+    let mut args = vec![];
+    let mut cursor = start + 1;
+
+    for _ in 0..token.arity() {
+        let child = flatten(tokens, cursor, theory)?;
+        cursor = child.range.end;
+        match (token, &tokens[child.range.start]) {
+            (Node::Internal(operator), Node::Internal(o)) if associative && o.to_string() == operator.to_string() => {
+                args.extend(child.args)
+            },
+            _ => args.push(Arc::new(child)),
+        }
+    }
+
+    Some(Term { range: start..cursor, args })
+}
+
+/// Mark the internal nodes which sit inside a chain of the same associative operator. These are covered by matching
+/// against part of the flattened chain, so aren't match positions in their own right.
+fn chain_interiors<L: TreeNode, I: TreeNode>(tokens: &[Node<L, I>], theory: &Theory) -> Vec<bool> {
+    let mut interiors = Vec::with_capacity(tokens.len());
+    // Open internal nodes and the number of children they are still waiting for
+    let mut open: Vec<(usize, usize)> = vec![];
+
+    for (index, token) in tokens.iter().enumerate() {
+        let interior = match (open.last(), token) {
+            (Some((parent, _)), Node::Internal(o)) => match &tokens[*parent] {
+                Node::Internal(p) => theory.properties(p).associative && p.to_string() == o.to_string(),
+                Node::Leaf(_) => false,
+            },
+            _ => false,
+        };
+        interiors.push(interior);
+
+        if let Some((_, remaining)) = open.last_mut() {
+            *remaining -= 1;
+            if *remaining == 0 {
+                open.pop();
+            }
+        }
+        if let Node::Internal(o) = token {
+            if o.arity() > 0 {
+                open.push((index, o.arity()));
+            }
+        }
+    }
+
+    interiors
+}
+
+/// The subject terms bound to a wildcard. More than one term means the wildcard absorbed several arguments, and stands
+/// for those terms joined by `operator`. Only sequence wildcards may be bound to no terms at all.
+#[derive(Clone)]
+struct Block {
+    operator: usize,
+    terms: Vec<Arc<Term>>,
+}
+
+/// What a wildcard is bound to.
+#[derive(Clone)]
+enum Bound {
+    Block(Block),
+    /// The token of the operator an operator wildcard stands for
+    Operator(usize),
+}
+
+type Substitution<'p> = Vec<(&'p str, Bound)>;
+
+/// The substitutions under which part of a pattern matches, produced lazily as the search goes.
+type Matches<'p> = Box<dyn Iterator<Item = Substitution<'p>> + 'p>;
+
+/// The arguments picked from part of a chain, paired with how to rebuild the chain around the replacement.
+type PartialChains = Box<dyn Iterator<Item = (Vec<Arc<Term>>, Vec<Piece>)>>;
+
+/// Part of a rebuilt associative chain.
+#[derive(Clone)]
+enum Piece {
+    Term(Arc<Term>),
+    Replacement,
+}
+
+struct Matcher<'p, L: TreeNode, I: TreeNode> {
+    tokens: &'p [Node<L, I>],
+    pattern: &'p [Node<PatternLeaf<L>, I>],
+    theory: &'p Theory,
+}
+
+// Matching hands copies of the matcher to the iterators it returns, so it's copyable whatever the tokens are
+impl<L: TreeNode, I: TreeNode> Clone for Matcher<'_, L, I> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<L: TreeNode, I: TreeNode> Copy for Matcher<'_, L, I> {}
+
+impl<'p, L: TreeNode + Clone, I: TreeNode + Clone> Matcher<'p, L, I> {
+
+    /// The name of `p` if it's a wildcard which can take a varying number of arguments, alongside the fewest it can
+    /// take. Sequence wildcards can take none, and subtree wildcards at least one when `absorbing` part of an
+    /// associative chain.
+    fn variadic(&self, p: &Term, absorbing: bool) -> Option<(&'p str, usize)> {
+        match &self.pattern[p.range.start] {
+            Node::Leaf(PatternLeaf::Subtree(name)) if absorbing => Some((name, 1)),
+            Node::Leaf(PatternLeaf::Sequence(name)) => Some((name, 0)),
+            _ => None,
+        }
+    }
+
+    /// A string which is equal for two terms exactly when they are equal modulo the theory.
+    fn canonical(&self, term: &Term) -> String {
+        self.canonical_apply(term.range.start, &term.args)
+    }
+
+    fn canonical_apply(&self, root: usize, args: &[Arc<Term>]) -> String {
+        let label = node_label(&self.tokens[root]);
+        if args.is_empty() {
+            return label;
+        }
+
+        let mut args: Vec<String> = args.iter().map(|arg| self.canonical(arg)).collect();
+        if let Node::Internal(o) = &self.tokens[root] {
+            if self.theory.properties(o).commutative {
+                args.sort();
+            }
+        }
+        format!("{}({})", label, args.join(")("))
+    }
+
+    fn canonical_bound(&self, bound: &Bound) -> String {
+        match bound {
+            Bound::Block(block) => match block.terms.as_slice() {
+                [] => String::new(),
+                [term] => self.canonical(term),
+                terms => self.canonical_apply(block.operator, terms),
+            },
+            Bound::Operator(operator) => node_label(&self.tokens[*operator]),
+        }
+    }
+
+    fn bind(&self, name: &'p str, bound: Bound, mut substitution: Substitution<'p>) -> Option<Substitution<'p>> {
+        match substitution.iter().find(|(b, _)| *b == name) {
+            Some((_, existing)) => {
+                if self.canonical_bound(existing) == self.canonical_bound(&bound) {
+                    Some(substitution)
+                } else {
+                    None
+                }
+            },
+            None => {
+                substitution.push((name, bound));
+                Some(substitution)
+            },
+        }
+    }
+
+    /// Every extension of `substitution` under which `p` matches `s`.
+    fn match_term(self, p: &'p Term, s: &Arc<Term>, substitution: Substitution<'p>) -> Matches<'p> {
+        match &self.pattern[p.range.start] {
+            Node::Leaf(PatternLeaf::Subtree(name) | PatternLeaf::Sequence(name)) => {
+                let block = Block { operator: s.range.start, terms: vec![s.clone()] };
+                Box::new(self.bind(name, Bound::Block(block), substitution).into_iter())
+            },
+            Node::Leaf(PatternLeaf::Leaf(name)) => match &self.tokens[s.range.start] {
+                Node::Leaf(_) => {
+                    let block = Block { operator: s.range.start, terms: vec![s.clone()] };
+                    Box::new(self.bind(name, Bound::Block(block), substitution).into_iter())
+                },
+                Node::Internal(_) => Box::new(iter::empty()),
+            },
+            Node::Leaf(PatternLeaf::Literal(l)) => match &self.tokens[s.range.start] {
+                Node::Leaf(e) if e.uid() == l.uid() => Box::new(iter::once(substitution)),
+                _ => Box::new(iter::empty()),
+            },
+            Node::Leaf(PatternLeaf::Operator(name, arity)) => match &self.tokens[s.range.start] {
+                // The children are matched modulo the properties of whichever operator was bound
+                Node::Internal(so) if so.arity() == *arity => match self.bind(name, Bound::Operator(s.range.start), substitution) {
+                    Some(sub) => self.match_args(so, &p.args, s.range.start, s.args.clone(), sub),
+                    None => Box::new(iter::empty()),
+                },
+                _ => Box::new(iter::empty()),
+            },
+            Node::Internal(po) => match &self.tokens[s.range.start] {
+                // Associative chains and sequence wildcards take however many arguments there are, otherwise a node
+                // only matches one with the same arity
+                Node::Internal(so) if so.to_string() == po.to_string()
+                    && (so.arity() == po.arity() || self.theory.properties(po).associative || self.has_sequence(&p.args)) => {
+                    self.match_args(po, &p.args, s.range.start, s.args.clone(), substitution)
+                },
+                _ => Box::new(iter::empty()),
+            },
+        }
+    }
+
+    fn has_sequence(&self, pargs: &[Arc<Term>]) -> bool {
+        pargs.iter().any(|p| matches!(self.pattern[p.range.start], Node::Leaf(PatternLeaf::Sequence(_))))
+    }
+
+    fn match_args(self, operator: &I, pargs: &'p [Arc<Term>], s_operator: usize, sargs: Vec<Arc<Term>>, substitution: Substitution<'p>) -> Matches<'p> {
+        let properties = self.theory.properties(operator);
+        let sequences = self.has_sequence(pargs);
+        match (properties.associative, properties.commutative) {
+            (false, false) if !sequences => self.match_in_order(pargs, sargs, substitution),
+            (false, true) if !sequences => {
+                if pargs.len() != sargs.len() {
+                    return Box::new(iter::empty());
+                }
+                Box::new(permutations(sargs.len()).flat_map(move |permutation| {
+                    let permuted = permutation.into_iter().map(|i| sargs[i].clone()).collect();
+                    self.match_in_order(pargs, permuted, substitution.clone())
+                }))
+            },
+            (associative, false) => self.match_sequence(pargs, s_operator, sargs, substitution, associative),
+            (associative, true) => self.match_multiset(pargs, s_operator, sargs, substitution, associative),
+        }
+    }
+
+    fn match_in_order(self, pargs: &'p [Arc<Term>], sargs: Vec<Arc<Term>>, substitution: Substitution<'p>) -> Matches<'p> {
+        if pargs.len() != sargs.len() {
+            return Box::new(iter::empty());
+        }
+
+        pargs.iter().zip(sargs).fold(Box::new(iter::once(substitution)), |solutions, (p, s)| {
+            Box::new(solutions.flat_map(move |sub| self.match_term(p, &s, sub)))
+        })
+    }
+
+    /// Match arguments in order: each pattern argument takes a contiguous run of subject arguments, and only
+    /// [variadic](Self::variadic) wildcards may take other than one.
+    fn match_sequence(
+            self,
+            pargs: &'p [Arc<Term>],
+            operator: usize,
+            sargs: Vec<Arc<Term>>,
+            substitution: Substitution<'p>,
+            absorbing: bool,
+        ) -> Matches<'p> {
+
+        let (p, rest) = match pargs.split_first() {
+            Some(split) => split,
+            None if sargs.is_empty() => return Box::new(iter::once(substitution)),
+            None => return Box::new(iter::empty()),
+        };
+        // The fewest subject arguments the rest of the pattern can take
+        let needed: usize = rest.iter().map(|p| self.variadic(p, absorbing).map_or(1, |(_, fewest)| fewest)).sum();
+        if sargs.len() < needed {
+            return Box::new(iter::empty());
+        }
+
+        match self.variadic(p, absorbing) {
+            None => match sargs.split_first() {
+                Some((s, srest)) => {
+                    let srest = srest.to_vec();
+                    Box::new(self.match_term(p, s, substitution)
+                        .flat_map(move |sub| self.match_sequence(rest, operator, srest.clone(), sub, absorbing)))
+                },
+                None => Box::new(iter::empty()),
+            },
+            Some((name, fewest)) => Box::new((fewest..=sargs.len() - needed).flat_map(move |end| {
+                let block = Block { operator, terms: sargs[..end].to_vec() };
+                match self.bind(name, Bound::Block(block), substitution.clone()) {
+                    Some(sub) => self.match_sequence(rest, operator, sargs[end..].to_vec(), sub, absorbing),
+                    None => Box::new(iter::empty()),
+                }
+            })),
+        }
+    }
+
+    /// Match the arguments of a commutative operator: every subject argument goes to exactly one pattern argument, in
+    /// any order, and only [variadic](Self::variadic) wildcards may take other than one.
+    fn match_multiset(
+            self,
+            pargs: &'p [Arc<Term>],
+            operator: usize,
+            sargs: Vec<Arc<Term>>,
+            substitution: Substitution<'p>,
+            absorbing: bool,
+        ) -> Matches<'p> {
+
+        let mut wildcards = vec![];
+        let mut fixed = vec![];
+        for p in pargs {
+            match self.variadic(p, absorbing) {
+                Some(wildcard) => wildcards.push(wildcard),
+                None => fixed.push(&**p),
+            }
+        }
+        if sargs.len() < fixed.len() + wildcards.iter().map(|(_, fewest)| fewest).sum::<usize>() {
+            return Box::new(iter::empty());
+        }
+
+        let used = vec![false; sargs.len()];
+        self.assign_fixed(fixed, Rc::new(wildcards), operator, Rc::new(sargs), used, substitution)
+    }
+
+    fn assign_fixed(
+            self,
+            fixed: Vec<&'p Term>,
+            wildcards: Rc<Vec<(&'p str, usize)>>,
+            operator: usize,
+            sargs: Rc<Vec<Arc<Term>>>,
+            used: Vec<bool>,
+            substitution: Substitution<'p>,
+        ) -> Matches<'p> {
+
+        let Some((&p, _)) = fixed.split_first() else {
+            return self.distribute(&wildcards, operator, &sargs, &used, substitution);
+        };
+
+        let unused: Vec<usize> = (0..sargs.len()).filter(|&j| !used[j]).collect();
+        Box::new(unused.into_iter().flat_map(move |j| {
+            let mut used = used.clone();
+            used[j] = true;
+            let (rest, wildcards, sargs) = (fixed[1..].to_vec(), wildcards.clone(), sargs.clone());
+            self.match_term(p, &sargs[j], substitution.clone()).flat_map(move |sub| {
+                self.assign_fixed(rest.clone(), wildcards.clone(), operator, sargs.clone(), used.clone(), sub)
+            })
+        }))
+    }
+
+    /// Hand out the unused subject arguments to the wildcards, which are paired with the fewest each can take, in every
+    /// way which gives each wildcard enough.
+    fn distribute(
+            self,
+            wildcards: &[(&'p str, usize)],
+            operator: usize,
+            sargs: &[Arc<Term>],
+            used: &[bool],
+            substitution: Substitution<'p>,
+        ) -> Matches<'p> {
+
+        let remaining: Vec<Arc<Term>> = sargs.iter().zip(used).filter(|(_, used)| !**used).map(|(s, _)| s.clone()).collect();
+
+        if wildcards.is_empty() {
+            return match remaining.is_empty() {
+                true => Box::new(iter::once(substitution)),
+                false => Box::new(iter::empty()),
+            };
+        }
+        if remaining.len() < wildcards.iter().map(|(_, fewest)| fewest).sum() {
+            return Box::new(iter::empty());
+        }
+
+        // Which wildcard each remaining argument goes to
+        let wildcards = wildcards.to_vec();
+        Box::new(Odometer::new(vec![wildcards.len(); remaining.len()]).filter_map(move |choice| {
+            if !wildcards.iter().enumerate().all(|(w, (_, fewest))| choice.iter().filter(|c| **c == w).count() >= *fewest) {
+                return None;
+            }
+            let mut sub = Some(substitution.clone());
+            for (w, (name, _)) in wildcards.iter().enumerate() {
+                let terms = remaining.iter().zip(&choice).filter(|(_, c)| **c == w).map(|(s, _)| s.clone()).collect();
+                sub = sub.and_then(|sub| self.bind(name, Bound::Block(Block { operator, terms }), sub));
+            }
+            sub
+        }))
+    }
+
+    fn key(&self, substitution: &Substitution, remainder: &[Piece]) -> String {
+        let mut bindings: Vec<String> = substitution.iter()
+            .map(|(name, bound)| format!("{}={}", name, self.canonical_bound(bound)))
+            .collect();
+        bindings.sort();
+
+        let remainder: Vec<String> = remainder.iter().map(|piece| match piece {
+            Piece::Term(term) => self.canonical(term),
+            Piece::Replacement => String::from("_"),
+        }).collect();
+
+        format!("{}|{}", bindings.join(","), remainder.join(","))
+    }
+
+    /// Push the tokens of the replacement with every wildcard substituted.
+    ///
+    /// Returns `None` if a sequence wildcard leaves a node of the replacement with a number of arguments it can't be
+    /// built with.
+    fn instantiate(&self, replacement: &[Node<PatternLeaf<L>, I>], substitution: &Substitution, out: &mut Vec<Node<L, I>>) -> Option<()> {
+        if has_sequences(replacement) {
+            let expanded = self.expand(replacement, &flatten(replacement, 0, self.theory)?, substitution)?;
+            let [tokens] = expanded.as_slice() else {
+                return None;
+            };
+            out.extend_from_slice(tokens);
+            return Some(());
+        }
+
+        for token in replacement {
+            match token {
+                Node::Internal(o) => out.push(Node::Internal(o.clone())),
+                Node::Leaf(PatternLeaf::Literal(l)) => out.push(Node::Leaf(l.clone())),
+                Node::Leaf(wildcard) => self.push_bound(self.bound(substitution, wildcard.wildcard()?), out),
+            }
+        }
+        Some(())
+    }
+
+    /// The terms the subterm `r` of the (flattened) replacement stands for, as the tokens of each. A sequence wildcard
+    /// stands for as many terms as it was bound to, and anything else for one.
+    ///
+    /// Nodes are rebuilt with however many arguments they end up with, repeating associative operators as needed, so
+    /// an associative operator left with a single argument is dropped. Other operators are resized with
+    /// [`TreeNode::with_arity`], and the replacement fails if they can't be.
+    fn expand(&self, replacement: &[Node<PatternLeaf<L>, I>], r: &Term, substitution: &Substitution) -> Option<Vec<Vec<Node<L, I>>>> {
+        let head = match &replacement[r.range.start] {
+            Node::Internal(o) => Node::Internal(o.clone()),
+            Node::Leaf(PatternLeaf::Literal(l)) => return Some(vec![vec![Node::Leaf(l.clone())]]),
+            Node::Leaf(PatternLeaf::Sequence(name)) => match self.bound(substitution, name) {
+                Bound::Block(block) => return Some(block.terms.iter().map(|term| self.tokens[term.range.clone()].to_vec()).collect()),
+                Bound::Operator(_) => return None,
+            },
+            Node::Leaf(PatternLeaf::Operator(name, _)) => match self.bound(substitution, name) {
+                Bound::Operator(operator) => self.tokens[*operator].clone(),
+                Bound::Block(_) => return None,
+            },
+            Node::Leaf(PatternLeaf::Subtree(name) | PatternLeaf::Leaf(name)) => {
+                let mut tokens = vec![];
+                self.push_bound(self.bound(substitution, name), &mut tokens);
+                return Some(vec![tokens]);
+            },
+        };
+
+        let mut args = vec![];
+        for arg in &r.args {
+            args.extend(self.expand(replacement, arg, substitution)?);
+        }
+        let associative = match &head {
+            Node::Internal(o) => self.theory.properties(o).associative,
+            Node::Leaf(_) => false,
+        };
+
+        let mut tokens = vec![];
+        if args.len() == head.arity() {
+            tokens.push(head);
+        } else if associative && !args.is_empty() {
+            tokens.extend((1..args.len()).map(|_| head.clone()));
+        } else {
+            let Node::Internal(o) = &head else {
+                return None;
+            };
+            tokens.push(Node::Internal(o.with_arity(args.len())?));
+        }
+        tokens.extend(args.into_iter().flatten());
+        Some(vec![tokens])
+    }
+
+    /// Replacements are checked against the pattern before matching, so every wildcard they use is bound.
+    fn bound<'b>(&self, substitution: &'b Substitution<'p>, name: &str) -> &'b Bound {
+        let (_, bound) = substitution.iter().find(|(b, _)| *b == name).unwrap();
+        bound
+    }
+
+    fn push_bound(&self, bound: &Bound, out: &mut Vec<Node<L, I>>) {
+        match bound {
+            Bound::Block(block) => {
+                for _ in 1..block.terms.len() {
+                    out.push(self.tokens[block.operator].clone());
+                }
+                for term in &block.terms {
+                    out.extend_from_slice(&self.tokens[term.range.clone()]);
+                }
+            },
+            Bound::Operator(operator) => out.push(self.tokens[*operator].clone()),
+        }
+    }
+
+    fn guard_holds(&self, substitution: &Substitution, guard: &Guard<L, I>) -> bool {
+        if guard.is_empty() {
+            return true;
+        }
+
+        let bound: Vec<(&str, Vec<Node<L, I>>)> = substitution.iter().map(|(name, bound)| {
+            let mut tokens = vec![];
+            self.push_bound(bound, &mut tokens);
+            (*name, tokens)
+        }).collect();
+
+        guard.holds(&guard::Substitution::new(
+            bound.iter().map(|(name, tokens)| (*name, tokens.as_slice())).collect()
+        ))
+    }
+
+    /// Build the tokens of the tree with `matched` replaced, either by the replacement alone or by a chain of
+    /// `operator` joining the replacement to the unmatched arguments.
+    fn rebuild(&self, matched: &Range<usize>, operator: usize, pieces: &[Piece], replacement: &[Node<PatternLeaf<L>, I>], substitution: &Substitution) -> Option<Vec<Node<L, I>>> {
+        let mut new_tokens = Vec::with_capacity(self.tokens.len() + replacement.len());
+        new_tokens.extend_from_slice(&self.tokens[..matched.start]);
+
+        for _ in 1..pieces.len() {
+            new_tokens.push(self.tokens[operator].clone());
+        }
+        for piece in pieces {
+            match piece {
+                Piece::Term(term) => new_tokens.extend_from_slice(&self.tokens[term.range.clone()]),
+                Piece::Replacement => self.instantiate(replacement, substitution, &mut new_tokens)?,
+            }
+        }
+
+        new_tokens.extend_from_slice(&self.tokens[matched.end..]);
+        Some(new_tokens)
+    }
+}
+
+/// Every rewrite of `tokens` by `pattern` (flattened as `p`) and `replacement` modulo `theory` which `guard` allows, as the start of the
+/// rewritten subtree and the tokens of the new tree. Only the subtree starting at token `at` is tried, if given.
+///
+/// A pattern rooted at an associative operator can also match part of a longer chain of that operator, in which case
+/// the replacement is joined back onto the rest of the chain. The rewrites are found lazily, skipping any which repeat
+/// an earlier one at the same subtree, and at most [`Theory::match_limit`] are produced per subtree.
+pub(super) fn rewrites<'p, L: TreeNode + Clone, I: TreeNode + Clone>(
+        tokens: &'p [Node<L, I>],
+        pattern: &'p [Node<PatternLeaf<L>, I>],
+        p: &'p Term,
+        replacement: &'p [Node<PatternLeaf<L>, I>],
+        theory: &'p Theory,
+        guard: &'p Guard<L, I>,
+        at: Option<usize>,
+    ) -> impl Iterator<Item = (usize, Vec<Node<L, I>>)> + 'p {
+
+    let matcher = Matcher { tokens, pattern, theory };
+
+    // Only patterns rooted at an associative operator can match part of a chain, and a sequence wildcard among the
+    // root's arguments already takes whatever is left of the chain
+    let absorbs_chain = p.args.iter().any(|arg| matches!(pattern[arg.range.start], Node::Leaf(PatternLeaf::Sequence(_))));
+    let chain_operator = match &pattern[0] {
+        Node::Internal(o) if theory.properties(o).associative && !absorbs_chain => Some((o.to_string(), theory.properties(o).commutative)),
+        _ => None,
+    };
+
+    chain_interiors(tokens, theory).into_iter()
+        .enumerate()
+        .filter(move |(start, interior)| !interior && at.is_none_or(|at| at == *start))
+        .map_while(move |(start, _)| Some((start, Arc::new(flatten(tokens, start, theory)?))))
+        .flat_map(move |(start, s)| {
+            let whole = matcher.match_term(p, &s, vec![]).map(|substitution| (substitution, vec![Piece::Replacement]));
+
+            let commutative = match (&chain_operator, &tokens[start]) {
+                (Some((label, commutative)), Node::Internal(o)) if *label == o.to_string() && s.args.len() > p.args.len() => Some(*commutative),
+                _ => None,
+            };
+            let partial = commutative.map(|commutative| partial_chains(s.args.clone(), p.args.len(), commutative))
+                .into_iter()
+                .flatten()
+                .flat_map(move |(matched, pieces)| {
+                    matcher.match_args(pattern_operator(pattern), &p.args, start, matched, vec![])
+                        .map(move |substitution| (substitution, pieces.clone()))
+                });
+
+            let mut seen = HashSet::new();
+            let matched = s.range.clone();
+            whole.chain(partial)
+                .filter(move |(substitution, pieces)| matcher.guard_holds(substitution, guard) && seen.insert(matcher.key(substitution, pieces)))
+                .filter_map(move |(substitution, pieces)| matcher.rebuild(&matched, start, &pieces, replacement, &substitution))
+                .take(theory.match_limit().unwrap_or(usize::MAX))
+                .map(move |new_tokens| (start, new_tokens))
+        })
+}
+
+fn pattern_operator<L: TreeNode, I: TreeNode>(pattern: &[Node<PatternLeaf<L>, I>]) -> &I {
+    match &pattern[0] {
+        Node::Internal(o) => o,
+        Node::Leaf(_) => unreachable!("Only patterns rooted at an operator match part of a chain"),
+    }
+}
+
+/// The ways of picking at least `minimum` (but not all) of the arguments of a chain, along with how to rebuild the
+/// chain around the replacement. Commutative chains may pick any subset, others only a contiguous run.
+fn partial_chains(sargs: Vec<Arc<Term>>, minimum: usize, commutative: bool) -> PartialChains {
+    let n = sargs.len();
+
+    if commutative {
+        return Box::new(subsets(n).filter(move |subset| subset.len() >= minimum && subset.len() < n).map(move |subset| {
+            let matched = subset.iter().map(|&i| sargs[i].clone()).collect();
+            let mut pieces = vec![Piece::Replacement];
+            pieces.extend((0..n).filter(|i| !subset.contains(i)).map(|i| Piece::Term(sargs[i].clone())));
+            (matched, pieces)
+        }));
+    }
+
+    Box::new((0..n)
+        .flat_map(move |start| (start + minimum.max(1)..=n).map(move |end| (start, end)))
+        .filter(move |&(start, end)| start != 0 || end != n)
+        .map(move |(start, end)| {
+            let mut pieces: Vec<Piece> = sargs[..start].iter().map(|s| Piece::Term(s.clone())).collect();
+            pieces.push(Piece::Replacement);
+            pieces.extend(sargs[end..].iter().map(|s| Piece::Term(s.clone())));
+            (sargs[start..end].to_vec(), pieces)
+        }))
+}
+
+/// Every vector of digits each below its radix, counted through like an odometer with the first digit turning fastest.
+struct Odometer {
+    radices: Vec<usize>,
+    digits: Option<Vec<usize>>,
+}
+
+impl Odometer {
+
+    fn new(radices: Vec<usize>) -> Self {
+        let digits = match radices.contains(&0) {
+            true => None,
+            false => Some(vec![0; radices.len()]),
+        };
+        Self { radices, digits }
+    }
+}
+
+impl Iterator for Odometer {
+    type Item = Vec<usize>;
+
+    fn next(&mut self) -> Option<Vec<usize>> {
+        let current = self.digits.clone()?;
+
+        let digits = self.digits.as_mut()?;
+        let turned = digits.iter_mut().zip(&self.radices).any(|(digit, radix)| {
+            *digit += 1;
+            if *digit < *radix {
+                return true;
+            }
+            *digit = 0;
+            false
+        });
+        if !turned {
+            self.digits = None;
+        }
+
+        Some(current)
+    }
+}
+
+/// The permutations of `0..n`, built by inserting each of `0..n` in turn, with the position of the last moving fastest.
+fn permutations(n: usize) -> impl Iterator<Item = Vec<usize>> {
+    Odometer::new((1..=n).rev().collect()).map(move |positions| {
+        let mut permutation = Vec::with_capacity(n);
+        for (i, position) in positions.into_iter().rev().enumerate() {
+            permutation.insert(position, i);
+        }
+        permutation
+    })
+}
+
+/// The subsets of `0..n`, with the membership of the last turning fastest.
+fn subsets(n: usize) -> impl Iterator<Item = Vec<usize>> {
+    Odometer::new(vec![2; n]).map(move |members| (0..n).filter(|i| members[n - 1 - i] == 1).collect())
+}
@@ -0,0 +1,264 @@
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::{panic, thread};
+
+use crate::PatternLeaf;
+use crate::guard::{Guard, Substitution};
+use crate::hash_cons::TermId;
+use crate::location::{Location, subtree_end};
+use crate::theory::Theory;
+use crate::tree::{Node, Tree, TreeNode, same_token};
+use super::ReplacementError;
+use super::rule::Rule;
+
+/// Wildcard names from the pattern, paired with the token range of the subtree they were bound to. Operator wildcards
+/// are bound to the range of the operator's token alone.
+pub(super) type Bindings<'p> = Vec<(&'p str, Range<usize>)>;
+
+/// Replace the subtree at `location` in `tree` with `identity.1`, if `identity.0` matches it modulo the operator
+/// properties declared in `theory` and `guard` holds.
+///
+/// Returns `Ok(None)` if the pattern doesn't match there. The identity is compiled on every call, so prefer a [`Rule`]
+/// when applying it repeatedly.
+pub fn replace_identity<
+        L: TreeNode + Clone,
+        I: TreeNode + Clone,
+        T: Tree<L=L, I=I>,
+        P: Tree<L=PatternLeaf<L>, I=I>,
+    >(
+        tree: &T,
+        identity: (&P, &P),
+        location: &Location,
+        theory: &Theory,
+        guard: &Guard<L, I>,
+    ) -> Result<Option<T>, ReplacementError> {
+
+    compile(identity, theory, guard)?.apply_at(tree, location)
+}
+
+/// Apply `identity` at every position in `tree` where its pattern matches, modulo the operator properties declared in
+/// `theory`. Modulo associativity and commutativity a position can match in more than one way, giving several results.
+/// Matches for which `guard` doesn't hold are skipped.
+///
+/// Each new tree is returned alongside the location of the subtree which was rewritten. The identity is compiled on
+/// every call, so prefer a [`Rule`] when applying it repeatedly.
+pub fn try_replace_identity<
+        L: TreeNode + Clone,
+        I: TreeNode + Clone,
+        T: Tree<L=L, I=I>,
+        P: Tree<L=PatternLeaf<L>, I=I>,
+    >(
+        tree: &T,
+        identity: (&P, &P),
+        theory: &Theory,
+        guard: &Guard<L, I>,
+    ) -> Result<Vec<(T, Location)>, ReplacementError> {
+
+    compile(identity, theory, guard)?.apply_all(tree)
+}
+
+/// [`try_replace_identity`] for each of `identities`, spread across a thread per available core. The results are in the
+/// same order as the identities.
+///
+/// Each identity is compiled once, up front, and fails with the first error of any identity.
+pub fn try_replace_identity_many<
+        L: TreeNode + Clone + Send + Sync,
+        I: TreeNode + Clone + Send + Sync,
+        T: Tree<L=L, I=I> + Send + Sync,
+        P: Tree<L=PatternLeaf<L>, I=I> + Sync,
+    >(
+        tree: &T,
+        identities: &[(&P, &P)],
+        theory: &Theory,
+        guard: &Guard<L, I>,
+    ) -> Result<Vec<Vec<(T, Location)>>, ReplacementError> {
+
+    let rules = identities.iter()
+        .map(|identity| compile(*identity, theory, guard))
+        .collect::<Result<Vec<Rule<L, I>>, ReplacementError>>()?;
+
+    let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let chunk_size = rules.len().div_ceil(threads).max(1);
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = rules.chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || {
+                chunk.iter()
+                    .map(|rule| rule.apply_all(tree))
+                    .collect::<Vec<_>>()
+            }))
+            .collect();
+
+        workers.into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    })
+}
+
+fn compile<L: TreeNode + Clone, I: TreeNode + Clone, P: Tree<L=PatternLeaf<L>, I=I>>(
+        identity: (&P, &P),
+        theory: &Theory,
+        guard: &Guard<L, I>,
+    ) -> Result<Rule<L, I>, ReplacementError> {
+
+    Rule::compile_with(
+        P::new(identity.0.tokens().clone()),
+        P::new(identity.1.tokens().clone()),
+        theory.clone(),
+        guard.clone(),
+    )
+}
+
+/// What a wildcard stands for once it's bound. Leaf wildcards stand for a subtree, so they're interchangeable with
+/// subtree wildcards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WildcardKind {
+    Subtree,
+    Sequence,
+    Operator(usize),
+}
+
+fn wildcard_kind<L: TreeNode, I: TreeNode>(token: &Node<PatternLeaf<L>, I>) -> Option<(&str, WildcardKind)> {
+    match token {
+        Node::Leaf(PatternLeaf::Subtree(name)) | Node::Leaf(PatternLeaf::Leaf(name)) => Some((name, WildcardKind::Subtree)),
+        Node::Leaf(PatternLeaf::Sequence(name)) => Some((name, WildcardKind::Sequence)),
+        Node::Leaf(PatternLeaf::Operator(name, arity)) => Some((name, WildcardKind::Operator(*arity))),
+        _ => None,
+    }
+}
+
+/// Whether the pattern has sequence wildcards, which are only matched against flattened terms.
+pub(super) fn has_sequences<L: TreeNode, I: TreeNode>(pattern: &[Node<PatternLeaf<L>, I>]) -> bool {
+    pattern.iter().any(|token| matches!(token, Node::Leaf(PatternLeaf::Sequence(_))))
+}
+
+/// Whether no wildcard appears more than once in the pattern, in which case matching never compares subtrees.
+pub(super) fn is_linear<L: TreeNode, I: TreeNode>(pattern: &[Node<PatternLeaf<L>, I>]) -> bool {
+    let names: Vec<&str> = pattern.iter().filter_map(wildcard_kind).map(|(name, _)| name).collect();
+    names.iter().enumerate().all(|(i, name)| !names[..i].contains(name))
+}
+
+/// Make sure every wildcard used by the replacement or the guard is bound by the pattern, and that each wildcard is
+/// used as the same kind of wildcard everywhere.
+pub(super) fn check_replacement<L: TreeNode, I: TreeNode>(
+        pattern: &[Node<PatternLeaf<L>, I>],
+        replacement: &[Node<PatternLeaf<L>, I>],
+        guard: &Guard<L, I>,
+    ) -> Result<(), ReplacementError> {
+
+    let mut bound: Vec<(&str, WildcardKind)> = vec![];
+    for (name, kind) in pattern.iter().filter_map(wildcard_kind) {
+        match bound.iter().find(|(b, _)| *b == name) {
+            Some((_, k)) if *k != kind => return Err(ReplacementError::WildcardKindMismatch(name.to_owned())),
+            Some(_) => {},
+            None => bound.push((name, kind)),
+        }
+    }
+
+    for (name, kind) in replacement.iter().filter_map(wildcard_kind) {
+        match bound.iter().find(|(b, _)| *b == name) {
+            Some((_, k)) if *k != kind => return Err(ReplacementError::WildcardKindMismatch(name.to_owned())),
+            Some(_) => {},
+            None => return Err(ReplacementError::UnknownWildcard(name.to_owned())),
+        }
+    }
+    for condition in guard.conditions() {
+        if let Some(name) = condition.wildcards().into_iter().find(|name| !bound.iter().any(|(b, _)| b == name)) {
+            return Err(ReplacementError::UnknownWildcard(name.to_owned()));
+        }
+    }
+    Ok(())
+}
+
+/// Make sure `location` picks out a subtree of `tokens`, with its index and path in agreement.
+pub(super) fn check_location<L: TreeNode, I: TreeNode>(tokens: &[Node<L, I>], location: &Location) -> Result<(), ReplacementError> {
+    match Location::of_index(tokens, location.index) {
+        Some(found) if found == *location => Ok(()),
+        _ => Err(ReplacementError::InvalidLocation(location.clone())),
+    }
+}
+
+pub(super) fn guard_holds<L: TreeNode, I: TreeNode>(tokens: &[Node<L, I>], bindings: &Bindings, guard: &Guard<L, I>) -> bool {
+    guard.is_empty() || guard.holds(&Substitution::new(
+        bindings.iter().map(|(name, range)| (*name, &tokens[range.clone()])).collect()
+    ))
+}
+
+/// Try to match `pattern` against the subtree of `tokens` rooted at `start`.
+///
+/// On success returns the end of the matched subtree and the range each wildcard was bound to. A wildcard which
+/// appears more than once must be bound to identical subtrees, or the same operator, each time. Subtrees are compared
+/// by their entry in `ids`, the interned id of the subtree at each token from `ids_start` on, which is only needed for
+/// non-linear patterns.
+pub(super) fn match_at<'p, L: TreeNode, I: TreeNode>(
+        tokens: &[Node<L, I>],
+        ids: &[TermId],
+        ids_start: usize,
+        start: usize,
+        pattern: &'p [Node<PatternLeaf<L>, I>],
+    ) -> Option<(usize, Bindings<'p>)> {
+
+    let mut bindings: Bindings<'p> = vec![];
+    let mut cursor = start;
+
+    for pattern_token in pattern {
+        let (name, end) = match (pattern_token, tokens.get(cursor)?) {
+            (Node::Internal(p), Node::Internal(o)) if p.to_string() == o.to_string() && p.arity() == o.arity() => {
+                cursor += 1;
+                continue
+            },
+            (Node::Leaf(PatternLeaf::Literal(p)), Node::Leaf(e)) if p.uid() == e.uid() => {
+                cursor += 1;
+                continue
+            },
+            (Node::Leaf(PatternLeaf::Subtree(name)), _) => (name, subtree_end(tokens, cursor)?),
+            (Node::Leaf(PatternLeaf::Leaf(name)), Node::Leaf(_)) => (name, cursor + 1),
+            // The operator's children are matched by the tokens which follow in the pattern
+            (Node::Leaf(PatternLeaf::Operator(name, arity)), Node::Internal(o)) if o.arity() == *arity => (name, cursor + 1),
+            _ => return None,
+        };
+
+        match bindings.iter().find(|(bound, _)| bound == name) {
+            Some((_, range)) => {
+                let same = match pattern_token {
+                    Node::Leaf(PatternLeaf::Operator(..)) => same_token(&tokens[range.start], &tokens[cursor]),
+                    _ => ids.get(range.start.checked_sub(ids_start)?)? == ids.get(cursor.checked_sub(ids_start)?)?,
+                };
+                if !same {
+                    return None;
+                }
+            },
+            None => bindings.push((name, cursor..end)),
+        }
+        cursor = end;
+    }
+
+    Some((cursor, bindings))
+}
+
+/// Build the tokens of a new tree, with the matched range replaced by the instantiated replacement.
+pub(super) fn substitute<L: TreeNode + Clone, I: TreeNode + Clone>(
+        tokens: &[Node<L, I>],
+        matched: Range<usize>,
+        bindings: &Bindings,
+        replacement: &[Node<PatternLeaf<L>, I>],
+    ) -> Vec<Node<L, I>> {
+
+    let mut new_tokens = Vec::with_capacity(tokens.len() + replacement.len());
+    new_tokens.extend_from_slice(&tokens[..matched.start]);
+
+    for token in replacement {
+        match token {
+            Node::Internal(o) => new_tokens.push(Node::Internal(o.clone())),
+            Node::Leaf(PatternLeaf::Literal(l)) => new_tokens.push(Node::Leaf(l.clone())),
+            Node::Leaf(PatternLeaf::Subtree(name) | PatternLeaf::Leaf(name) | PatternLeaf::Operator(name, _) | PatternLeaf::Sequence(name)) => {
+                // Replacements are checked against the pattern before matching, so every wildcard is bound
+                let (_, range) = bindings.iter().find(|(bound, _)| bound == name).unwrap();
+                new_tokens.extend_from_slice(&tokens[range.clone()]);
+            },
+        }
+    }
+
+    new_tokens.extend_from_slice(&tokens[matched.end..]);
+    new_tokens
+}
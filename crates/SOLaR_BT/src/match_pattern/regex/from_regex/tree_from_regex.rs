@@ -1,41 +1,65 @@
-use lazy_static::lazy_static;
-use onig::{Regex, RegexOptions, Syntax};
 use super::super::RegexParseError;
-use crate::tree::{Tree, ParsableTreeNode, Node};
-
-const GLOBAL_REGEX: &str = r"^(?<op>[+*])\((?<one>[+*]\(\g<one>\)\(\g<one>\)|[a-z])\)\((?<two>[+*]\(\g<two>\)\(\g<two>\)|[a-z])\)$|^(?<el>[a-z])$";  // PITA
-
+use super::super::to_regex::regex::decode_label;
+use crate::tree::{Tree, TreeNode, ParsableTreeNode, Node};
+
+/// Parse the `op(first)(second)...` string representation of a tree, in which an operator with no children is written
+/// `op()` and a leaf alone.
+///
+/// Labels are handed to the `from_string` implementations of the tree's own node types, so any vocabulary which
+/// survives a `to_string`/`from_string` round trip can be parsed.
 pub fn tree_from_regex<T: Tree>(expression_string: &str) -> Result<T, RegexParseError> where T::I: ParsableTreeNode, T::L: ParsableTreeNode {
-    lazy_static! {
-        static ref GLOBAL_PATTERN: Regex = Regex::with_options(
-            GLOBAL_REGEX,
-            RegexOptions::REGEX_OPTION_DONT_CAPTURE_GROUP,
-            Syntax::default(),
-        ).unwrap();
+    let mut tokens = vec![];
+    let rest = _tree_from_regex::<T>(expression_string, expression_string, &mut tokens)?;
+
+    if !rest.is_empty() {
+        return Err(RegexParseError::UnbalancedBrackets { offset: offset(expression_string, rest) });
     }
-    let caps = GLOBAL_PATTERN.captures(expression_string).unwrap();
-    if let Some(x) = caps.at(1) {       
-        let left = tree_from_regex::<T>(caps.at(2).unwrap()).unwrap(); // Push on the left subtree
-        let right = tree_from_regex::<T>(caps.at(3).unwrap()).unwrap(); // Push on the right subtree
-        
-        let first = T::join(
-            T::I::from_string(x).unwrap(),
-            left,
-            right
-        );
 
-        return Ok(first);
-    };
+    Ok(T::new(tokens))
+}
 
-    if let Some(x) = caps.at(4) {
-        let first = T::new(vec![
-            Node::Leaf(T::L::from_string(x).unwrap()),
-        ]);
+/// The byte offset of `rest` within `whole`, of which it must be a suffix.
+fn offset(whole: &str, rest: &str) -> usize {
+    whole.len() - rest.len()
+}
+
+/// Parse a single subtree from the front of `string`, pushing its tokens and returning whatever follows it. `whole` is
+/// the full string being parsed, for reporting offsets.
+fn _tree_from_regex<'s, T: Tree>(whole: &str, string: &'s str, tokens: &mut Vec<Node<T::L, T::I>>) -> Result<&'s str, RegexParseError> where T::I: ParsableTreeNode, T::L: ParsableTreeNode {
+    let end = string.find(['(', ')']).unwrap_or(string.len());
+    let (encoded, mut rest) = string.split_at(end);
+    let label = decode_label(encoded).ok_or(RegexParseError::InvalidLabel { offset: offset(whole, string) })?;
+
+    if !rest.starts_with('(') {
+        let leaf = T::L::from_string(&label).map_err(|reason| RegexParseError::UnparseableNode { label, reason })?;
+        tokens.push(Node::Leaf(leaf));
+        return Ok(rest);
+    }
 
-        return Ok(first);
+    let mut children = vec![];
+    let mut arity = 0;
+    match rest.strip_prefix("()") {
+        // An operator with no children
+        Some(after) => rest = after,
+        None => while let Some(child) = rest.strip_prefix('(') {
+            rest = _tree_from_regex::<T>(whole, child, &mut children)?;
+            rest = rest.strip_prefix(')').ok_or(RegexParseError::UnbalancedBrackets { offset: offset(whole, rest) })?;
+            arity += 1;
+        },
+    }
+
+    let operator = match T::I::from_string_with_arity(&label, arity) {
+        Ok(operator) => operator,
+        Err(reason) => return Err(RegexParseError::UnparseableNode { label, reason }),
     };
+    if operator.arity() != arity {
+        return Err(RegexParseError::ArityMismatch { label, expected: operator.arity(), found: arity });
+    }
 
-    Err(RegexParseError::UnableToParseRegex)
+    tokens.push(Node::Internal(operator));
+    tokens.append(&mut children);
+
+    Ok(rest)
 }
 
 // #[cfg(test)]
@@ -118,4 +142,33 @@ pub fn tree_from_regex<T: Tree>(expression_string: &str) -> Result<T, RegexParse
 //             },
 //         ]);
 //     }
-// }
\ No newline at end of file
+// }
+
+#[cfg(test)]
+mod tests {
+
+    use crate::test_utils::Equation;
+    use super::super::super::RegexParseError;
+    use super::tree_from_regex;
+
+    fn parse(string: &str) -> Result<Equation, RegexParseError> {
+        tree_from_regex(string)
+    }
+
+    #[test]
+    fn test_parse_errors_carry_context() {
+        assert!(parse("+(a)(if(b)(c)(d))").is_ok());
+
+        assert_eq!(parse("+(a)(%G0)"), Err(RegexParseError::InvalidLabel { offset: 5 }));
+        assert_eq!(parse("+(a)(b"), Err(RegexParseError::UnbalancedBrackets { offset: 6 }));
+        assert_eq!(parse("+(a)(b))"), Err(RegexParseError::UnbalancedBrackets { offset: 7 }));
+        assert_eq!(
+            parse("?(a)(b)"),
+            Err(RegexParseError::UnparseableNode { label: "?".to_string(), reason: "Unknown operator" })
+        );
+        assert_eq!(
+            parse("+(a)"),
+            Err(RegexParseError::ArityMismatch { label: "+".to_string(), expected: 2, found: 1 })
+        );
+    }
+}
@@ -1,26 +1,233 @@
+use std::fmt;
+
+use onig::{Regex, RegexOptions, Syntax, Captures};
+
+use crate::PatternLeaf;
+use crate::location::Location;
+use crate::tree::{Node, ParsableTreeNode, Tree, TreeNode};
+use crate::guard::Guard;
+use super::ReplacementError;
+use super::native::{check_location, check_replacement, has_sequences};
+
 mod to_regex {
     pub mod tree_to_regex;
     pub mod pattern_to_regex;
     pub mod replacement_to_regex;
-    mod regex;
+    pub mod regex;
 }
 
-pub use to_regex::tree_to_regex::tree_to_regex;
-pub use to_regex::pattern_to_regex::pattern_to_regex;
-pub use to_regex::replacement_to_regex::replacement_to_regex;
+use to_regex::tree_to_regex::tree_to_regex;
+use to_regex::pattern_to_regex::pattern_to_regex;
+use to_regex::replacement_to_regex::replacement_to_regex;
+use to_regex::regex::Vocabulary;
+pub(crate) use to_regex::regex::node_label;
 
 mod from_regex {
     pub mod tree_from_regex;
 }
 
-pub use from_regex::tree_from_regex::tree_from_regex;
+use from_regex::tree_from_regex::tree_from_regex;
+
+pub use crate::tree::TreeParseError;
 
-#[derive(Debug, PartialEq)]
+/// Ways in which an `op(first)(second)...` string can fail to parse. Offsets are in bytes from the start of the string.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum RegexParseError {
-    UnableToParseRegex,
+    /// The label starting at `offset` isn't a valid encoding of a node label
+    InvalidLabel { offset: usize },
+    /// `from_string` rejected the label of a node
+    UnparseableNode { label: String, reason: &'static str },
+    /// The internal node was parsed with `found` children but reports an arity of `expected`
+    ArityMismatch { label: String, expected: usize, found: usize },
+    /// A bracket was missing, or something other than a bracket followed a subtree, at `offset`
+    UnbalancedBrackets { offset: usize },
+}
+
+impl fmt::Display for RegexParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegexParseError::InvalidLabel { offset } => write!(f, "invalid label at byte {offset}"),
+            RegexParseError::UnparseableNode { label, reason } => write!(f, "unable to parse node `{label}`: {reason}"),
+            RegexParseError::ArityMismatch { label, expected, found } => {
+                write!(f, "node `{label}` has arity {expected} but was given {found} children")
+            },
+            RegexParseError::UnbalancedBrackets { offset } => write!(f, "unbalanced brackets at byte {offset}"),
+        }
+    }
+}
+
+impl std::error::Error for RegexParseError {}
+
+/// Regex backed equivalent of [`crate::replace_identity`].
+///
+/// The tree is serialized to a string, matched against a recursive Oniguruma regex and parsed back. Kept as a reference
+/// implementation for the native matcher.
+pub fn replace_identity<
+        L: ParsableTreeNode,
+        I: ParsableTreeNode,
+        T: Tree<L=L, I=I>,
+        P: Tree<L=PatternLeaf<L>, I=I>,
+    >(
+        tree: &T,
+        identity: (&P, &P),
+        location: &Location,
+        ) -> Result<Option<T>, ReplacementError> {
+
+    let (expression_string, pattern_string, replacement_string) = to_regex_strings(tree, identity)?;
+    check_location(tree.tokens(), location)?;
+
+    let re = Regex::with_options(
+            &pattern_string,
+    RegexOptions::REGEX_OPTION_DONT_CAPTURE_GROUP,
+    Syntax::default(),
+    ).map_err(|e| ReplacementError::RegexBackend(e.to_string()))?;
+
+    let offset = match regex_offsets(tree.tokens()).get(location.index) {
+        Some(offset) => *offset,
+        None => return Ok(None),
+    };
+    let mut captures_iter = re.captures_iter(&expression_string);
+
+    match captures_iter.find(|caps| caps.offset() == offset) {
+        Some(caps) => {
+            let new_tree_string = _replace_capture(&caps, &expression_string, &replacement_string)?;
+            let new_tree = tree_from_regex(&new_tree_string)?;
+            Ok(Some(new_tree))
+        },
+        None => Ok(None)
+    }
+}
+
+/// Regex backed equivalent of [`crate::try_replace_identity`].
+pub fn try_replace_identity<
+        L: ParsableTreeNode,
+        I: ParsableTreeNode,
+        T: Tree<L=L, I=I>,
+        P: Tree<L=PatternLeaf<L>, I=I>,
+    >(
+        tree: &T,
+        identity: (&P, &P),
+    ) -> Result<Vec<(T, Location)>, ReplacementError> {
+
+    let (expression_string, pattern_string, replacement_string) = to_regex_strings(tree, identity)?;
+
+    let re = Regex::new(
+        &pattern_string,
+    ).map_err(|e| ReplacementError::RegexBackend(e.to_string()))?;
+
+    let captures_iter: Vec<Captures> = re.captures_iter(&expression_string).collect();
+    let mut new_tree_strings = vec![];
+
+    for caps in captures_iter {
+        new_tree_strings.push(
+                (
+                    _replace_capture(&caps, &expression_string, &replacement_string)?,
+                    caps.offset(),
+                )
+        );
+    };
+
+    let offsets = regex_offsets(tree.tokens());
+    new_tree_strings.iter().map(|s| {
+        // Matches are anchored to the start of a subtree, so every offset belongs to a token
+        let location = offsets.iter()
+            .position(|offset| *offset == s.1)
+            .and_then(|index| Location::of_index(tree.tokens(), index))
+            .ok_or_else(|| ReplacementError::RegexBackend(format!("match at byte {} is not the start of a subtree", s.1)))?;
+        Ok((tree_from_regex(&s.0)?, location))
+    }).collect()
+}
+
+/// Serialize the tree, and the identity as a regex and replacement template respectively.
+fn to_regex_strings<
+        L: TreeNode,
+        I: TreeNode,
+        T: Tree<L=L, I=I>,
+        P: Tree<L=PatternLeaf<L>, I=I>,
+    >(tree: &T, identity: (&P, &P)) -> Result<(String, String, String), ReplacementError> {
+
+    check_replacement(identity.0.tokens(), identity.1.tokens(), &Guard::new())?;
+    if has_sequences(identity.0.tokens()) || has_sequences(identity.1.tokens()) {
+        return Err(ReplacementError::RegexBackend("sequence wildcards aren't supported by the regex matcher".to_string()));
+    }
+    let expression_string = tree_to_regex(tree).map_err(ReplacementError::InvalidTree)?;
+    let (pattern_string, wildcards) = pattern_to_regex(identity.0, &Vocabulary::from_tree(tree))
+        .map_err(ReplacementError::InvalidPattern)?;
+    let replacement_string = replacement_to_regex(identity.1, &wildcards)?;
+
+    Ok((expression_string, pattern_string, replacement_string))
 }
 
-#[derive(Debug, PartialEq)]
-pub enum TreeParseError {
-    InvalidWildcard,
-}
\ No newline at end of file
+fn _replace_capture(
+        caps: &Captures,
+        expression_string: &str,
+        replacement_string: &str,
+    ) -> Result<String, ReplacementError> {
+    // Group 1 is the whole matched subtree and the rest are the wildcards, all of which take part in every match
+    let (offset, end) = caps.pos(1).ok_or_else(|| ReplacementError::RegexBackend("match has no subtree group".to_string()))?;
+    let mut new_tree_string = String::new();
+    new_tree_string.push_str(
+            &expression_string[..offset]
+    );
+    let captures: Vec<&str> = caps.iter().skip(2).enumerate()
+        .map(|(i, cap)| cap.ok_or_else(|| ReplacementError::RegexBackend(format!("wildcard group g{i} did not take part in the match"))))
+        .collect::<Result<_, _>>()?;
+    render_template(replacement_string, &captures, &mut new_tree_string)?;
+    new_tree_string.push_str(
+            &expression_string[end..]
+    );
+    Ok(new_tree_string)
+}
+
+/// Write out a replacement template, swapping each `{gN}` placeholder for the `N`th wildcard's capture. Encoded labels
+/// never contain braces, so anything else is copied as it is.
+fn render_template(template: &str, captures: &[&str], out: &mut String) -> Result<(), ReplacementError> {
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        out.push_str(&rest[..open]);
+        let close = rest[open..].find('}').map(|close| open + close)
+            .ok_or_else(|| ReplacementError::RegexBackend("unterminated placeholder in replacement template".to_string()))?;
+        let capture = rest[open + 1..close].strip_prefix('g')
+            .and_then(|index| index.parse::<usize>().ok())
+            .and_then(|index| captures.get(index))
+            .ok_or_else(|| ReplacementError::RegexBackend(format!("unknown placeholder `{}`", &rest[open..=close])))?;
+        out.push_str(capture);
+        rest = &rest[close + 1..];
+    }
+    out.push_str(rest);
+    Ok(())
+}
+
+/// The byte offset at which each token starts in the `op(first)(second)...` string produced by `tree_to_regex`, where
+/// an operator with no children is written `op()`.
+fn regex_offsets<L: TreeNode, I: TreeNode>(tokens: &[Node<L, I>]) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(tokens.len());
+    let mut position = 0;
+    // Number of children still to be written for each open internal node
+    let mut open: Vec<usize> = vec![];
+
+    for token in tokens {
+        offsets.push(position);
+        position += node_label(token).len();
+        if let Node::Internal(o) = token {
+            if o.arity() > 0 {
+                position += 1;
+                open.push(o.arity());
+                continue
+            }
+            position += 2;
+        }
+
+        while let Some(remaining) = open.last_mut() {
+            *remaining -= 1;
+            if *remaining > 0 {
+                position += 2;
+                break
+            }
+            open.pop();
+            position += 1;
+        }
+    }
+
+    offsets
+}
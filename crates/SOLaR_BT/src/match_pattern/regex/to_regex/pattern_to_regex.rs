@@ -1,22 +1,25 @@
-use std::slice::Iter;
-use super::super::TreeParseError;
 use crate::{
-    tree::{Node, TreeNode, Tree},
+    tree::{Node, TreeNode, Tree, TreeParseError},
     pattern::PatternLeaf,
 };
-use super::tree_to_regex::internal_to_regex;
+use super::tree_to_regex::{internal_to_regex, branch_to_regex};
+use super::regex::{Tokens, Vocabulary, subtree_regex, leaf_regex, operator_regex, encode_label, escape, expect_end, truncated};
 
 
 pub fn pattern_to_regex<
         L: TreeNode,
         I: TreeNode,
         P: Tree<L=PatternLeaf<L>, I=I>
-    >(pattern: &P) -> Result<(String, Vec<Vec<u8>>), TreeParseError> {
+    >(pattern: &P, vocabulary: &Vocabulary) -> Result<(String, Vec<Vec<u8>>), TreeParseError> {
     
         let mut wildcards = vec![];
-        let result = _pattern_to_regex::<L, I, P>(&mut pattern.iter(), &mut wildcards)?;
+        let mut tokens = pattern.iter().enumerate();
+        let result = _pattern_to_regex::<L, I, P>(&mut tokens, None, &mut wildcards, vocabulary)?;
+        expect_end(&mut tokens)?;
 
-        let lookahead_result = format!("(?=(?<a>{}))", result);
+        // Only match whole subtrees: a subtree starts at the beginning of the string or after a '(', and is followed by
+        // a ')' or the end of the string
+        let lookahead_result = format!(r"(?<![^(])(?=(?<a>{})(?![^)]))", result);
 
         Ok((lookahead_result, wildcards))
 } 
@@ -25,33 +28,53 @@ pub fn pattern_to_regex<
 fn _pattern_to_regex<
     L: TreeNode,
     I: TreeNode,
-    P: Tree<L=PatternLeaf<L>, I=I>>(pattern: &mut Iter<Node<P::L, P::I>>, wildcards: &mut Vec<Vec<u8>>) -> Result<String, TreeParseError> {
+    P: Tree<L=PatternLeaf<L>, I=I>>(
+        pattern: &mut Tokens<P::L, P::I>,
+        parent: Option<usize>,
+        wildcards: &mut Vec<Vec<u8>>,
+        vocabulary: &Vocabulary,
+    ) -> Result<String, TreeParseError> {
 
         Ok(match pattern.next() {
 
-            Some(Node::Leaf(PatternLeaf::Literal(e))) => e.to_string(),
-
-            Some(Node::Leaf(PatternLeaf::Subtree(s))) => {
-                let mut result = String::new();
-                match wildcards.iter().position(|r| r == s.as_bytes()) {
-                    Some(index) => {
-                        result.push_str(&format!(r"\k<g{index}>"));
-                    },
-                    None => {
-                        let index = wildcards.len();
-                        result.push_str(&super::regex::subtree_regex(format!(r"g{index}")));
-                        wildcards.push(s.as_bytes().to_vec());
-                    }
-                };
-                result
+            Some((_, Node::Leaf(PatternLeaf::Literal(e)))) => escape(&encode_label(&e.uid())),
+
+            // Sequences are turned away before conversion, as the regex can't flatten chains
+            Some((_, Node::Leaf(PatternLeaf::Subtree(s) | PatternLeaf::Sequence(s)))) => {
+                wildcard_regex(s, wildcards, |name| subtree_regex(name, vocabulary))
+            },
+
+            Some((_, Node::Leaf(PatternLeaf::Leaf(s)))) => {
+                wildcard_regex(s, wildcards, |name| leaf_regex(name, vocabulary))
             },
 
-            Some(Node::Internal(o)) => {
-                let left_subtree_string = _pattern_to_regex::<L, I, P>(pattern, wildcards)?;
-                let right_subtree_string = _pattern_to_regex::<L, I, P>(pattern, wildcards)?;
-                internal_to_regex::<P>(o, &left_subtree_string, &right_subtree_string, true)
+            Some((index, Node::Leaf(PatternLeaf::Operator(s, arity)))) => {
+                let head = wildcard_regex(s, wildcards, |name| operator_regex(name, *arity, vocabulary));
+                let children = (0..*arity)
+                    .map(|_| _pattern_to_regex::<L, I, P>(pattern, Some(index), wildcards, vocabulary))
+                    .collect::<Result<Vec<String>, TreeParseError>>()?;
+                branch_to_regex(head, &children, true)
             },
 
-            None => panic!()
+            Some((index, Node::Internal(o))) => {
+                let children = (0..o.arity())
+                    .map(|_| _pattern_to_regex::<L, I, P>(pattern, Some(index), wildcards, vocabulary))
+                    .collect::<Result<Vec<String>, TreeParseError>>()?;
+                internal_to_regex::<P>(o, &children, true)
+            },
+
+            None => return Err(truncated(parent))
         })
-}
\ No newline at end of file
+}
+
+/// A backreference to the wildcard `s` if it has been seen already, otherwise a new named group built by `group`.
+fn wildcard_regex(s: &str, wildcards: &mut Vec<Vec<u8>>, group: impl FnOnce(String) -> String) -> String {
+    match wildcards.iter().position(|r| r == s.as_bytes()) {
+        Some(index) => format!(r"\k<g{index}>"),
+        None => {
+            let index = wildcards.len();
+            wildcards.push(s.as_bytes().to_vec());
+            group(format!(r"g{index}"))
+        }
+    }
+}
@@ -1,4 +1,182 @@
+use std::iter::Enumerate;
+use std::slice::Iter;
 
-pub fn subtree_regex(name: String) -> String {
-    format!(r"(?<{name}>[+*]\(\g<{name}>\)\(\g<{name}>\)|[a-z])")
-}
\ No newline at end of file
+use crate::tree::{Node, Tree, TreeNode, TreeParseError};
+
+/// Tokens being serialized, alongside their index in the tree.
+pub type Tokens<'t, L, I> = Enumerate<Iter<'t, Node<L, I>>>;
+
+/// The error for running out of tokens while reading a child of the internal node at `parent`, or the root if `None`.
+pub fn truncated(parent: Option<usize>) -> TreeParseError {
+    match parent {
+        Some(index) => TreeParseError::Truncated { index },
+        None => TreeParseError::Empty,
+    }
+}
+
+/// Make sure no tokens are left over once a whole tree has been read.
+pub fn expect_end<L: TreeNode, I: TreeNode>(tokens: &mut Tokens<L, I>) -> Result<(), TreeParseError> {
+    match tokens.next() {
+        Some((index, _)) => Err(TreeParseError::TrailingTokens { index }),
+        None => Ok(()),
+    }
+}
+
+/// Characters with a structural meaning in the regex string representation, which are percent encoded inside labels.
+const RESERVED: [char; 6] = ['%', '(', ')', '{', '}', '\\'];
+
+/// Encode a node label so that it can't be confused with the `op(left)(right)` structure or the replacement template.
+pub fn encode_label(label: &str) -> String {
+    let mut encoded = String::with_capacity(label.len());
+    for c in label.chars() {
+        if RESERVED.contains(&c) {
+            encoded.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            encoded.push(c);
+        }
+    }
+    encoded
+}
+
+/// Reverse of [`encode_label`]. Returns `None` if the label contains a malformed escape.
+pub fn decode_label(encoded: &str) -> Option<String> {
+    let mut label = String::with_capacity(encoded.len());
+    let mut chars = encoded.chars();
+    while let Some(c) = chars.next() {
+        if c == '%' {
+            let code: String = chars.by_ref().take(2).collect();
+            label.push(char::from(u8::from_str_radix(&code, 16).ok()?));
+        } else {
+            label.push(c);
+        }
+    }
+    Some(label)
+}
+
+/// Escape an encoded label for use as a literal in an Oniguruma pattern.
+pub fn escape(encoded: &str) -> String {
+    let mut escaped = String::with_capacity(encoded.len());
+    for c in encoded.chars() {
+        if c.is_ascii_punctuation() {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The operators and leaves which appear in a tree, as escaped regex literals. Operators are grouped by arity.
+///
+/// Wildcards can only ever bind to subtrees of the tree being matched, so this is all the alphabet they need.
+pub struct Vocabulary {
+    operators: Vec<(usize, Vec<String>)>,
+    leaves: Vec<String>,
+}
+
+impl Vocabulary {
+
+    pub fn from_tree<T: Tree>(tree: &T) -> Self {
+        let mut operators = vec![];
+        let mut leaves = vec![];
+
+        for token in tree.iter() {
+            let (label, set) = match token {
+                Node::Internal(o) => {
+                    let position = match operators.iter().position(|(arity, _)| *arity == o.arity()) {
+                        Some(position) => position,
+                        None => {
+                            operators.push((o.arity(), vec![]));
+                            operators.len() - 1
+                        },
+                    };
+                    (escape(&encode_label(&o.to_string())), &mut operators[position].1)
+                },
+                Node::Leaf(e) => (escape(&encode_label(&e.uid())), &mut leaves),
+            };
+            if !set.contains(&label) {
+                set.push(label);
+            }
+        }
+
+        // Prefer the longest label when one is a prefix of another
+        operators.sort_by_key(|(arity, _)| *arity);
+        for (_, labels) in operators.iter_mut() {
+            labels.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+        }
+        leaves.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+
+        Self {
+            operators,
+            leaves,
+        }
+    }
+}
+
+fn alternation(items: &[String]) -> String {
+    if items.is_empty() {
+        // Never matches
+        return String::from("(?!)");
+    }
+    format!("(?:{})", items.join("|"))
+}
+
+/// A named, recursive group matching any subtree built from `vocabulary`.
+pub fn subtree_regex(name: String, vocabulary: &Vocabulary) -> String {
+    let mut alternatives: Vec<String> = vocabulary.operators.iter().map(|(arity, labels)| {
+        let mut alternative = alternation(labels);
+        for _ in 0..*arity {
+            alternative.push_str(&format!(r"\(\g<{name}>\)"));
+        }
+        if *arity == 0 {
+            alternative.push_str(r"\(\)");
+        }
+        alternative
+    }).collect();
+    alternatives.push(alternation(&vocabulary.leaves));
+
+    format!(r"(?<{name}>{})", alternatives.join("|"))
+}
+
+/// A named group matching any leaf built from `vocabulary`.
+pub fn leaf_regex(name: String, vocabulary: &Vocabulary) -> String {
+    format!(r"(?<{name}>{})", alternation(&vocabulary.leaves))
+}
+
+/// A named group matching the label of any operator from `vocabulary` which has `arity` children.
+pub fn operator_regex(name: String, arity: usize, vocabulary: &Vocabulary) -> String {
+    let labels = vocabulary.operators.iter()
+        .find(|(a, _)| *a == arity)
+        .map(|(_, labels)| labels.as_slice())
+        .unwrap_or_default();
+    format!(r"(?<{name}>{})", alternation(labels))
+}
+
+/// Fetch the label of a node as it's written in the regex string representation.
+pub fn node_label<L: TreeNode, I: TreeNode>(node: &Node<L, I>) -> String {
+    match node {
+        Node::Internal(o) => encode_label(&o.to_string()),
+        Node::Leaf(e) => encode_label(&e.uid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{encode_label, decode_label, escape};
+
+    #[test]
+    fn test_label_encoding_round_trip() {
+        for label in ["a", "x1", "f(x)", "{{y}}", "50%", r"\n", "⁻¹"] {
+            let encoded = encode_label(label);
+            assert!(!encoded.contains(['(', ')', '{', '}', '\\']));
+            assert_eq!(decode_label(&encoded).unwrap(), label);
+        }
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a+b"), r"a\+b");
+        assert_eq!(escape("x1"), "x1");
+        assert_eq!(escape("%28"), r"\%28");
+    }
+}
@@ -1,17 +1,19 @@
-use std::slice::Iter;
-use super::super::TreeParseError;
 use crate::{
     tree::{Node, TreeNode, Tree},
-    pattern::PatternLeaf
+    pattern::PatternLeaf,
+    match_pattern::ReplacementError,
 };
-use super::tree_to_regex::internal_to_regex;
+use super::tree_to_regex::{internal_to_regex, branch_to_regex};
+use super::regex::{Tokens, encode_label, expect_end, truncated};
 
 pub fn replacement_to_regex<
         L: TreeNode,
         I: TreeNode,
         P: Tree<L=PatternLeaf<L>, I=I>
-    >(pattern: &P, wildcards: &[Vec<u8>]) -> Result<String, TreeParseError> {
-        let result = _replacement_to_regex::<L, I, P>(&mut pattern.iter(), wildcards)?;
+    >(pattern: &P, wildcards: &[Vec<u8>]) -> Result<String, ReplacementError> {
+        let mut tokens = pattern.iter().enumerate();
+        let result = _replacement_to_regex::<L, I, P>(&mut tokens, None, wildcards)?;
+        expect_end(&mut tokens).map_err(ReplacementError::InvalidReplacement)?;
 
         Ok(result)
 } 
@@ -21,32 +23,42 @@ fn _replacement_to_regex<
         L: TreeNode,
         I: TreeNode,
         P: Tree<L=PatternLeaf<L>, I=I>
-    >(pattern: &mut Iter<Node<P::L, P::I>>, wildcards: &[Vec<u8>]) -> Result<String, TreeParseError>{
+    >(pattern: &mut Tokens<P::L, P::I>, parent: Option<usize>, wildcards: &[Vec<u8>]) -> Result<String, ReplacementError>{
 
         Ok(match pattern.next() {
 
-            Some(Node::Leaf(PatternLeaf::Literal(e))) => e.to_string(),
+            Some((_, Node::Leaf(PatternLeaf::Literal(e)))) => encode_label(&e.to_string()),
 
-            Some(Node::Leaf(PatternLeaf::Subtree(s))) => {
-                let mut result = String::new();
-                match wildcards.iter().position(|r| r == s.as_bytes()) {
-                    Some(index) => {
-                        result.push_str(&format!(r"{{{{g{index}}}}}"));
-                    },
-                    None => return Err(TreeParseError::InvalidWildcard)
-                };
-                result
-            }
-            Some(Node::Internal(o)) => {
-                let left_subtree_string = _replacement_to_regex::<L, I, P>(pattern, wildcards)?;
-                let right_subtree_string = _replacement_to_regex::<L, I, P>(pattern, wildcards)?;
-                internal_to_regex::<P>(o, &left_subtree_string, &right_subtree_string, false)
+            Some((_, Node::Leaf(PatternLeaf::Subtree(s) | PatternLeaf::Leaf(s) | PatternLeaf::Sequence(s)))) => {
+                wildcard_template(s, wildcards)?
             },
 
-            None => panic!()
+            Some((index, Node::Leaf(PatternLeaf::Operator(s, arity)))) => {
+                let head = wildcard_template(s, wildcards)?;
+                let children = (0..*arity)
+                    .map(|_| _replacement_to_regex::<L, I, P>(pattern, Some(index), wildcards))
+                    .collect::<Result<Vec<String>, ReplacementError>>()?;
+                branch_to_regex(head, &children, false)
+            },
+            Some((index, Node::Internal(o))) => {
+                let children = (0..o.arity())
+                    .map(|_| _replacement_to_regex::<L, I, P>(pattern, Some(index), wildcards))
+                    .collect::<Result<Vec<String>, ReplacementError>>()?;
+                internal_to_regex::<P>(o, &children, false)
+            },
+
+            None => return Err(ReplacementError::InvalidReplacement(truncated(parent)))
         })
 }
 
+/// The template placeholder for whatever the wildcard `s` was bound to.
+fn wildcard_template(s: &str, wildcards: &[Vec<u8>]) -> Result<String, ReplacementError> {
+    match wildcards.iter().position(|r| r == s.as_bytes()) {
+        Some(index) => Ok(format!("{{g{index}}}")),
+        None => Err(ReplacementError::UnknownWildcard(s.to_owned())),
+    }
+}
+
 // #[cfg(test)]
 // mod tests {
 
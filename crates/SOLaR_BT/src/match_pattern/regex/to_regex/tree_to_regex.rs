@@ -1,47 +1,59 @@
-use std::slice::Iter;
-use crate::{tree::{Node, Tree, TreeNode}, match_pattern::regex::TreeParseError};
+use crate::tree::{Node, Tree, TreeNode, TreeParseError};
+use super::regex::{Tokens, encode_label, escape, expect_end, truncated};
 
 pub fn tree_to_regex<T: Tree>(tree: &T) -> Result<String, TreeParseError> {
-    _tree_to_regex::<T>(&mut tree.iter())
+    let mut tokens = tree.iter().enumerate();
+    let result = _tree_to_regex::<T>(&mut tokens, None)?;
+    expect_end(&mut tokens)?;
+    Ok(result)
 }
 
-fn _tree_to_regex<T: Tree>(tree: &mut Iter<Node<T::L, T::I>>) -> Result<String, TreeParseError> {
+fn _tree_to_regex<T: Tree>(tree: &mut Tokens<T::L, T::I>, parent: Option<usize>) -> Result<String, TreeParseError> {
     Ok(match tree.next() {
 
-        Some(Node::Leaf(e)) => e.uid(),
+        Some((_, Node::Leaf(e))) => encode_label(&e.uid()),
 
-        Some(Node::Internal(o)) => {
-            let left_subtree_string = _tree_to_regex::<T>(tree)?;
-            let right_subtree_string= _tree_to_regex::<T>(tree)?;
-            internal_to_regex::<T>(o, &left_subtree_string, &right_subtree_string, false)
+        Some((index, Node::Internal(o))) => {
+            let children = (0..o.arity())
+                .map(|_| _tree_to_regex::<T>(tree, Some(index)))
+                .collect::<Result<Vec<String>, TreeParseError>>()?;
+            internal_to_regex::<T>(o, &children, false)
         },
 
-        None => panic!()
+        None => return Err(truncated(parent))
     })
 }
 
-pub fn internal_to_regex<T: Tree>(internal_node: &T::I, left: &str, right: &str, regex: bool) -> String {
-    let mut result = String::new();
+/// Write an internal node followed by each of its children in brackets, `op(first)(second)...`
+pub fn internal_to_regex<T: Tree>(internal_node: &T::I, children: &[String], regex: bool) -> String {
+    let label = encode_label(&internal_node.to_string());
+    if regex {
+        branch_to_regex(escape(&label), children, regex)
+    } else {
+        branch_to_regex(label, children, regex)
+    }
+}
+
+/// Write `head`, which is already encoded, followed by each child in brackets, `head(first)(second)...`. A node with
+/// no children is written `head()`, which tells it apart from a leaf.
+pub fn branch_to_regex(head: String, children: &[String], regex: bool) -> String {
+    let mut result = head;
 
-    let escape = |_result: &mut String| {
+    let escape_structure = |_result: &mut String| {
         if regex { 
             _result.push('\\');
         };
     };
-    escape(&mut result);
-    result.push_str(&internal_node.to_string());
-
-    escape(&mut result);
-    result.push('(');
-    result.push_str(left);
-    escape(&mut result);
-    result.push(')');
-
-    escape(&mut result);
-    result.push('(');
-    result.push_str(right);
-    escape(&mut result);
-    result.push(')');
+
+    // An empty child stands for the brackets of a node with no children
+    let nullary = [String::new()];
+    for child in if children.is_empty() { &nullary } else { children } {
+        escape_structure(&mut result);
+        result.push('(');
+        result.push_str(child);
+        escape_structure(&mut result);
+        result.push(')');
+    }
 
     result
 }
@@ -0,0 +1,241 @@
+use crate::PatternLeaf;
+use crate::guard::Guard;
+use crate::hash_cons::{HashCons, TermId};
+use crate::location::{Location, subtree_end};
+use crate::theory::Theory;
+use crate::tree::{Node, Tree, TreeNode, validate_tokens};
+use super::ReplacementError;
+use super::ac;
+use super::native::{check_location, check_replacement, guard_holds, has_sequences, is_linear, match_at, substitute};
+
+/// The start of each rewritten subtree, paired with the tokens of the new tree.
+type Rewrites<'t, L, I> = Box<dyn Iterator<Item = (usize, Vec<Node<L, I>>)> + 't>;
+
+/// A rewrite rule, validated and prepared once so that it can be applied to many trees.
+///
+/// Compiling checks that the pattern and replacement are well formed trees and that the replacement and guard only use
+/// wildcards bound by the pattern, and flattens the pattern for matching modulo the theory, so none of that is repeated
+/// per application.
+#[derive(Clone, Debug)]
+pub struct Rule<L: TreeNode, I: TreeNode> {
+    pattern: Vec<Node<PatternLeaf<L>, I>>,
+    replacement: Vec<Node<PatternLeaf<L>, I>>,
+    theory: Theory,
+    guard: Guard<L, I>,
+    /// The flattened pattern, when the theory makes matching more than syntactic or the pattern has sequence wildcards
+    flattened: Option<ac::Term>,
+}
+
+impl<L: TreeNode + Clone, I: TreeNode + Clone> Rule<L, I> {
+
+    /// Compile a purely syntactic, unguarded rule rewriting `pattern` to `replacement`.
+    pub fn compile<P: Tree<L=PatternLeaf<L>, I=I>>(pattern: P, replacement: P) -> Result<Self, ReplacementError> {
+        Self::compile_with(pattern, replacement, Theory::new(), Guard::new())
+    }
+
+    /// Compile a rule which matches modulo `theory` and only rewrites where `guard` holds.
+    pub fn compile_with<P: Tree<L=PatternLeaf<L>, I=I>>(
+            pattern: P,
+            replacement: P,
+            theory: Theory,
+            guard: Guard<L, I>,
+        ) -> Result<Self, ReplacementError> {
+
+        let pattern = pattern.into_tokens();
+        let replacement = replacement.into_tokens();
+        validate_tokens(&pattern).map_err(ReplacementError::InvalidPattern)?;
+        validate_tokens(&replacement).map_err(ReplacementError::InvalidReplacement)?;
+        check_replacement(&pattern, &replacement, &guard)?;
+
+        let flattened = if theory.is_syntactic() && !has_sequences(&pattern) {
+            None
+        } else {
+            ac::flatten(&pattern, 0, &theory)
+        };
+
+        Ok(Self {
+            pattern,
+            replacement,
+            theory,
+            guard,
+            flattened,
+        })
+    }
+
+    pub fn pattern(&self) -> &[Node<PatternLeaf<L>, I>] {
+        &self.pattern
+    }
+
+    pub fn replacement(&self) -> &[Node<PatternLeaf<L>, I>] {
+        &self.replacement
+    }
+
+    pub fn theory(&self) -> &Theory {
+        &self.theory
+    }
+
+    pub fn guard(&self) -> &Guard<L, I> {
+        &self.guard
+    }
+
+    /// Every rewrite of `tokens`, or only those of the subtree starting at token `at`.
+    pub(crate) fn rewrites<'t>(&'t self, tokens: &'t [Node<L, I>], at: Option<usize>) -> Rewrites<'t, L, I> {
+        if let Some(flattened) = &self.flattened {
+            return Box::new(ac::rewrites(tokens, &self.pattern, flattened, &self.replacement, &self.theory, &self.guard, at));
+        }
+
+        let starts = match at {
+            Some(start) => start..(start + 1).min(tokens.len()),
+            None => 0..tokens.len(),
+        };
+
+        // Repeated wildcards are checked by comparing interned subtrees, so only intern when the pattern has them, and
+        // then only the subtree being rewritten if there is just the one
+        let interned = match at {
+            Some(start) => start..subtree_end(tokens, start).unwrap_or(start),
+            None => 0..tokens.len(),
+        };
+        let ids: Vec<TermId> = match is_linear(&self.pattern) {
+            true => vec![],
+            false => tokens.get(interned.clone()).and_then(|subtree| HashCons::new().intern_tokens(subtree)).unwrap_or_default(),
+        };
+
+        Box::new(
+            starts
+                .filter_map(move |start| match_at(tokens, &ids, interned.start, start, &self.pattern).map(|m| (start, m)))
+                .filter(move |(_, (_, bindings))| guard_holds(tokens, bindings, &self.guard))
+                .map(move |(start, (end, bindings))| (start, substitute(tokens, start..end, &bindings, &self.replacement)))
+        )
+    }
+
+    /// Rewrite the subtree at `location` in `tree`. The location's index and path must agree on where that is.
+    ///
+    /// Returns `Ok(None)` if the rule doesn't match there. Modulo associativity and commutativity a subtree can be
+    /// rewritten in more than one way, in which case the first is taken.
+    pub fn apply_at<T: Tree<L=L, I=I>>(&self, tree: &T, location: &Location) -> Result<Option<T>, ReplacementError> {
+        tree.validate().map_err(ReplacementError::InvalidTree)?;
+        check_location(tree.tokens(), location)?;
+
+        Ok(self.rewrites(tree.tokens(), Some(location.index))
+            .next()
+            .map(|(_, new_tokens)| T::new(new_tokens)))
+    }
+
+    /// Every way of rewriting the subtree at `location` in `tree`. There is at most one unless matching modulo a theory.
+    pub fn apply_each_at<T: Tree<L=L, I=I>>(&self, tree: &T, location: &Location) -> Result<Vec<T>, ReplacementError> {
+        tree.validate().map_err(ReplacementError::InvalidTree)?;
+        check_location(tree.tokens(), location)?;
+
+        Ok(self.rewrites(tree.tokens(), Some(location.index))
+            .map(|(_, new_tokens)| T::new(new_tokens))
+            .collect())
+    }
+
+    /// Rewrite every match in `tree`, in pre-order of the rewritten subtree.
+    ///
+    /// Each new tree is returned alongside the location of the subtree which was rewritten.
+    pub fn apply_all<T: Tree<L=L, I=I>>(&self, tree: &T) -> Result<Vec<(T, Location)>, ReplacementError> {
+        tree.validate().map_err(ReplacementError::InvalidTree)?;
+        let tokens = tree.tokens();
+
+        Ok(self.rewrites(tokens, None)
+            // Every start is the index of a token in the tree
+            .filter_map(|(start, new_tokens)| Some((T::new(new_tokens), Location::of_index(tokens, start)?)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{Tree, Node, Theory, Guard, Condition, Location};
+    use crate::test_utils::{Equation, Operator, Identity, leaf, wildcard, commutativity};
+
+    use super::super::{try_replace_identity, ReplacementError};
+    use super::Rule;
+
+    #[test]
+    fn test_compiled_rule_matches_uncompiled_identity() {
+        let (pattern, replacement) = commutativity(Operator::MULTIPLY);
+        let rule = Rule::compile(pattern.clone(), replacement.clone()).unwrap();
+
+        // (a*b)*(c*d)
+        let trees = [
+            Equation::new(vec![
+                Node::Internal(Operator::MULTIPLY),
+                Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b"),
+                Node::Internal(Operator::MULTIPLY), leaf("c"), leaf("d"),
+            ]),
+            Equation::new(vec![Node::Internal(Operator::ADD), leaf("a"), leaf("b")]),
+        ];
+
+        for tree in trees {
+            let uncompiled: Vec<(Equation, Location)> = try_replace_identity(&tree, (&pattern, &replacement), &Theory::new(), &Guard::new()).unwrap();
+            let compiled: Vec<(Equation, Location)> = rule.apply_all(&tree).unwrap();
+            assert_eq!(compiled, uncompiled);
+
+            for index in 0..tree.tokens().len() {
+                let location = Location::of_index(tree.tokens(), index).unwrap();
+                let expected = uncompiled.iter().find(|(_, l)| *l == location).map(|(tree, _)| tree);
+                assert_eq!(rule.apply_at(&tree, &location).unwrap().as_ref(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compiled_rule_with_theory_and_guard() {
+        let (pattern, replacement) = commutativity(Operator::MULTIPLY);
+        let theory = Theory::new().associative(&Operator::MULTIPLY).commutative(&Operator::MULTIPLY);
+        let guard = Guard::new().when(Condition::Ordered("y".to_string(), "x".to_string()));
+        let rule = Rule::compile_with(pattern.clone(), replacement.clone(), theory.clone(), guard.clone()).unwrap();
+
+        // c*(b*a)
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::MULTIPLY), leaf("c"),
+            Node::Internal(Operator::MULTIPLY), leaf("b"), leaf("a"),
+        ]);
+
+        let uncompiled: Vec<(Equation, Location)> = try_replace_identity(&tree, (&pattern, &replacement), &theory, &guard).unwrap();
+        assert!(!uncompiled.is_empty());
+        assert_eq!(rule.apply_all(&tree).unwrap(), uncompiled);
+    }
+
+    #[test]
+    fn test_repeated_wildcards_at_a_location() {
+        // x+x = x, whose repeated wildcard is checked within just the subtree being rewritten
+        let rule = Rule::compile(
+            Identity::new(vec![Node::Internal(Operator::ADD), wildcard("x"), wildcard("x")]),
+            Identity::new(vec![wildcard("x")]),
+        ).unwrap();
+
+        // (a+a)*(a+b)
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::MULTIPLY),
+            Node::Internal(Operator::ADD), leaf("a"), leaf("a"),
+            Node::Internal(Operator::ADD), leaf("a"), leaf("b"),
+        ]);
+
+        let all: Vec<(Equation, Location)> = rule.apply_all(&tree).unwrap();
+        assert_eq!(all.len(), 1);
+        for index in 0..tree.tokens().len() {
+            let location = Location::of_index(tree.tokens(), index).unwrap();
+            let expected = all.iter().find(|(_, l)| *l == location).map(|(tree, _)| tree);
+            assert_eq!(rule.apply_at(&tree, &location).unwrap().as_ref(), expected);
+        }
+    }
+
+    #[test]
+    fn test_compile_with_unbound_wildcard() {
+        let (pattern, _) = commutativity(Operator::MULTIPLY);
+        let replacement = Identity::new(vec![wildcard("z")]);
+
+        assert!(matches!(
+            Rule::compile(pattern.clone(), replacement),
+            Err(ReplacementError::UnknownWildcard(name)) if name == "z"
+        ));
+        assert!(matches!(
+            Rule::compile_with(pattern.clone(), pattern, Theory::new(), Guard::new().when(Condition::IsLeaf("w".to_string()))),
+            Err(ReplacementError::UnknownWildcard(name)) if name == "w"
+        ));
+    }
+}
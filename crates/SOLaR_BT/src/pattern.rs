@@ -1,9 +1,38 @@
 use crate::tree::TreeNode;
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PatternLeaf<L: TreeNode> {
     Literal(L),
+    /// A wildcard matching any subtree
     Subtree(String),
+    /// A wildcard matching only a single leaf
+    Leaf(String),
+    /// A wildcard matching an internal node with this many children, whichever operator it is. The operator is bound
+    /// by name, so it can be reused in the replacement.
+    ///
+    /// It takes the place of an internal node, so its children follow it in pre-order like any other internal node's.
+    Operator(String, usize),
+    /// A wildcard matching any number of consecutive arguments of a node, including none, written `...name`.
+    ///
+    /// Chains of an associative operator are flattened into a single node before matching, so a sequence wildcard can
+    /// take any part of a long sum or product. Where it's used in the replacement, the chain it sits in is rebuilt
+    /// around however many arguments it was bound to.
+    Sequence(String),
+}
+
+impl<L: TreeNode> PatternLeaf<L> {
+
+    /// The name of the wildcard, or `None` for a literal.
+    pub fn wildcard(&self) -> Option<&str> {
+        match self {
+            PatternLeaf::Literal(_) => None,
+            PatternLeaf::Subtree(name)
+            | PatternLeaf::Leaf(name)
+            | PatternLeaf::Operator(name, _)
+            | PatternLeaf::Sequence(name) => Some(name),
+        }
+    }
 }
 
 impl<L: TreeNode> TreeNode for PatternLeaf<L> {
@@ -13,10 +42,40 @@ impl<L: TreeNode> TreeNode for PatternLeaf<L> {
             PatternLeaf::Literal(l) => {
                 l.to_string()
             },
-            PatternLeaf::Subtree(s) => {
+            PatternLeaf::Subtree(s) | PatternLeaf::Leaf(s) | PatternLeaf::Operator(s, _) => {
                 String::from(s)
             },
+            PatternLeaf::Sequence(s) => {
+                format!("...{s}")
+            },
+        }
+    }
+
+    fn leaf_arity(&self) -> usize {
+        match self {
+            PatternLeaf::Operator(_, arity) => *arity,
+            _ => 0,
         }
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+
+    use crate::Node;
+    use crate::test_utils::{Element, Operator};
+
+    use super::PatternLeaf;
+
+    #[test]
+    fn test_serde_round_trip() {
+        let pattern: Vec<Node<PatternLeaf<Element>, Operator>> = vec![
+            Node::Leaf(PatternLeaf::Operator("o".to_string(), 2)),
+            Node::Leaf(PatternLeaf::Literal(Element { label: b"a".to_vec() })),
+            Node::Leaf(PatternLeaf::Sequence("rest".to_string())),
+        ];
+        let json = serde_json::to_string(&pattern).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<Node<PatternLeaf<Element>, Operator>>>(&json).unwrap(), pattern);
+    }
+}
@@ -0,0 +1,276 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use crate::tree::{Node, ParsableTreeNode, Tree, TreeNode};
+
+/// Ways in which an S-expression can fail to parse. Offsets are in bytes from the start of the string.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SExprParseError {
+    /// The string ended before the tree was complete
+    UnexpectedEnd,
+    /// The closing bracket at `offset` has nothing to close
+    UnexpectedClose { offset: usize },
+    /// The tree was already complete, and more follows it from `offset`
+    TrailingInput { offset: usize },
+    /// The quoted label starting at `offset` has no closing quote
+    UnterminatedString { offset: usize },
+    /// The opening bracket at `offset` isn't followed by the label of an internal node
+    MissingLabel { offset: usize },
+    /// `from_string` rejected the label at `offset`
+    UnparseableNode { label: String, reason: &'static str, offset: usize },
+    /// The internal node at `offset` was given `found` children but reports an arity of `expected`
+    ArityMismatch { label: String, expected: usize, found: usize, offset: usize },
+}
+
+impl fmt::Display for SExprParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SExprParseError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            SExprParseError::UnexpectedClose { offset } => write!(f, "unmatched `)` at byte {offset}"),
+            SExprParseError::TrailingInput { offset } => write!(f, "unexpected input after the end of the tree, at byte {offset}"),
+            SExprParseError::UnterminatedString { offset } => write!(f, "unterminated quoted label starting at byte {offset}"),
+            SExprParseError::MissingLabel { offset } => write!(f, "expected a label after the `(` at byte {offset}"),
+            SExprParseError::UnparseableNode { label, reason, offset } => {
+                write!(f, "unable to parse node `{label}` at byte {offset}: {reason}")
+            },
+            SExprParseError::ArityMismatch { label, expected, found, offset } => {
+                write!(f, "node `{label}` at byte {offset} has arity {expected} but was given {found} children")
+            },
+        }
+    }
+}
+
+impl std::error::Error for SExprParseError {}
+
+/// A lexical token of an S-expression, with the byte offset it starts at.
+enum Token {
+    Open(usize),
+    Close(usize),
+    Atom(String, usize),
+}
+
+struct Lexer<'s> {
+    chars: Peekable<CharIndices<'s>>,
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Result<Token, SExprParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+
+        let (offset, c) = self.chars.next()?;
+        Some(Ok(match c {
+            '(' => Token::Open(offset),
+            ')' => Token::Close(offset),
+            '"' => {
+                let mut label = String::new();
+                loop {
+                    match self.chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match self.chars.next() {
+                            Some((_, escaped)) => label.push(escaped),
+                            None => return Some(Err(SExprParseError::UnterminatedString { offset })),
+                        },
+                        Some((_, c)) => label.push(c),
+                        None => return Some(Err(SExprParseError::UnterminatedString { offset })),
+                    }
+                }
+                Token::Atom(label, offset)
+            },
+            c => {
+                let mut label = String::from(c);
+                while let Some((_, c)) = self.chars.next_if(|(_, c)| !is_delimiter(*c)) {
+                    label.push(c);
+                }
+                Token::Atom(label, offset)
+            },
+        }))
+    }
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')' | '"')
+}
+
+/// Write `tree` as an S-expression: a leaf is its label, and an internal node is `(label child ...)`.
+///
+/// Labels are quoted, with `"` and `\` escaped by a `\`, if they are empty or contain whitespace, brackets, quotes or
+/// backslashes, so any tree whose nodes can be parsed from their labels round trips through [`from_sexpr`].
+pub fn to_sexpr<T: Tree>(tree: &T) -> String {
+    let mut string = String::new();
+    // Children each open node is still waiting for
+    let mut open: Vec<usize> = vec![];
+
+    for (index, token) in tree.iter().enumerate() {
+        if index > 0 {
+            string.push(' ');
+        }
+        if let Some(remaining) = open.last_mut() {
+            *remaining -= 1;
+        }
+
+        let label = match token {
+            Node::Leaf(l) => l.to_string(),
+            Node::Internal(o) => o.to_string(),
+        };
+        // Internal nodes are always bracketed, so one with no children isn't read back as a leaf
+        if matches!(token, Node::Internal(_)) || token.arity() > 0 {
+            string.push('(');
+            push_label(&mut string, &label);
+            open.push(token.arity());
+        } else {
+            push_label(&mut string, &label);
+        }
+
+        while open.last() == Some(&0) {
+            open.pop();
+            string.push(')');
+        }
+    }
+
+    // Close off anything a truncated tree left open
+    for _ in open {
+        string.push(')');
+    }
+    string
+}
+
+fn push_label(string: &mut String, label: &str) {
+    if !label.is_empty() && !label.contains(|c| is_delimiter(c) || c == '\\') {
+        return string.push_str(label);
+    }
+
+    string.push('"');
+    for c in label.chars() {
+        if matches!(c, '"' | '\\') {
+            string.push('\\');
+        }
+        string.push(c);
+    }
+    string.push('"');
+}
+
+/// Parse a tree from an S-expression, as written by [`to_sexpr`].
+///
+/// An atom on its own is a leaf, and a bracketed list is an internal node labelled by its first atom, whose children
+/// are the rest. Labels can be quoted to include whitespace, brackets or quotes.
+pub fn from_sexpr<T: Tree>(string: &str) -> Result<T, SExprParseError> where T::L: ParsableTreeNode, T::I: ParsableTreeNode {
+    let mut lexer = Lexer { chars: string.char_indices().peekable() };
+    let mut tokens = vec![];
+    match lexer.next().transpose()? {
+        Some(Token::Close(offset)) => return Err(SExprParseError::UnexpectedClose { offset }),
+        Some(first) => parse_node::<T>(&mut lexer, first, &mut tokens)?,
+        None => return Err(SExprParseError::UnexpectedEnd),
+    };
+
+    match lexer.next().transpose()? {
+        None => Ok(T::new(tokens)),
+        Some(Token::Open(offset) | Token::Close(offset) | Token::Atom(_, offset)) => Err(SExprParseError::TrailingInput { offset }),
+    }
+}
+
+/// Parse a single subtree starting with `first`, pushing its tokens. Returns `false` without pushing anything if
+/// `first` is the closing bracket of an enclosing list.
+fn parse_node<T: Tree>(lexer: &mut Lexer, first: Token, tokens: &mut Vec<Node<T::L, T::I>>) -> Result<bool, SExprParseError>
+    where T::L: ParsableTreeNode, T::I: ParsableTreeNode {
+
+    let open = match first {
+        Token::Close(_) => return Ok(false),
+        Token::Atom(label, offset) => {
+            let leaf = T::L::from_string(&label).map_err(|reason| SExprParseError::UnparseableNode { label, reason, offset })?;
+            tokens.push(Node::Leaf(leaf));
+            return Ok(true);
+        },
+        Token::Open(open) => open,
+    };
+
+    let (label, offset) = match lexer.next().transpose()? {
+        Some(Token::Atom(label, offset)) => (label, offset),
+        Some(_) => return Err(SExprParseError::MissingLabel { offset: open }),
+        None => return Err(SExprParseError::UnexpectedEnd),
+    };
+
+    let mut children = vec![];
+    let mut arity = 0;
+    loop {
+        let next = lexer.next().transpose()?.ok_or(SExprParseError::UnexpectedEnd)?;
+        if !parse_node::<T>(lexer, next, &mut children)? {
+            break
+        }
+        arity += 1;
+    }
+
+    let operator = T::I::from_string_with_arity(&label, arity)
+        .map_err(|reason| SExprParseError::UnparseableNode { label: label.clone(), reason, offset })?;
+    if operator.arity() != arity {
+        return Err(SExprParseError::ArityMismatch { label, expected: operator.arity(), found: arity, offset });
+    }
+
+    tokens.push(Node::Internal(operator));
+    tokens.append(&mut children);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{Tree, Node};
+    use crate::test_utils::{Equation, Operator, leaf};
+
+    use super::{from_sexpr, to_sexpr, SExprParseError};
+
+    #[test]
+    fn test_round_trip() {
+        // (a + if(b, "c d", inv(e))) * ""
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::MULTIPLY),
+            Node::Internal(Operator::ADD), leaf("a"),
+            Node::Internal(Operator::CONDITIONAL), leaf("b"), leaf("c d"), Node::Internal(Operator::INVERSE), leaf("e"),
+            leaf(""),
+        ]);
+
+        let string = to_sexpr(&tree);
+        assert_eq!(string, r#"(* (+ a (if b "c d" (inv e))) "")"#);
+        assert_eq!(from_sexpr::<Equation>(&string), Ok(tree));
+
+        // Whitespace is free, and any label can be quoted
+        let spaced: Equation = from_sexpr("  ( +\n\"a\"\t( inv b ) ) ").unwrap();
+        assert_eq!(spaced, Equation::new(vec![Node::Internal(Operator::ADD), leaf("a"), Node::Internal(Operator::INVERSE), leaf("b")]));
+
+        let quoted = Equation::new(vec![leaf(r#"say "hi" \o/"#)]);
+        assert_eq!(to_sexpr(&quoted), r#""say \"hi\" \\o/""#);
+        assert_eq!(from_sexpr::<Equation>(&to_sexpr(&quoted)), Ok(quoted));
+    }
+
+    #[test]
+    fn test_round_trip_nullary_operators() {
+        // An operator with no arguments keeps its brackets, where a leaf of the same label wouldn't have any
+        let tree = Equation::new(vec![Node::Internal(Operator::ADD), Node::Internal(Operator::PI), leaf("pi")]);
+        let string = to_sexpr(&tree);
+        assert_eq!(string, "(+ (pi) pi)");
+        assert_eq!(from_sexpr::<Equation>(&string), Ok(tree));
+
+        let tree = Equation::new(vec![Node::Internal(Operator::PI)]);
+        assert_eq!(to_sexpr(&tree), "(pi)");
+        assert_eq!(from_sexpr::<Equation>("(pi)"), Ok(tree));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let parse = |string: &str| from_sexpr::<Equation>(string);
+
+        assert_eq!(parse(""), Err(SExprParseError::UnexpectedEnd));
+        assert_eq!(parse("(+ a"), Err(SExprParseError::UnexpectedEnd));
+        assert_eq!(parse(")"), Err(SExprParseError::UnexpectedClose { offset: 0 }));
+        assert_eq!(parse("a b"), Err(SExprParseError::TrailingInput { offset: 2 }));
+        assert_eq!(parse("(+ a b))"), Err(SExprParseError::TrailingInput { offset: 7 }));
+        assert_eq!(parse("(+ \"a b)"), Err(SExprParseError::UnterminatedString { offset: 3 }));
+        assert_eq!(parse("(+ (a) b)"), Err(SExprParseError::UnparseableNode { label: "a".to_string(), reason: "Unknown operator", offset: 4 }));
+        assert_eq!(parse("((+) a b)"), Err(SExprParseError::MissingLabel { offset: 0 }));
+        assert_eq!(parse("()"), Err(SExprParseError::MissingLabel { offset: 0 }));
+        assert_eq!(parse("(% a b)"), Err(SExprParseError::UnparseableNode { label: "%".to_string(), reason: "Unknown operator", offset: 1 }));
+        assert_eq!(parse("(inv a b)"), Err(SExprParseError::ArityMismatch { label: "inv".to_string(), expected: 1, found: 2, offset: 1 }));
+    }
+}
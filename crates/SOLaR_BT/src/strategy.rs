@@ -0,0 +1,374 @@
+use std::cmp::Reverse;
+use std::fmt;
+
+use crate::location::subtree_end;
+use crate::match_pattern::Rule;
+use crate::tree::{Node, Tree, TreeNode, TreeParseError, same_token};
+
+/// Why a [`Strategy`] couldn't be run to the end.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StrategyError {
+    /// The tree isn't a single well formed tree
+    InvalidTree(TreeParseError),
+    /// The strategy made this many rewrites without finishing, so the rules probably don't terminate
+    StepLimit(usize),
+}
+
+impl fmt::Display for StrategyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StrategyError::InvalidTree(e) => write!(f, "invalid tree: {e}"),
+            StrategyError::StepLimit(steps) => write!(f, "gave up after {steps} rewrites"),
+        }
+    }
+}
+
+impl std::error::Error for StrategyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StrategyError::InvalidTree(e) => Some(e),
+            StrategyError::StepLimit(_) => None,
+        }
+    }
+}
+
+/// A deterministic way of rewriting a tree with rules.
+///
+/// A strategy is applied to one subtree at a time, starting from the root, and either succeeds with a new tree or
+/// fails. Traversals move the strategies they wrap to other subtrees, and combinators chain them, so a whole
+/// normalisation procedure can be described once and then run to a single answer rather than searched for.
+#[derive(Clone, Debug)]
+pub enum Strategy<L: TreeNode, I: TreeNode> {
+    /// Rewrite the root of the subtree by the first rule which matches there, taking its first rewrite. Fails if none
+    /// of the rules match.
+    Rules(Vec<Rule<L, I>>),
+    /// Apply each strategy in turn, failing if any of them fails
+    Sequence(Vec<Strategy<L, I>>),
+    /// Apply the first strategy which succeeds, failing if none of them do
+    Choice(Vec<Strategy<L, I>>),
+    /// Apply the strategy until it fails or leaves the tree as it was, as traversals succeed without rewriting
+    /// anything. Always succeeds, even if the strategy never did.
+    Repeat(Box<Strategy<L, I>>),
+    /// Apply the strategy to the first subtree in pre-order where it succeeds, failing if there is none
+    OnceTopDown(Box<Strategy<L, I>>),
+    /// Apply the strategy to the first subtree in post-order where it succeeds, failing if there is none
+    OnceBottomUp(Box<Strategy<L, I>>),
+    /// Try the strategy at the root of the subtree, then at each child of whatever that leaves. Always succeeds.
+    TopDown(Box<Strategy<L, I>>),
+    /// Try the strategy at each child of the subtree, then at its root. Always succeeds.
+    BottomUp(Box<Strategy<L, I>>),
+}
+
+/// The rewrites a strategy may still make.
+struct Budget {
+    limit: usize,
+    used: usize,
+}
+
+impl Budget {
+    fn spend(&mut self) -> Result<(), StrategyError> {
+        if self.used == self.limit {
+            return Err(StrategyError::StepLimit(self.limit));
+        }
+        self.used += 1;
+        Ok(())
+    }
+}
+
+impl<L: TreeNode + Clone, I: TreeNode + Clone> Strategy<L, I> {
+
+    /// Rewrite by the first of `rules` which matches.
+    pub fn rules(rules: Vec<Rule<L, I>>) -> Self {
+        Strategy::Rules(rules)
+    }
+
+    /// This strategy followed by `next`.
+    pub fn then(self, next: Self) -> Self {
+        match self {
+            Strategy::Sequence(mut strategies) => {
+                strategies.push(next);
+                Strategy::Sequence(strategies)
+            },
+            first => Strategy::Sequence(vec![first, next]),
+        }
+    }
+
+    /// This strategy, or `other` if this one fails.
+    pub fn or(self, other: Self) -> Self {
+        match self {
+            Strategy::Choice(mut strategies) => {
+                strategies.push(other);
+                Strategy::Choice(strategies)
+            },
+            first => Strategy::Choice(vec![first, other]),
+        }
+    }
+
+    pub fn repeat(self) -> Self {
+        Strategy::Repeat(Box::new(self))
+    }
+
+    pub fn once_top_down(self) -> Self {
+        Strategy::OnceTopDown(Box::new(self))
+    }
+
+    pub fn once_bottom_up(self) -> Self {
+        Strategy::OnceBottomUp(Box::new(self))
+    }
+
+    pub fn top_down(self) -> Self {
+        Strategy::TopDown(Box::new(self))
+    }
+
+    pub fn bottom_up(self) -> Self {
+        Strategy::BottomUp(Box::new(self))
+    }
+
+    /// Rewrite the innermost, leftmost subtree where this strategy applies, until it applies nowhere.
+    pub fn innermost(self) -> Self {
+        self.once_bottom_up().repeat()
+    }
+
+    /// Rewrite the outermost, leftmost subtree where this strategy applies, until it applies nowhere.
+    pub fn outermost(self) -> Self {
+        self.once_top_down().repeat()
+    }
+
+    /// Apply the strategy to `tree`, making at most `steps` rewrites.
+    ///
+    /// Returns `Ok(None)` if the strategy fails, and [`StrategyError::StepLimit`] if it would need more rewrites
+    /// than that, which is what happens when the rules can rewrite forever.
+    pub fn apply<T: Tree<L=L, I=I>>(&self, tree: &T, steps: usize) -> Result<Option<T>, StrategyError> {
+        tree.validate().map_err(StrategyError::InvalidTree)?;
+
+        let mut budget = Budget { limit: steps, used: 0 };
+        Ok(self.run(tree.tokens(), 0, &mut budget)?.map(T::new))
+    }
+
+    /// Apply the strategy to `tree`, keeping the tree as it is if the strategy fails.
+    pub fn normalise<T: Tree<L=L, I=I> + Clone>(&self, tree: &T, steps: usize) -> Result<T, StrategyError> {
+        Ok(self.apply(tree, steps)?.unwrap_or_else(|| tree.clone()))
+    }
+
+    /// Apply the strategy to the subtree of `tokens` starting at token `at`, returning the tokens of the whole new
+    /// tree if it succeeds. Rewriting a subtree never moves its start, so `at` stays valid throughout.
+    fn run(&self, tokens: &[Node<L, I>], at: usize, budget: &mut Budget) -> Result<Option<Vec<Node<L, I>>>, StrategyError> {
+        match self {
+            Strategy::Rules(rules) => {
+                for rule in rules {
+                    if let Some((_, new_tokens)) = rule.rewrites(tokens, Some(at)).next() {
+                        budget.spend()?;
+                        return Ok(Some(new_tokens));
+                    }
+                }
+                Ok(None)
+            },
+            Strategy::Sequence(strategies) => {
+                let mut current = tokens.to_vec();
+                for strategy in strategies {
+                    match strategy.run(&current, at, budget)? {
+                        Some(new_tokens) => current = new_tokens,
+                        None => return Ok(None),
+                    }
+                }
+                Ok(Some(current))
+            },
+            Strategy::Choice(strategies) => {
+                for strategy in strategies {
+                    if let Some(new_tokens) = strategy.run(tokens, at, budget)? {
+                        return Ok(Some(new_tokens));
+                    }
+                }
+                Ok(None)
+            },
+            Strategy::Repeat(strategy) => {
+                let mut current = tokens.to_vec();
+                while let Some(new_tokens) = strategy.run(&current, at, budget)? {
+                    if same_tokens(&new_tokens, &current) {
+                        break
+                    }
+                    current = new_tokens;
+                }
+                Ok(Some(current))
+            },
+            Strategy::OnceTopDown(strategy) => {
+                for position in pre_order(tokens, at) {
+                    if let Some(new_tokens) = strategy.run(tokens, position, budget)? {
+                        return Ok(Some(new_tokens));
+                    }
+                }
+                Ok(None)
+            },
+            Strategy::OnceBottomUp(strategy) => {
+                for position in post_order(tokens, at) {
+                    if let Some(new_tokens) = strategy.run(tokens, position, budget)? {
+                        return Ok(Some(new_tokens));
+                    }
+                }
+                Ok(None)
+            },
+            Strategy::TopDown(strategy) => {
+                let current = strategy.run(tokens, at, budget)?.unwrap_or_else(|| tokens.to_vec());
+                self.run_children(current, at, budget).map(Some)
+            },
+            Strategy::BottomUp(strategy) => {
+                let current = self.run_children(tokens.to_vec(), at, budget)?;
+                Ok(Some(strategy.run(&current, at, budget)?.unwrap_or(current)))
+            },
+        }
+    }
+
+    /// Apply the strategy to each child of the node at `at` in turn, keeping any child it fails on as it is.
+    fn run_children(&self, mut tokens: Vec<Node<L, I>>, at: usize, budget: &mut Budget) -> Result<Vec<Node<L, I>>, StrategyError> {
+        let mut child = at + 1;
+        for _ in 0..tokens[at].arity() {
+            if let Some(new_tokens) = self.run(&tokens, child, budget)? {
+                tokens = new_tokens;
+            }
+            // Rewrites always leave a well formed tree, so every child has an end
+            child = match subtree_end(&tokens, child) {
+                Some(end) => end,
+                None => break,
+            };
+        }
+        Ok(tokens)
+    }
+}
+
+fn same_tokens<L: TreeNode, I: TreeNode>(a: &[Node<L, I>], b: &[Node<L, I>]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_token(a, b))
+}
+
+/// The starts of every subtree within the subtree starting at `at`, in pre-order.
+fn pre_order<L: TreeNode, I: TreeNode>(tokens: &[Node<L, I>], at: usize) -> std::ops::Range<usize> {
+    at..subtree_end(tokens, at).unwrap_or(at)
+}
+
+/// The starts of every subtree within the subtree starting at `at`, in post-order.
+fn post_order<L: TreeNode, I: TreeNode>(tokens: &[Node<L, I>], at: usize) -> Vec<usize> {
+    // A subtree ends with its last descendant, so sorting by end puts every subtree after its earlier siblings and
+    // descendants, and the later start goes first among those sharing an end
+    let mut positions: Vec<(usize, usize)> = pre_order(tokens, at)
+        .filter_map(|position| Some((subtree_end(tokens, position)?, position)))
+        .collect();
+    positions.sort_by_key(|(end, position)| (*end, Reverse(*position)));
+    positions.into_iter().map(|(_, position)| position).collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{Tree, Node};
+    use crate::test_utils::{Equation, Element, Operator, leaf, wildcard, literal, rule};
+
+    use super::{Strategy, StrategyError};
+
+    /// x+0 = x and x*1 = x
+    fn identities() -> Strategy<Element, Operator> {
+        Strategy::rules(vec![
+            rule(vec![Node::Internal(Operator::ADD), wildcard("x"), literal("0")], vec![wildcard("x")]),
+            rule(vec![Node::Internal(Operator::MULTIPLY), wildcard("x"), literal("1")], vec![wildcard("x")]),
+        ])
+    }
+
+    /// x*(y+z) = x*y + x*z
+    fn distribute() -> Strategy<Element, Operator> {
+        Strategy::rules(vec![rule(
+            vec![Node::Internal(Operator::MULTIPLY), wildcard("x"), Node::Internal(Operator::ADD), wildcard("y"), wildcard("z")],
+            vec![
+                Node::Internal(Operator::ADD),
+                Node::Internal(Operator::MULTIPLY), wildcard("x"), wildcard("y"),
+                Node::Internal(Operator::MULTIPLY), wildcard("x"), wildcard("z"),
+            ],
+        )])
+    }
+
+    #[test]
+    fn test_traversal_order() {
+        // ((a+0)*1)+0
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), Node::Internal(Operator::ADD), leaf("a"), leaf("0"), leaf("1"),
+            leaf("0"),
+        ]);
+
+        // The root goes first top down, and the innermost sum bottom up
+        let top_down: Equation = identities().once_top_down().apply(&tree, 10).unwrap().unwrap();
+        assert_eq!(top_down, Equation::new(vec![
+            Node::Internal(Operator::MULTIPLY), Node::Internal(Operator::ADD), leaf("a"), leaf("0"), leaf("1"),
+        ]));
+        let bottom_up: Equation = identities().once_bottom_up().apply(&tree, 10).unwrap().unwrap();
+        assert_eq!(bottom_up, Equation::new(vec![
+            Node::Internal(Operator::ADD), Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("1"), leaf("0"),
+        ]));
+
+        // A single pass either way removes every identity here, as removing one never exposes another
+        let normal = Equation::new(vec![leaf("a")]);
+        for strategy in [identities().bottom_up(), identities().innermost(), identities().outermost()] {
+            assert_eq!(strategy.apply(&tree, 10).unwrap(), Some(normal.clone()));
+        }
+        // Top down, the root is rewritten to a product and the pass moves on to its children
+        let top_down: Equation = identities().top_down().apply(&tree, 10).unwrap().unwrap();
+        assert_eq!(top_down, Equation::new(vec![Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("1")]));
+
+        // Nothing left to rewrite
+        assert_eq!(identities().once_top_down().apply(&normal, 10).unwrap(), None);
+        assert_eq!(identities().once_top_down().normalise(&normal, 10).unwrap(), normal);
+        assert_eq!(identities().innermost().apply(&normal, 10).unwrap(), Some(normal));
+    }
+
+    #[test]
+    fn test_sequence_and_choice() {
+        // a*((b+0)+c)
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::MULTIPLY), leaf("a"),
+            Node::Internal(Operator::ADD), Node::Internal(Operator::ADD), leaf("b"), leaf("0"), leaf("c"),
+        ]);
+        // a*b + a*c
+        let expanded = Equation::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b"),
+            Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("c"),
+        ]);
+
+        let simplify_then_expand = identities().innermost().then(distribute().innermost());
+        assert_eq!(simplify_then_expand.apply(&tree, 10).unwrap(), Some(expanded.clone()));
+
+        let either = identities().or(distribute()).innermost();
+        assert_eq!(either.apply(&tree, 10).unwrap(), Some(expanded));
+
+        // A sequence fails as soon as one of its strategies does
+        let strict = identities().once_top_down().then(identities().once_top_down());
+        assert_eq!(strict.apply(&tree, 10).unwrap(), None);
+    }
+
+    #[test]
+    fn test_step_limit() {
+        // x+y = y+x rewrites forever
+        let commute = Strategy::rules(vec![rule(
+            vec![Node::Internal(Operator::ADD), wildcard("x"), wildcard("y")],
+            vec![Node::Internal(Operator::ADD), wildcard("y"), wildcard("x")],
+        )]);
+        let tree = Equation::new(vec![Node::Internal(Operator::ADD), leaf("a"), leaf("b")]);
+
+        assert_eq!(commute.clone().innermost().apply(&tree, 5), Err(StrategyError::StepLimit(5)));
+        assert_eq!(commute.clone().apply(&tree, 1).unwrap(), Some(Equation::new(vec![Node::Internal(Operator::ADD), leaf("b"), leaf("a")])));
+        assert_eq!(commute.apply(&tree, 0), Err(StrategyError::StepLimit(0)));
+
+        let truncated = Equation::new(vec![Node::Internal(Operator::ADD), leaf("a")]);
+        assert!(matches!(identities().apply(&truncated, 5), Err(StrategyError::InvalidTree(_))));
+    }
+
+    #[test]
+    fn test_repeated_traversal_stops_when_nothing_changes() {
+        // Traversals succeed whether or not they rewrite anything, so repeating one must stop at a fixed point
+        let r = rule(vec![Node::Internal(Operator::ADD), wildcard("x"), literal("0")], vec![wildcard("x")]);
+        let tree = Equation::new(vec![Node::Internal(Operator::MULTIPLY), leaf("a"), leaf("b")]);
+        assert_eq!(Strategy::rules(vec![r.clone()]).top_down().repeat().apply(&tree, 100).unwrap(), Some(tree.clone()));
+        assert_eq!(Strategy::rules(vec![r]).bottom_up().repeat().apply(&tree, 100).unwrap(), Some(tree.clone()));
+
+        // (a+0)+0 takes one pass, and a second to see nothing changes
+        let tree = Equation::new(vec![Node::Internal(Operator::ADD), Node::Internal(Operator::ADD), leaf("a"), leaf("0"), leaf("0")]);
+        assert_eq!(identities().top_down().repeat().apply(&tree, 100).unwrap(), Some(Equation::new(vec![leaf("a")])));
+    }
+}
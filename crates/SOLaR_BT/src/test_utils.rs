@@ -1,9 +1,21 @@
-use crate::{Tree, Node, TreeNode, PatternLeaf, tree::ParsableTreeNode};
+use crate::{Tree, Node, TreeNode, PatternLeaf, Rule, tree::ParsableTreeNode};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Operator {
     ADD,
     MULTIPLY,
+    SUBTRACT,
+    DIVIDE,
+    POWER,
+    COMPOSE,
+    INVERSE,
+    CONDITIONAL,
+    /// A `+` with more than two arguments
+    SUM(usize),
+    /// A constant, as an operator with no arguments
+    PI,
 }
 
 impl TreeNode for Operator {
@@ -11,6 +23,32 @@ impl TreeNode for Operator {
         match self {
             Self::ADD => String::from("+"),
             Self::MULTIPLY => String::from("*"),
+            Self::SUBTRACT => String::from("-"),
+            Self::DIVIDE => String::from("/"),
+            Self::POWER => String::from("^"),
+            Self::COMPOSE => String::from("."),
+            Self::INVERSE => String::from("inv"),
+            Self::CONDITIONAL => String::from("if"),
+            Self::SUM(_) => String::from("+"),
+            Self::PI => String::from("pi"),
+        }
+    }
+
+    fn arity(&self) -> usize {
+        match self {
+            Self::INVERSE => 1,
+            Self::CONDITIONAL => 3,
+            Self::SUM(arity) => *arity,
+            Self::PI => 0,
+            _ => 2,
+        }
+    }
+
+    fn with_arity(&self, arity: usize) -> Option<Self> {
+        match self {
+            Self::ADD | Self::SUM(_) if arity == 2 => Some(Self::ADD),
+            Self::ADD | Self::SUM(_) if arity > 2 => Some(Self::SUM(arity)),
+            _ => None,
         }
     }
 }
@@ -20,12 +58,27 @@ impl ParsableTreeNode for Operator {
         match id {
             "+" => Ok(Self::ADD),
             "*" => Ok(Self::MULTIPLY),
-            _ => panic!(),
+            "-" => Ok(Self::SUBTRACT),
+            "/" => Ok(Self::DIVIDE),
+            "^" => Ok(Self::POWER),
+            "." => Ok(Self::COMPOSE),
+            "inv" => Ok(Self::INVERSE),
+            "if" => Ok(Self::CONDITIONAL),
+            "pi" => Ok(Self::PI),
+            _ => Err("Unknown operator"),
+        }
+    }
+
+    fn from_string_with_arity(id: &str, arity: usize) -> Result<Self, &'static str> {
+        match id {
+            "+" if arity > 2 => Ok(Self::SUM(arity)),
+            _ => Self::from_string(id),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Element {
     pub label: Vec<u8>,
 }
@@ -36,7 +89,7 @@ impl TreeNode for Element {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Equation {
     tokens: Vec<Node<Element, Operator>>,
 }
@@ -59,7 +112,7 @@ impl Tree for Equation {
         }
     }
 
-    fn iter(&self) -> std::slice::Iter<Node<Self::L, Self::I>> {
+    fn iter(&self) -> std::slice::Iter<'_, Node<Self::L, Self::I>> {
         self.tokens.iter()
     }
 
@@ -67,7 +120,7 @@ impl Tree for Equation {
         &self.tokens
     }
 
-    fn tokens_mut<'a>(&'a mut self) -> &'a mut Vec<Node<Self::L, Self::I>> {
+    fn tokens_mut(&mut self) -> &mut Vec<Node<Self::L, Self::I>> {
         &mut self.tokens
     }
 
@@ -76,6 +129,7 @@ impl Tree for Equation {
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct Identity {
     tokens: Vec<Node<PatternLeaf<Element>, Operator>>,
 }
@@ -90,7 +144,7 @@ impl Tree for Identity {
         }
     }
 
-    fn iter(&self) -> std::slice::Iter<Node<Self::L, Self::I>> {
+    fn iter(&self) -> std::slice::Iter<'_, Node<Self::L, Self::I>> {
         self.tokens.iter()
     }
 
@@ -98,11 +152,55 @@ impl Tree for Identity {
         &self.tokens
     }
 
-    fn tokens_mut<'a>(&'a mut self) -> &'a mut Vec<Node<Self::L, Self::I>> {
+    fn tokens_mut(&mut self) -> &mut Vec<Node<Self::L, Self::I>> {
         &mut self.tokens
     }
 
     fn into_tokens(self) -> Vec<Node<Self::L, Self::I>> {
         self.tokens
     }
-}
\ No newline at end of file
+}
+
+pub fn leaf(label: &str) -> Node<Element, Operator> {
+    Node::Leaf(Element {
+        label: label.as_bytes().to_vec(),
+    })
+}
+
+pub fn wildcard(name: &str) -> Node<PatternLeaf<Element>, Operator> {
+    Node::Leaf(PatternLeaf::Subtree(name.to_string()))
+}
+
+pub fn literal(label: &str) -> Node<PatternLeaf<Element>, Operator> {
+    Node::Leaf(PatternLeaf::Literal(Element {
+        label: label.as_bytes().to_vec(),
+    }))
+}
+
+/// Compile a rule from the tokens of its two sides.
+pub fn rule(pattern: Vec<Node<PatternLeaf<Element>, Operator>>, replacement: Vec<Node<PatternLeaf<Element>, Operator>>) -> Rule<Element, Operator> {
+    Rule::compile(Identity::new(pattern), Identity::new(replacement)).unwrap()
+}
+
+/// Compile a rule from an identity's two sides.
+pub fn compile((pattern, replacement): (Identity, Identity)) -> Rule<Element, Operator> {
+    Rule::compile(pattern, replacement).unwrap()
+}
+
+/// x.y = y.x, for the operator `.`
+pub fn commutativity(operator: Operator) -> (Identity, Identity) {
+    let op = || Node::Internal(operator.clone());
+    (
+        Identity::new(vec![op(), wildcard("x"), wildcard("y")]),
+        Identity::new(vec![op(), wildcard("y"), wildcard("x")]),
+    )
+}
+
+/// (x.y).z = x.(y.z), for the operator `.`
+pub fn associativity(operator: Operator) -> (Identity, Identity) {
+    let op = || Node::Internal(operator.clone());
+    (
+        Identity::new(vec![op(), op(), wildcard("x"), wildcard("y"), wildcard("z")]),
+        Identity::new(vec![op(), wildcard("x"), op(), wildcard("y"), wildcard("z")]),
+    )
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+use crate::tree::TreeNode;
+
+/// Equational properties of an operator which patterns should be matched modulo.
+///
+/// Associativity is only respected for binary operators.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OperatorProperties {
+    pub associative: bool,
+    pub commutative: bool,
+}
+
+/// A per-operator declaration of which operators are associative and/or commutative.
+///
+/// Operators are identified by their `to_string` representation. An empty theory gives purely syntactic matching.
+///
+/// Modulo associativity and commutativity a subtree can match in exponentially many ways, so the theory can also bound
+/// how many rewrites of any one subtree are produced.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Theory {
+    operators: HashMap<String, OperatorProperties>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    match_limit: Option<usize>,
+}
+
+impl Theory {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare the properties of `operator`, replacing anything previously declared for it.
+    pub fn declare<I: TreeNode>(mut self, operator: &I, properties: OperatorProperties) -> Self {
+        self.operators.insert(operator.to_string(), properties);
+        self
+    }
+
+    pub fn associative<I: TreeNode>(mut self, operator: &I) -> Self {
+        self.operators.entry(operator.to_string()).or_default().associative = true;
+        self
+    }
+
+    pub fn commutative<I: TreeNode>(mut self, operator: &I) -> Self {
+        self.operators.entry(operator.to_string()).or_default().commutative = true;
+        self
+    }
+
+    /// Produce at most `limit` rewrites of each subtree when matching modulo the theory.
+    pub fn limit_matches(mut self, limit: usize) -> Self {
+        self.match_limit = Some(limit);
+        self
+    }
+
+    /// The most rewrites of each subtree matching produces, if bounded.
+    pub fn match_limit(&self) -> Option<usize> {
+        self.match_limit
+    }
+
+    pub fn properties<I: TreeNode>(&self, operator: &I) -> OperatorProperties {
+        let mut properties = self.operators.get(&operator.to_string()).copied().unwrap_or_default();
+        properties.associative &= operator.arity() == 2;
+        properties
+    }
+
+    /// True if no operator has any properties, in which case matching is purely syntactic.
+    pub fn is_syntactic(&self) -> bool {
+        self.operators.values().all(|properties| !properties.associative && !properties.commutative)
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+
+    use crate::test_utils::Operator;
+
+    use super::Theory;
+
+    #[test]
+    fn test_serde_round_trip() {
+        let theory = Theory::new().associative(&Operator::ADD).commutative(&Operator::MULTIPLY);
+        assert_eq!(serde_json::from_str::<Theory>(&serde_json::to_string(&theory).unwrap()).unwrap(), theory);
+    }
+}
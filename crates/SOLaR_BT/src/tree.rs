@@ -1,4 +1,8 @@
 use core::slice::Iter;
+use std::fmt;
+use std::ops::Range;
+
+use crate::location::{children, parent, subtree_end};
 
 /// Represents a node of a tree. All trees are composed of TreeNodes
 pub trait TreeNode {
@@ -7,21 +11,120 @@ pub trait TreeNode {
     fn uid(&self) -> String {
         self.to_string()
     }
+
+    /// The number of children this node has when it is used as an internal node. Leaves ignore it.
+    ///
+    /// Variadic node types should report the number of children of this particular instance.
+    fn arity(&self) -> usize {
+        2
+    }
+
+    /// The number of children this node has when it is used as a leaf. Only stand-ins for internal nodes, like the
+    /// operator wildcards of patterns, have any.
+    fn leaf_arity(&self) -> usize {
+        0
+    }
+
+    /// This operator rebuilt to take `arity` children, for variadic types whose arity is part of the value. Rewrites
+    /// which change the number of arguments a node has, like those with sequence wildcards, need it.
+    fn with_arity(&self, _arity: usize) -> Option<Self> where Self: Sized {
+        None
+    }
 }
 
 /// If a tree is to be constructable from a string, it needs to be composed of elements which implement `ParsableTreeNode`
 pub trait ParsableTreeNode where Self: TreeNode + Sized {
     fn from_string(id: &str) -> Result<Self, &'static str>;
+
+    /// Parse an internal node which was found with `arity` children.
+    ///
+    /// Variadic node types should override this to record the number of children they were parsed with.
+    fn from_string_with_arity(id: &str, _arity: usize) -> Result<Self, &'static str> {
+        Self::from_string(id)
+    }
 }
 
 /// All tree nodes are wrapped in the `Node` type to express whether the node is a leaf or branch node
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Node<L: TreeNode, I: TreeNode> {
     Leaf(L),
     Internal(I),
 }
 
-/// 
+impl<L: TreeNode, I: TreeNode> Node<L, I> {
+
+    /// The number of children which follow this token in pre-order.
+    pub fn arity(&self) -> usize {
+        match self {
+            Node::Leaf(l) => l.leaf_arity(),
+            Node::Internal(o) => o.arity(),
+        }
+    }
+}
+
+/// Ways in which a token vector can fail to describe exactly one tree in pre-order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TreeParseError {
+    /// There are no tokens at all
+    Empty,
+    /// The tokens ran out before the internal node at `index` had all of its children
+    Truncated { index: usize },
+    /// The tree was already complete, and the first token left over is at `index`
+    TrailingTokens { index: usize },
+}
+
+impl fmt::Display for TreeParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeParseError::Empty => write!(f, "the tree has no tokens"),
+            TreeParseError::Truncated { index } => write!(f, "the internal node at token {index} is missing children"),
+            TreeParseError::TrailingTokens { index } => write!(f, "unexpected tokens after the end of the tree, from token {index}"),
+        }
+    }
+}
+
+impl std::error::Error for TreeParseError {}
+
+/// Check that `tokens` describe exactly one tree in pre-order.
+pub(crate) fn validate_tokens<L: TreeNode, I: TreeNode>(tokens: &[Node<L, I>]) -> Result<(), TreeParseError> {
+    if tokens.is_empty() {
+        return Err(TreeParseError::Empty);
+    }
+
+    // Open internal nodes and the number of children they are still waiting for
+    let mut open: Vec<(usize, usize)> = vec![];
+
+    for (index, token) in tokens.iter().enumerate() {
+        if index > 0 && open.is_empty() {
+            return Err(TreeParseError::TrailingTokens { index });
+        }
+
+        if let Some((_, remaining)) = open.last_mut() {
+            *remaining -= 1;
+        }
+        open.push((index, token.arity()));
+        while let Some((_, 0)) = open.last() {
+            open.pop();
+        }
+    }
+
+    match open.last() {
+        Some((index, _)) => Err(TreeParseError::Truncated { index: *index }),
+        None => Ok(()),
+    }
+}
+
+/// Whether two tokens are the same: leaves by their uid, and internal nodes by their label.
+pub(crate) fn same_token<L: TreeNode, I: TreeNode>(a: &Node<L, I>, b: &Node<L, I>) -> bool {
+    match (a, b) {
+        (Node::Leaf(a), Node::Leaf(b)) => a.uid() == b.uid(),
+        (Node::Internal(a), Node::Internal(b)) => a.to_string() == b.to_string(),
+        _ => false,
+    }
+}
+
+/// A tree stored as its nodes in pre-order
 pub trait Tree where Self: Sized {
     type L: TreeNode;
     type I: TreeNode;
@@ -32,42 +135,78 @@ pub trait Tree where Self: Sized {
     fn tokens_mut(&mut self) -> &mut Vec<Node<Self::L, Self::I>>;
     fn into_tokens(self) -> Vec<Node<Self::L, Self::I>>;
 
-    fn iter(&self) -> Iter<Node<Self::L, Self::I>> {
+    fn iter(&self) -> Iter<'_, Node<Self::L, Self::I>> {
         self.tokens().iter()
     }
 
-    /// Join two trees together with an internal node
+    /// Join two trees together with a binary internal node
     fn join(operator: Self::I, tree_1: Self, tree_2: Self) -> Self {
-        let mut tokens: Vec<Node<Self::L, Self::I>> = Vec::with_capacity(1 + tree_1.tokens().len() + tree_2.tokens().len());
-        
+        Self::join_many(operator, vec![tree_1, tree_2])
+    }
+
+    /// Join any number of trees together with an internal node. Panics unless its arity matches the number of trees.
+    fn join_many(operator: Self::I, trees: Vec<Self>) -> Self {
+        assert_eq!(operator.arity(), trees.len(), "an operator of arity {} can't join {} trees", operator.arity(), trees.len());
+
+        let capacity = 1 + trees.iter().map(|tree| tree.tokens().len()).sum::<usize>();
+        let mut tokens: Vec<Node<Self::L, Self::I>> = Vec::with_capacity(capacity);
+
         tokens.push(Node::Internal(operator));
-        tokens.append(&mut tree_1.into_tokens());
-        tokens.append(&mut tree_2.into_tokens());
+        for tree in trees {
+            tokens.append(&mut tree.into_tokens());
+        }
         Self::new(tokens)
     }
 
-    /// Lower is better
-    fn simplicity(&self) -> usize {
-        self.tokens().len()
+    /// Check that the tokens describe exactly one tree in pre-order.
+    fn validate(&self) -> Result<(), TreeParseError> {
+        validate_tokens(self.tokens())
     }
-}
 
-pub trait ParsableTree where Self: Tree {
-    type L: ParsableTreeNode;
-    type I: ParsableTreeNode;
-}
+    /// The tokens of the subtree rooted at token `index`, or `None` if there is no such subtree.
+    fn subtree_span(&self, index: usize) -> Option<Range<usize>> {
+        Some(index..subtree_end(self.tokens(), index)?)
+    }
 
-impl<L, B, T> ParsableTree for T where T: Tree<L=L, I=B>, L: ParsableTreeNode, B: ParsableTreeNode {
-    type L = L;
-    type I = B;
-}
+    /// A copy of the subtree rooted at token `index`.
+    fn subtree(&self, index: usize) -> Option<Self> where Self::L: Clone, Self::I: Clone {
+        Some(Self::new(self.tokens()[self.subtree_span(index)?].to_vec()))
+    }
+
+    /// The token indices of the children of the node at `index`, in order.
+    fn children(&self, index: usize) -> Option<Vec<usize>> {
+        children(self.tokens(), index)
+    }
+
+    /// The token index of child `n` of the node at `index`.
+    fn child(&self, index: usize, n: usize) -> Option<usize> {
+        self.children(index)?.get(n).copied()
+    }
 
+    /// The token index of the parent of the node at `index`, or `None` for the root.
+    fn parent(&self, index: usize) -> Option<usize> {
+        parent(self.tokens(), index)
+    }
+
+    /// Replace the subtree rooted at token `index` with `replacement`, returning the subtree which was there. Returns
+    /// `None`, leaving the tree as it is, if there is no such subtree or `replacement` isn't a single well formed tree.
+    fn replace_subtree(&mut self, index: usize, replacement: Self) -> Option<Self> {
+        replacement.validate().ok()?;
+        let span = self.subtree_span(index)?;
+        let removed = self.tokens_mut().splice(span, replacement.into_tokens()).collect();
+        Some(Self::new(removed))
+    }
 
+    /// Lower is better
+    fn simplicity(&self) -> usize {
+        self.tokens().len()
+    }
+}
 
 #[cfg(test)]
 mod tests {
 
-    use super::{Tree, Node, TreeNode};
+    use super::{Tree, Node, TreeNode, TreeParseError};
 
     impl TreeNode for String {
         fn to_string(&self) -> String {
@@ -93,7 +232,7 @@ mod tests {
             &self.tokens
         }
 
-        fn tokens_mut<'a>(&'a mut self) -> &'a mut Vec<Node<Self::L, Self::I>> {
+        fn tokens_mut(&mut self) -> &mut Vec<Node<Self::L, Self::I>> {
             &mut self.tokens
         }
 
@@ -129,6 +268,65 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn test_join_many(){
+        struct Negate;
+
+        impl TreeNode for Negate {
+            fn to_string(&self) -> String {
+                String::from("neg")
+            }
+
+            fn arity(&self) -> usize {
+                1
+            }
+        }
+
+        struct UnaryTree {
+            tokens: Vec<Node<String, Negate>>,
+        }
+
+        impl Tree for UnaryTree {
+            type L = String;
+            type I = Negate;
+
+            fn new(tokens: Vec<Node<String, Negate>>) -> Self {
+                Self {
+                    tokens,
+                }
+            }
+
+            fn tokens(&self) -> &Vec<Node<Self::L, Self::I>> {
+                &self.tokens
+            }
+
+            fn tokens_mut(&mut self) -> &mut Vec<Node<Self::L, Self::I>> {
+                &mut self.tokens
+            }
+
+            fn into_tokens(self) -> Vec<Node<Self::L, Self::I>> {
+                self.tokens
+            }
+        }
+
+        let tree = UnaryTree::new(vec![
+            Node::Leaf(String::from("a")),
+        ]);
+
+        let negated = UnaryTree::join_many(Negate, vec![tree]);
+
+        assert_eq!(negated.tokens.len(), 2);
+        assert!(matches!(negated.tokens[0], Node::Internal(Negate)));
+        assert!(matches!(&negated.tokens[1], Node::Leaf(a) if a == "a"));
+    }
+
+    #[test]
+    #[should_panic(expected = "an operator of arity 2 can't join 3 trees")]
+    fn test_join_many_with_wrong_arity(){
+        let leaf = || TestTree::new(vec![Node::Leaf(String::from("a"))]);
+        TestTree::join_many(String::from("+"), vec![leaf(), leaf(), leaf()]);
+    }
+
     #[test]
     fn test_simplicity(){
         let tree = TestTree::new(vec![
@@ -150,4 +348,60 @@ mod tests {
         
         assert!(tree.iter().eq(tree.tokens.iter()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_validate(){
+        let tree = |labels: &[&str]| TestTree::new(labels.iter().map(|label| match *label {
+            "+" | "*" => Node::Internal(label.to_string()),
+            _ => Node::Leaf(label.to_string()),
+        }).collect());
+
+        assert_eq!(tree(&["+", "a", "*", "b", "c"]).validate(), Ok(()));
+        assert_eq!(tree(&[]).validate(), Err(TreeParseError::Empty));
+        assert_eq!(tree(&["+", "a", "*", "b"]).validate(), Err(TreeParseError::Truncated { index: 2 }));
+        assert_eq!(tree(&["+", "a"]).validate(), Err(TreeParseError::Truncated { index: 0 }));
+        assert_eq!(tree(&["+", "a", "b", "c"]).validate(), Err(TreeParseError::TrailingTokens { index: 3 }));
+    }
+
+    #[test]
+    fn test_navigation(){
+        // (a + b) * (c - d)
+        let labels = ["*", "+", "a", "b", "-", "c", "d"];
+        let mut tree = TestTree::new(labels.iter().map(|label| match *label {
+            "*" | "+" | "-" => Node::Internal(label.to_string()),
+            _ => Node::Leaf(label.to_string()),
+        }).collect());
+
+        assert_eq!(tree.subtree_span(0), Some(0..7));
+        assert_eq!(tree.subtree_span(4), Some(4..7));
+        assert_eq!(tree.subtree_span(7), None);
+
+        assert_eq!(tree.children(0), Some(vec![1, 4]));
+        assert_eq!(tree.children(2), Some(vec![]));
+        assert_eq!(tree.child(4, 1), Some(6));
+        assert_eq!(tree.child(4, 2), None);
+
+        assert_eq!(tree.parent(0), None);
+        assert_eq!(tree.parent(3), Some(1));
+        assert_eq!(tree.parent(4), Some(0));
+        assert_eq!(tree.parent(7), None);
+
+        let removed = tree.replace_subtree(1, TestTree::new(vec![Node::Leaf(String::from("e"))])).unwrap();
+        assert_eq!(removed.tokens.len(), 3);
+        assert_eq!(tree.tokens, vec![
+            Node::Internal(String::from("*")),
+            Node::Leaf(String::from("e")),
+            Node::Internal(String::from("-")),
+            Node::Leaf(String::from("c")),
+            Node::Leaf(String::from("d")),
+        ]);
+        assert!(tree.replace_subtree(5, TestTree::new(vec![])).is_none());
+        assert_eq!(tree.tokens.len(), 5);
+
+        // Replacements which aren't a single tree would leave the tree malformed
+        assert!(tree.replace_subtree(1, TestTree::new(vec![])).is_none());
+        assert!(tree.replace_subtree(1, TestTree::new(vec![Node::Internal(String::from("+")), Node::Leaf(String::from("a"))])).is_none());
+        assert_eq!(tree.tokens.len(), 5);
+        assert_eq!(tree.validate(), Ok(()));
+    }
+}
@@ -0,0 +1,514 @@
+use std::fmt;
+
+use crate::PatternLeaf;
+use crate::location::Location;
+use crate::tree::{Node, Tree, TreeNode, TreeParseError, validate_tokens};
+
+/// Why two patterns don't unify.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UnificationError {
+    /// The patterns have different nodes, with these labels, in the same place
+    Clash { left: String, right: String },
+    /// The wildcard would have to be bound to a subtree containing itself
+    Occurs(String),
+    /// One of the patterns isn't a single well formed tree
+    InvalidPattern(TreeParseError),
+    /// The wildcard takes a varying number of arguments, which syntactic unification can't account for
+    SequenceWildcard(String),
+}
+
+impl fmt::Display for UnificationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnificationError::Clash { left, right } => write!(f, "`{left}` can't be unified with `{right}`"),
+            UnificationError::Occurs(name) => write!(f, "wildcard `{name}` would have to contain itself"),
+            UnificationError::InvalidPattern(e) => write!(f, "invalid pattern: {e}"),
+            UnificationError::SequenceWildcard(name) => write!(f, "sequence wildcard `...{name}` can't be unified syntactically"),
+        }
+    }
+}
+
+impl std::error::Error for UnificationError {}
+
+/// A substitution of patterns for wildcards, as found by [`unify`] or [`specialisation`].
+///
+/// Bindings are fully resolved, so a bound pattern never mentions a wildcard which is itself bound.
+#[derive(Clone, Debug)]
+pub struct Unifier<L: TreeNode, I: TreeNode> {
+    bindings: Vec<(String, Pattern<L, I>)>,
+}
+
+/// The tokens of a pattern.
+type Pattern<L, I> = Vec<Node<PatternLeaf<L>, I>>;
+
+impl<L: TreeNode + Clone, I: TreeNode + Clone> Unifier<L, I> {
+
+    /// The pattern bound to `wildcard`, if it's bound. An operator wildcard is bound to a single token, the operator or
+    /// operator wildcard which it stands for.
+    pub fn get(&self, wildcard: &str) -> Option<&[Node<PatternLeaf<L>, I>]> {
+        self.bindings.iter().find(|(name, _)| name == wildcard).map(|(_, tokens)| tokens.as_slice())
+    }
+
+    /// Every bound wildcard and its pattern.
+    pub fn bindings(&self) -> impl Iterator<Item = (&str, &[Node<PatternLeaf<L>, I>])> {
+        self.bindings.iter().map(|(name, tokens)| (name.as_str(), tokens.as_slice()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bindings.is_empty()
+    }
+
+    /// Substitute the bound patterns for their wildcards in `pattern`.
+    pub fn apply<P: Tree<L=PatternLeaf<L>, I=I>>(&self, pattern: &P) -> P {
+        P::new(self.apply_tokens(pattern.tokens()))
+    }
+
+    fn apply_tokens(&self, tokens: &[Node<PatternLeaf<L>, I>]) -> Pattern<L, I> {
+        tokens.iter().flat_map(|token| match token {
+            Node::Leaf(leaf) => match leaf.wildcard().and_then(|name| self.get(name)) {
+                Some(bound) => bound.to_vec(),
+                None => vec![token.clone()],
+            },
+            Node::Internal(_) => vec![token.clone()],
+        }).collect()
+    }
+}
+
+/// A pattern as a nested term, which is easier to unify than a flat token list.
+enum Term<'p, L: TreeNode, I: TreeNode> {
+    Wildcard(&'p str),
+    /// A wildcard which only stands for leaves
+    LeafWildcard(&'p str),
+    Literal(&'p L),
+    Internal(Head<'p, I>, Vec<Term<'p, L, I>>),
+}
+
+/// What labels an internal term: an operator, or an operator wildcard and its arity.
+enum Head<'p, I: TreeNode> {
+    Operator(&'p I),
+    Wildcard(&'p str, usize),
+}
+
+fn term<'p, L: TreeNode, I: TreeNode>(tokens: &'p [Node<PatternLeaf<L>, I>], cursor: &mut usize) -> Term<'p, L, I> {
+    let token = &tokens[*cursor];
+    *cursor += 1;
+    let head = match token {
+        // Sequences are turned away by `parse`
+        Node::Leaf(PatternLeaf::Subtree(name) | PatternLeaf::Sequence(name)) => return Term::Wildcard(name),
+        Node::Leaf(PatternLeaf::Leaf(name)) => return Term::LeafWildcard(name),
+        Node::Leaf(PatternLeaf::Literal(l)) => return Term::Literal(l),
+        Node::Leaf(PatternLeaf::Operator(name, arity)) => Head::Wildcard(name, *arity),
+        Node::Internal(o) => Head::Operator(o),
+    };
+    Term::Internal(head, (0..token.arity()).map(|_| term(tokens, cursor)).collect())
+}
+
+fn parse<L: TreeNode, I: TreeNode>(tokens: &[Node<PatternLeaf<L>, I>]) -> Result<Term<'_, L, I>, UnificationError> {
+    validate_tokens(tokens).map_err(UnificationError::InvalidPattern)?;
+    if let Some(Node::Leaf(PatternLeaf::Sequence(name))) = tokens.iter().find(|token| matches!(token, Node::Leaf(PatternLeaf::Sequence(_)))) {
+        return Err(UnificationError::SequenceWildcard(name.clone()));
+    }
+    Ok(term(tokens, &mut 0))
+}
+
+impl<I: TreeNode + Clone> Head<'_, I> {
+
+    fn label(&self) -> String {
+        match self {
+            Head::Operator(o) => o.to_string(),
+            Head::Wildcard(name, _) => name.to_string(),
+        }
+    }
+
+    fn token<L: TreeNode>(&self) -> Node<PatternLeaf<L>, I> {
+        match self {
+            Head::Operator(o) => Node::Internal((*o).clone()),
+            Head::Wildcard(name, arity) => Node::Leaf(PatternLeaf::Operator(name.to_string(), *arity)),
+        }
+    }
+}
+
+impl<'t, 'p, L: TreeNode + Clone, I: TreeNode + Clone> Term<'p, L, I> {
+
+    fn label(&self) -> String {
+        match self {
+            Term::Wildcard(name) | Term::LeafWildcard(name) => name.to_string(),
+            Term::Literal(l) => l.to_string(),
+            Term::Internal(head, _) => head.label(),
+        }
+    }
+
+    /// Push the tokens of this term, with every bound wildcard replaced, in pre-order.
+    fn write(&'t self, bindings: &Bindings<'t, 'p, L, I>, tokens: &mut Pattern<L, I>) {
+        match self {
+            Term::Wildcard(name) => match bindings.term(name) {
+                Some(bound) => bound.write(bindings, tokens),
+                None => tokens.push(Node::Leaf(PatternLeaf::Subtree(name.to_string()))),
+            },
+            Term::LeafWildcard(name) => match bindings.term(name) {
+                Some(bound) => bound.write(bindings, tokens),
+                None => tokens.push(Node::Leaf(PatternLeaf::Leaf(name.to_string()))),
+            },
+            Term::Literal(l) => tokens.push(Node::Leaf(PatternLeaf::Literal((*l).clone()))),
+            Term::Internal(head, children) => {
+                tokens.push(bindings.walk_head(head).token());
+                children.iter().for_each(|child| child.write(bindings, tokens));
+            },
+        }
+    }
+}
+
+/// Wildcards bound so far, each to a term or operator from one of the patterns. Bindings may refer to other bound
+/// wildcards.
+struct Bindings<'t, 'p, L: TreeNode, I: TreeNode> {
+    /// Subtree and leaf wildcards
+    terms: Vec<(&'p str, &'t Term<'p, L, I>)>,
+    operators: Vec<(&'p str, &'t Head<'p, I>)>,
+}
+
+impl<'t, 'p, L: TreeNode, I: TreeNode> Bindings<'t, 'p, L, I> {
+
+    fn new() -> Self {
+        Self {
+            terms: vec![],
+            operators: vec![],
+        }
+    }
+
+    fn term(&self, name: &str) -> Option<&'t Term<'p, L, I>> {
+        self.terms.iter().find(|(bound, _)| *bound == name).map(|(_, term)| *term)
+    }
+
+    fn operator(&self, name: &str) -> Option<&'t Head<'p, I>> {
+        self.operators.iter().find(|(bound, _)| *bound == name).map(|(_, head)| *head)
+    }
+
+    /// Follow bound wildcards until reaching an unbound wildcard or a node.
+    fn walk(&self, mut term: &'t Term<'p, L, I>) -> &'t Term<'p, L, I> {
+        while let Term::Wildcard(name) | Term::LeafWildcard(name) = term {
+            match self.term(name) {
+                Some(bound) => term = bound,
+                None => break,
+            }
+        }
+        term
+    }
+
+    /// Follow bound operator wildcards until reaching an unbound one or an operator.
+    fn walk_head(&self, mut head: &'t Head<'p, I>) -> &'t Head<'p, I> {
+        while let Head::Wildcard(name, _) = head {
+            match self.operator(name) {
+                Some(bound) => head = bound,
+                None => break,
+            }
+        }
+        head
+    }
+
+    fn occurs(&self, name: &str, term: &'t Term<'p, L, I>) -> bool {
+        match self.walk(term) {
+            Term::Wildcard(other) | Term::LeafWildcard(other) => *other == name,
+            Term::Literal(_) => false,
+            Term::Internal(_, children) => children.iter().any(|child| self.occurs(name, child)),
+        }
+    }
+}
+
+fn unify_terms<'t, 'p, L: TreeNode + Clone, I: TreeNode + Clone>(
+        bindings: &mut Bindings<'t, 'p, L, I>,
+        a: &'t Term<'p, L, I>,
+        b: &'t Term<'p, L, I>,
+    ) -> Result<(), UnificationError> {
+
+    match (bindings.walk(a), bindings.walk(b)) {
+        (Term::Wildcard(x), Term::Wildcard(y)) | (Term::LeafWildcard(x), Term::LeafWildcard(y)) if x == y => Ok(()),
+        (Term::Wildcard(x), other) | (other, Term::Wildcard(x)) => {
+            if bindings.occurs(x, other) {
+                return Err(UnificationError::Occurs(x.to_string()));
+            }
+            bindings.terms.push((x, other));
+            Ok(())
+        },
+        // A leaf wildcard can only be bound to another leaf
+        (Term::LeafWildcard(x), other @ (Term::LeafWildcard(_) | Term::Literal(_))) | (other @ Term::Literal(_), Term::LeafWildcard(x)) => {
+            bindings.terms.push((x, other));
+            Ok(())
+        },
+        (Term::Literal(p), Term::Literal(q)) if p.uid() == q.uid() => Ok(()),
+        (Term::Internal(f, left), Term::Internal(g, right)) if left.len() == right.len() => {
+            unify_heads(bindings, f, g)?;
+            left.iter().zip(right.iter()).try_for_each(|(l, r)| unify_terms(bindings, l, r))
+        },
+        (left, right) => Err(UnificationError::Clash { left: left.label(), right: right.label() }),
+    }
+}
+
+fn unify_heads<'t, 'p, L: TreeNode, I: TreeNode + Clone>(
+        bindings: &mut Bindings<'t, 'p, L, I>,
+        f: &'t Head<'p, I>,
+        g: &'t Head<'p, I>,
+    ) -> Result<(), UnificationError> {
+
+    match (bindings.walk_head(f), bindings.walk_head(g)) {
+        (Head::Wildcard(x, _), Head::Wildcard(y, _)) if x == y => Ok(()),
+        (Head::Wildcard(x, _), other) | (other, Head::Wildcard(x, _)) => {
+            bindings.operators.push((x, other));
+            Ok(())
+        },
+        (Head::Operator(o), Head::Operator(p)) if o.to_string() == p.to_string() => Ok(()),
+        (f, g) => Err(UnificationError::Clash { left: f.label(), right: g.label() }),
+    }
+}
+
+fn resolve<L: TreeNode + Clone, I: TreeNode + Clone>(bindings: &Bindings<'_, '_, L, I>) -> Unifier<L, I> {
+    let terms = bindings.terms.iter().map(|(name, term)| {
+        let mut tokens = vec![];
+        term.write(bindings, &mut tokens);
+        (name.to_string(), tokens)
+    });
+    let operators = bindings.operators.iter().map(|(name, head)| (name.to_string(), vec![bindings.walk_head(head).token()]));
+
+    Unifier {
+        bindings: terms.chain(operators).collect(),
+    }
+}
+
+/// The most general unifier of two patterns: the least specific substitution which makes them identical.
+///
+/// Both patterns share the same wildcards, so rename one apart first if they're meant to be independent.
+pub fn unify<L: TreeNode + Clone, I: TreeNode + Clone, P: Tree<L=PatternLeaf<L>, I=I>>(a: &P, b: &P) -> Result<Unifier<L, I>, UnificationError> {
+    let (a, b) = (parse(a.tokens())?, parse(b.tokens())?);
+    let mut bindings = Bindings::new();
+    unify_terms(&mut bindings, &a, &b)?;
+    Ok(resolve(&bindings))
+}
+
+fn match_terms<'t, 'p, L: TreeNode + Clone, I: TreeNode + Clone>(
+        bindings: &mut Bindings<'t, 'p, L, I>,
+        general: &'t Term<'p, L, I>,
+        specific: &'t Term<'p, L, I>,
+    ) -> bool {
+
+    match (general, specific) {
+        (Term::Wildcard(x), _) | (Term::LeafWildcard(x), Term::LeafWildcard(_) | Term::Literal(_)) => match bindings.term(x) {
+            Some(bound) => same_term(bound, specific),
+            None => {
+                bindings.terms.push((x, specific));
+                true
+            },
+        },
+        (Term::Literal(p), Term::Literal(q)) => p.uid() == q.uid(),
+        (Term::Internal(f, left), Term::Internal(g, right)) if left.len() == right.len() => {
+            match_heads(bindings, f, g) && left.iter().zip(right.iter()).all(|(l, r)| match_terms(bindings, l, r))
+        },
+        _ => false,
+    }
+}
+
+fn match_heads<'t, 'p, L: TreeNode, I: TreeNode>(
+        bindings: &mut Bindings<'t, 'p, L, I>,
+        general: &'t Head<'p, I>,
+        specific: &'t Head<'p, I>,
+    ) -> bool {
+
+    match (general, specific) {
+        (Head::Wildcard(x, _), _) => match bindings.operator(x) {
+            Some(bound) => same_head(bound, specific),
+            None => {
+                bindings.operators.push((x, specific));
+                true
+            },
+        },
+        (Head::Operator(o), Head::Operator(p)) => o.to_string() == p.to_string(),
+        _ => false,
+    }
+}
+
+fn same_head<I: TreeNode>(a: &Head<I>, b: &Head<I>) -> bool {
+    match (a, b) {
+        (Head::Operator(o), Head::Operator(p)) => o.to_string() == p.to_string(),
+        (Head::Wildcard(x, _), Head::Wildcard(y, _)) => x == y,
+        _ => false,
+    }
+}
+
+fn same_term<L: TreeNode, I: TreeNode>(a: &Term<L, I>, b: &Term<L, I>) -> bool {
+    match (a, b) {
+        (Term::Wildcard(x), Term::Wildcard(y)) | (Term::LeafWildcard(x), Term::LeafWildcard(y)) => x == y,
+        (Term::Literal(p), Term::Literal(q)) => p.uid() == q.uid(),
+        (Term::Internal(f, left), Term::Internal(g, right)) => {
+            same_head(f, g) && left.len() == right.len() && left.iter().zip(right.iter()).all(|(l, r)| same_term(l, r))
+        },
+        _ => false,
+    }
+}
+
+/// The substitution which turns `general` into `specific`, if `specific` is a specialisation of it.
+///
+/// Only the wildcards of `general` are bound; those of `specific` are treated as opaque leaves.
+pub fn specialisation<L: TreeNode + Clone, I: TreeNode + Clone, P: Tree<L=PatternLeaf<L>, I=I>>(general: &P, specific: &P) -> Option<Unifier<L, I>> {
+    let (general, specific) = (parse(general.tokens()).ok()?, parse(specific.tokens()).ok()?);
+    let mut bindings = Bindings::new();
+    if !match_terms(&mut bindings, &general, &specific) {
+        return None;
+    }
+
+    // The wildcards of the specific pattern aren't bound, so bindings are already resolved
+    let unbound = Bindings::new();
+    let terms = bindings.terms.iter().map(|(name, term)| {
+        let mut tokens = vec![];
+        term.write(&unbound, &mut tokens);
+        (name.to_string(), tokens)
+    });
+    let operators = bindings.operators.iter().map(|(name, head)| (name.to_string(), vec![head.token()]));
+
+    Some(Unifier {
+        bindings: terms.chain(operators).collect(),
+    })
+}
+
+/// Rename every wildcard of `pattern` so that none clash with the wildcards of `other`, by priming them.
+pub fn rename_apart<L: TreeNode + Clone, I: TreeNode + Clone, P: Tree<L=PatternLeaf<L>, I=I>>(pattern: &P, other: &P) -> P {
+    let taken = |name: &str| other.tokens().iter().any(|token| matches!(token, Node::Leaf(leaf) if leaf.wildcard() == Some(name)));
+
+    P::new(pattern.tokens().iter().map(|token| {
+        let mut token = token.clone();
+        if let Node::Leaf(PatternLeaf::Subtree(name) | PatternLeaf::Leaf(name) | PatternLeaf::Operator(name, _)) = &mut token {
+            let mut renamed = format!("{name}'");
+            while taken(&renamed) {
+                renamed.push('\'');
+            }
+            *name = renamed;
+        }
+        token
+    }).collect())
+}
+
+/// Every way `b` overlaps a part of `a` which isn't just a wildcard: the locations in `a` where a subpattern unifies
+/// with `b`, after renaming the wildcards of `b` apart from those of `a`.
+///
+/// These are the overlaps from which critical pairs between two identities are found.
+pub fn overlaps<L: TreeNode + Clone, I: TreeNode + Clone, P: Tree<L=PatternLeaf<L>, I=I>>(a: &P, b: &P) -> Vec<(Location, Unifier<L, I>)> {
+    let renamed = rename_apart(b, a);
+    let (tokens, b) = match (validate_tokens(a.tokens()), parse(renamed.tokens())) {
+        (Ok(()), Ok(b)) => (a.tokens(), b),
+        _ => return vec![],
+    };
+
+    (0..tokens.len())
+        .filter(|index| !matches!(tokens[*index], Node::Leaf(PatternLeaf::Subtree(_) | PatternLeaf::Leaf(_))))
+        .filter_map(|index| {
+            let subpattern = term(tokens, &mut index.clone());
+            let mut bindings = Bindings::new();
+            unify_terms(&mut bindings, &subpattern, &b).ok()?;
+            Some((Location::of_index(tokens, index)?, resolve(&bindings)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{Tree, Node, PatternLeaf, Location};
+    use crate::test_utils::{Operator, Identity, wildcard, literal};
+
+    use super::{unify, specialisation, overlaps, rename_apart, UnificationError};
+
+    #[test]
+    fn test_most_general_unifier() {
+        // x * (a + y)
+        let left = Identity::new(vec![
+            Node::Internal(Operator::MULTIPLY), wildcard("x"),
+            Node::Internal(Operator::ADD), literal("a"), wildcard("y"),
+        ]);
+        // (b + z) * z
+        let right = Identity::new(vec![
+            Node::Internal(Operator::MULTIPLY),
+            Node::Internal(Operator::ADD), literal("b"), wildcard("z"),
+            wildcard("z"),
+        ]);
+
+        let unifier = unify(&left, &right).unwrap();
+        let unified = unifier.apply(&left);
+        assert_eq!(unified, unifier.apply(&right));
+        // (b + (a + y)) * (a + y)
+        assert_eq!(unified, Identity::new(vec![
+            Node::Internal(Operator::MULTIPLY),
+            Node::Internal(Operator::ADD), literal("b"), Node::Internal(Operator::ADD), literal("a"), wildcard("y"),
+            Node::Internal(Operator::ADD), literal("a"), wildcard("y"),
+        ]));
+        assert!(unifier.get("y").is_none());
+
+        let clash = Identity::new(vec![Node::Internal(Operator::ADD), wildcard("x"), wildcard("y")]);
+        assert_eq!(unify(&left, &clash).err(), Some(UnificationError::Clash { left: "*".to_string(), right: "+".to_string() }));
+
+        // x = x * y
+        let occurs = Identity::new(vec![Node::Internal(Operator::MULTIPLY), wildcard("x"), wildcard("y")]);
+        assert_eq!(unify(&Identity::new(vec![wildcard("x")]), &occurs).err(), Some(UnificationError::Occurs("x".to_string())));
+    }
+
+    #[test]
+    fn test_specialisation_and_overlaps() {
+        // x * (y + z)
+        let general = Identity::new(vec![
+            Node::Internal(Operator::MULTIPLY), wildcard("x"),
+            Node::Internal(Operator::ADD), wildcard("y"), wildcard("z"),
+        ]);
+        // a * (y + y)
+        let specific = Identity::new(vec![
+            Node::Internal(Operator::MULTIPLY), literal("a"),
+            Node::Internal(Operator::ADD), wildcard("y"), wildcard("y"),
+        ]);
+
+        let unifier = specialisation(&general, &specific).unwrap();
+        assert_eq!(unifier.apply(&general), specific);
+        assert_eq!(unifier.get("z"), Some(&[wildcard("y")][..]));
+        assert!(specialisation(&specific, &general).is_none());
+
+        // x + 0 overlaps the sum in x * (y + z), and the whole product doesn't unify with a sum
+        let identity = Identity::new(vec![Node::Internal(Operator::ADD), wildcard("x"), literal("0")]);
+        let found = overlaps(&general, &identity);
+        assert_eq!(found.len(), 1);
+        let (location, unifier) = &found[0];
+        assert_eq!(*location, Location::of_path(general.tokens(), &[1]).unwrap());
+        assert_eq!(unifier.get("z"), Some(&[literal("0")][..]));
+        assert_eq!(unifier.get("y"), Some(&[wildcard("x'")][..]));
+    }
+
+    #[test]
+    fn test_operator_and_leaf_wildcards() {
+        let operator = |name: &str| Node::Leaf(PatternLeaf::Operator(name.to_string(), 2));
+        let leaf_wildcard = |name: &str| Node::Leaf(PatternLeaf::Leaf(name.to_string()));
+
+        // x o l
+        let generic = Identity::new(vec![operator("o"), wildcard("x"), leaf_wildcard("l")]);
+        // (y * z) + m
+        let sum = Identity::new(vec![
+            Node::Internal(Operator::ADD),
+            Node::Internal(Operator::MULTIPLY), wildcard("y"), wildcard("z"),
+            leaf_wildcard("m"),
+        ]);
+
+        let unifier = unify(&generic, &sum).unwrap();
+        assert_eq!(unifier.apply(&generic), unifier.apply(&sum));
+        assert_eq!(unifier.get("o"), Some(&[Node::Internal(Operator::ADD)][..]));
+        assert!(specialisation(&generic, &sum).is_some());
+        assert!(specialisation(&sum, &generic).is_none());
+
+        // A leaf wildcard is more specific than a subtree wildcard, and can't stand for an internal node
+        let (x, l) = (Identity::new(vec![wildcard("x")]), Identity::new(vec![leaf_wildcard("l")]));
+        assert!(specialisation(&x, &l).is_some());
+        assert!(specialisation(&l, &x).is_none());
+        let product = Identity::new(vec![Node::Internal(Operator::MULTIPLY), wildcard("y"), wildcard("z")]);
+        assert_eq!(unify(&l, &product).err(), Some(UnificationError::Clash { left: "l".to_string(), right: "*".to_string() }));
+
+        // The same operator wildcard must stand for the same operator throughout
+        let nested = Identity::new(vec![operator("o"), wildcard("x"), operator("o"), wildcard("y"), wildcard("z")]);
+        let mixed = Identity::new(vec![
+            Node::Internal(Operator::ADD), wildcard("a"),
+            Node::Internal(Operator::MULTIPLY), wildcard("b"), wildcard("c"),
+        ]);
+        assert!(unify(&nested, &mixed).is_err());
+        assert_eq!(rename_apart(&nested, &nested).tokens()[0], operator("o'"));
+    }
+}
@@ -0,0 +1,175 @@
+use crate::location::Location;
+use crate::tree::{Node, Tree};
+
+/// A tree with a focus on one of its subtrees, which can be moved around the tree and edited in place.
+///
+/// Each move returns whether it was possible, and leaves the focus where it was if not.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Zipper<T: Tree> {
+    tree: T,
+    location: Location,
+}
+
+impl<T: Tree> Zipper<T> {
+
+    /// Focus on the root of `tree`.
+    pub fn new(tree: T) -> Self {
+        Self {
+            tree,
+            location: Location::root(),
+        }
+    }
+
+    /// Focus on the subtree of `tree` at `location`, or `None` if there is no such subtree.
+    pub fn at(tree: T, location: Location) -> Option<Self> {
+        match Location::of_index(tree.tokens(), location.index) {
+            Some(found) if found == location => Some(Self { tree, location }),
+            _ => None,
+        }
+    }
+
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
+
+    pub fn tree(&self) -> &T {
+        &self.tree
+    }
+
+    pub fn into_tree(self) -> T {
+        self.tree
+    }
+
+    /// The root token of the focused subtree.
+    pub fn node(&self) -> &Node<T::L, T::I> {
+        &self.tree.tokens()[self.location.index]
+    }
+
+    /// The tokens of the focused subtree.
+    pub fn tokens(&self) -> &[Node<T::L, T::I>] {
+        match self.tree.subtree_span(self.location.index) {
+            Some(span) => &self.tree.tokens()[span],
+            None => &[],
+        }
+    }
+
+    /// Move to the parent of the focus.
+    pub fn up(&mut self) -> bool {
+        match self.tree.parent(self.location.index) {
+            Some(parent) => {
+                self.location.index = parent;
+                self.location.path.pop();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Move to child `n` of the focus.
+    pub fn down(&mut self, n: usize) -> bool {
+        match self.tree.child(self.location.index, n) {
+            Some(child) => {
+                self.location.index = child;
+                self.location.path.push(n);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Move to the previous sibling of the focus.
+    pub fn left(&mut self) -> bool {
+        match self.location.path.last() {
+            Some(&n) if n > 0 => self.sibling(n - 1),
+            _ => false,
+        }
+    }
+
+    /// Move to the next sibling of the focus.
+    pub fn right(&mut self) -> bool {
+        match self.location.path.last() {
+            Some(&n) => self.sibling(n + 1),
+            None => false,
+        }
+    }
+
+    fn sibling(&mut self, n: usize) -> bool {
+        let sibling = self.tree.parent(self.location.index).and_then(|parent| self.tree.child(parent, n));
+        match (sibling, self.location.path.last_mut()) {
+            (Some(sibling), Some(last)) => {
+                self.location.index = sibling;
+                *last = n;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Replace the focused subtree with `replacement`, returning the subtree which was there. The focus stays on the
+    /// replacement.
+    ///
+    /// Returns `None`, leaving the tree as it is, if `replacement` isn't a single well formed tree, or the focus isn't
+    /// on a whole subtree because the tree itself wasn't.
+    pub fn replace(&mut self, replacement: T) -> Option<T> {
+        self.tree.replace_subtree(self.location.index, replacement)
+    }
+
+    /// A copy of the focused subtree.
+    pub fn subtree(&self) -> T where T::L: Clone, T::I: Clone {
+        T::new(self.tokens().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{Tree, Node, Location};
+    use crate::test_utils::{Equation, Operator, leaf};
+
+    use super::Zipper;
+
+    #[test]
+    fn test_moves_and_edits() {
+        // (a + if(b, c, inv(d))) * e
+        let tree = Equation::new(vec![
+            Node::Internal(Operator::MULTIPLY),
+            Node::Internal(Operator::ADD), leaf("a"),
+            Node::Internal(Operator::CONDITIONAL), leaf("b"), leaf("c"), Node::Internal(Operator::INVERSE), leaf("d"),
+            leaf("e"),
+        ]);
+
+        let mut zipper = Zipper::new(tree);
+        assert!(!zipper.up());
+        assert!(!zipper.left());
+        assert!(!zipper.right());
+
+        assert!(zipper.down(0));
+        assert!(zipper.down(1));
+        assert!(zipper.down(2));
+        assert_eq!(zipper.location(), &Location { index: 6, path: vec![0, 1, 2] });
+        assert_eq!(zipper.tokens(), &[Node::Internal(Operator::INVERSE), leaf("d")]);
+        assert!(!zipper.right());
+        assert!(!zipper.down(1));
+
+        assert!(zipper.left());
+        assert_eq!(zipper.node(), &leaf("c"));
+        assert!(zipper.up());
+        assert!(zipper.left());
+        assert_eq!(zipper.location(), &Location { index: 2, path: vec![0, 0] });
+
+        // Swap a for something longer, after which every later location shifts
+        let removed = zipper.replace(Equation::new(vec![Node::Internal(Operator::SUBTRACT), leaf("f"), leaf("g")]));
+        assert_eq!(removed, Some(Equation::new(vec![leaf("a")])));
+        assert!(zipper.up());
+        assert!(zipper.right());
+        assert_eq!(zipper.location(), &Location { index: 10, path: vec![1] });
+        assert_eq!(zipper.subtree(), Equation::new(vec![leaf("e")]));
+
+        let location = zipper.location().clone();
+        let tree = zipper.into_tree();
+        assert_eq!(tree.tokens().len(), 11);
+        assert!(tree.validate().is_ok());
+        assert_eq!(Zipper::at(tree.clone(), location.clone()).map(|zipper| zipper.node().clone()), Some(leaf("e")));
+        assert_eq!(Zipper::at(tree, Location { index: 10, path: vec![0] }), None);
+    }
+}
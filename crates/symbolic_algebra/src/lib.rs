@@ -1,3 +1,11 @@
 mod manipulation;
 mod simplification;
-mod structures;
\ No newline at end of file
+mod structures;
+
+pub use manipulation::Manipulatable;
+pub use simplification::{Simplifiable, simplify, simplify_with, simplify_within, simplify_breadth_first, simplify_best_first, expand, saturate, saturate_with};
+pub use simplification::{SearchConfig, SearchStatus, SearchOutcome, CancellationToken, Heuristic, EditDistanceTo};
+pub use structures::{Expression, Identity, Algebra, Element, BinaryOperator};
+
+#[cfg(test)]
+mod test_utils;
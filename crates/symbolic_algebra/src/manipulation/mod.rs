@@ -13,12 +13,26 @@ pub trait Manipulatable<'a> where Self: Sized + ToString {
     type Identity: ToString;
     type Instruction: Clone;
     type Error;
+    /// A lookup built once over a set of identities, for finding which of them might apply without trying each one.
+    type Index;
 
     fn manipulate(&self, instruction: &'a Self::Instruction) -> Result<Option<Self>, Self::Error>;
 
     fn try_manipulate(&self, identity: &'a Self::Identity) -> Result<Vec<(Self, Self::Instruction)>, &'static str>;
+
+    fn index(identities: &'a [Self::Identity]) -> Result<Self::Index, &'static str>;
+
+    /// The same as calling `try_manipulate` with each of the identities `index` was built from in turn, but skipping
+    /// those which can't apply. Only identities which apply somewhere are returned.
+    fn try_manipulate_indexed(&self, index: &Self::Index) -> Result<IndexedManipulations<'a, Self>, &'static str>;
 }
 
+/// The results of manipulating `T` with a single identity.
+pub type Manipulations<'a, T> = Vec<(T, <T as Manipulatable<'a>>::Instruction)>;
+
+/// The results of manipulating `T` with each identity which applied.
+pub type IndexedManipulations<'a, T> = Vec<(&'a <T as Manipulatable<'a>>::Identity, Manipulations<'a, T>)>;
+
 // impl Manipulatable for Expression {
 
 //     fn manipulate(&self, identity: &Identity, position: usize) -> Result<Self, &'static str> {
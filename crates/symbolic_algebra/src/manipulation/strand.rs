@@ -1,6 +1,6 @@
 use std::rc::Rc;
 
-use super::{step::Step, Manipulatable, Manipulation};
+use super::{step::Step, Manipulatable, Manipulation, IndexedManipulations};
 
 pub struct Strand<'a, T: Manipulatable<'a>> {
     pub step: Rc<Step<'a, T>>,
@@ -20,6 +20,7 @@ impl<'a, T: 'a> Manipulatable<'a> for Strand<'a, T> where T: Manipulatable<'a> {
     type Instruction = T::Instruction;
     type Identity = T::Identity;
     type Error = T::Error;
+    type Index = T::Index;
 
     fn manipulate(&self, instruction: &'a Self::Instruction) -> Result<Option<Self>, Self::Error> {
         let maybe_new_current = self.current.manipulate(instruction)?;
@@ -43,16 +44,37 @@ impl<'a, T: 'a> Manipulatable<'a> for Strand<'a, T> where T: Manipulatable<'a> {
     fn try_manipulate(&self, identity: &'a Self::Identity) -> Result<Vec<(Self, Self::Instruction)>, &'static str> {
         let new_currents = self.current.try_manipulate(identity)?;
 
-        Ok(new_currents.into_iter().map(|(new_current, instruction)|{
-            let new_step = Step::Manipulation(Manipulation {
-                parent: self.step.clone(),
-                instruction: instruction.clone(),
-            });
+        Ok(new_currents.into_iter().map(|(new_current, instruction)| self.extend(new_current, instruction)).collect())
+    }
+
+    fn index(identities: &'a [Self::Identity]) -> Result<Self::Index, &'static str> {
+        T::index(identities)
+    }
 
-            (Self {
-                step: Rc::new(new_step),
-                current: new_current,
-            }, instruction)
+    fn try_manipulate_indexed(&self, index: &Self::Index) -> Result<IndexedManipulations<'a, Self>, &'static str> {
+        let new_currents = self.current.try_manipulate_indexed(index)?;
+
+        Ok(new_currents.into_iter().map(|(identity, new_currents)| {
+            (
+                identity,
+                new_currents.into_iter().map(|(new_current, instruction)| self.extend(new_current, instruction)).collect(),
+            )
         }).collect())
     }
+}
+
+impl<'a, T: 'a> Strand<'a, T> where T: Manipulatable<'a> {
+
+    /// The strand continuing on from this one to `new_current`, by way of `instruction`.
+    fn extend(&self, new_current: T, instruction: T::Instruction) -> (Self, T::Instruction) {
+        let new_step = Step::Manipulation(Manipulation {
+            parent: self.step.clone(),
+            instruction: instruction.clone(),
+        });
+
+        (Self {
+            step: Rc::new(new_step),
+            current: new_current,
+        }, instruction)
+    }
 }
\ No newline at end of file
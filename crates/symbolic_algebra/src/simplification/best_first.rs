@@ -0,0 +1,93 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use solar_bt::{edit_distance, CostModel, Tree};
+
+use super::Simplifiable;
+use super::budget::{Budget, SearchConfig, SearchOutcome, SearchStatus};
+use super::history::History;
+
+/// An estimate of how far an item is from what a search is looking for, where lower is closer.
+///
+/// Any `Fn(&T) -> usize` is a heuristic which never recognises a goal.
+pub trait Heuristic<T> {
+
+    fn estimate(&self, item: &T) -> usize;
+
+    /// Whether `item` is exactly what the search is looking for, in which case the search stops there.
+    fn is_goal(&self, _item: &T) -> bool {
+        false
+    }
+}
+
+impl<T, F> Heuristic<T> for F where F: Fn(&T) -> usize {
+    fn estimate(&self, item: &T) -> usize {
+        self(item)
+    }
+}
+
+/// Steer a search towards a known target, by the tree edit distance from it.
+pub struct EditDistanceTo<T>(pub T);
+
+impl<T: Tree> Heuristic<T> for EditDistanceTo<T> {
+    fn estimate(&self, item: &T) -> usize {
+        edit_distance(item, &self.0).unwrap_or(usize::MAX)
+    }
+
+    fn is_goal(&self, item: &T) -> bool {
+        self.estimate(item) == 0
+    }
+}
+
+/// Simplify `item` with a best first search, which always expands the state with the lowest cost by `model` plus
+/// `heuristic` estimate next, breaking ties by the fewest steps taken to reach it.
+///
+/// The search stops once the heuristic recognises a goal, which is returned, or once `config` runs out or there is
+/// nothing left to expand, when the cheapest state found is returned.
+pub fn simplify_best_first<'a, T, H, C>(item: T, identities: &'a [T::Identity], heuristic: &H, model: &C, config: &SearchConfig) -> Result<SearchOutcome<T>, &'static str>
+    where T: 'a + Simplifiable<'a> + Clone, H: Heuristic<T>, C: CostModel<T::Leaf, T::Operator> {
+
+    let index = T::index(identities)?;
+    let mut history = History::default();
+    history.visit(&item)?;
+    let mut budget = Budget::new(config);
+
+    // Every state found, and the queue of those still to expand as (priority, steps, state), earliest found first
+    let mut queue = BinaryHeap::from([Reverse((model.cost(item.nodes()).saturating_add(heuristic.estimate(&item)), 0, 0))]);
+    let mut states = vec![item];
+    let mut simplest = 0;
+
+    while let Some(Reverse((_, steps, state))) = queue.pop() {
+        if heuristic.is_goal(&states[state]) {
+            return Ok(SearchOutcome {
+                best: states.swap_remove(state),
+                status: SearchStatus::Complete,
+            });
+        }
+        if !budget.explore(steps) {
+            // Too deep a state only stops that state, but anything else stops the search
+            match budget.status() {
+                SearchStatus::DepthLimit => continue,
+                _ => break,
+            }
+        }
+
+        for (_, manipulated) in states[state].try_manipulate_indexed(&index)? {
+            for (next, _) in manipulated {
+                if !history.visit(&next)? {
+                    continue
+                }
+                if model.compare(next.nodes(), states[simplest].nodes()).is_lt() {
+                    simplest = states.len();
+                }
+                queue.push(Reverse((model.cost(next.nodes()).saturating_add(heuristic.estimate(&next)), steps + 1, states.len())));
+                states.push(next);
+            }
+        }
+    }
+
+    Ok(SearchOutcome {
+        best: states.swap_remove(simplest),
+        status: budget.status(),
+    })
+}
@@ -0,0 +1,126 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+/// Limits on how much work a search may do, after which it returns the best it has found so far.
+#[derive(Clone, Debug)]
+pub struct SearchConfig {
+    /// The most states the search may expand
+    pub max_states: usize,
+    /// The most rewrites the search may apply one after another
+    pub max_depth: usize,
+    /// The number of steps a strand may take without improving on the best it has found before it is abandoned
+    pub max_strand_depth: usize,
+    /// When to give up, if ever
+    pub deadline: Option<Instant>,
+    /// Stops the search once cancelled, from any thread
+    pub cancellation: Option<CancellationToken>,
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            max_states: 100_000,
+            max_depth: 200,
+            max_strand_depth: 3,
+            deadline: None,
+            cancellation: None,
+        }
+    }
+}
+
+/// A flag for stopping a search early. Clones share the flag, so one can be kept to cancel a search which was given
+/// another.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Why a search stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchStatus {
+    /// Every strand was followed until it could go no further, so the result is a local optimum
+    Complete,
+    StateLimit,
+    DepthLimit,
+    Deadline,
+    Cancelled,
+}
+
+/// The best item a search found, and whether it can be sure there is nothing better nearby.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SearchOutcome<T> {
+    pub best: T,
+    pub status: SearchStatus,
+}
+
+impl<T> SearchOutcome<T> {
+
+    /// Whether the search finished without running out of budget, rather than stopping at the best so far.
+    pub fn is_optimal(&self) -> bool {
+        self.status == SearchStatus::Complete
+    }
+}
+
+/// The work a search has done so far against its [`SearchConfig`].
+pub struct Budget<'c> {
+    config: &'c SearchConfig,
+    states: usize,
+    /// Why some state wasn't expanded, if one wasn't
+    truncated: Option<SearchStatus>,
+}
+
+impl<'c> Budget<'c> {
+
+    pub fn new(config: &'c SearchConfig) -> Self {
+        Self {
+            config,
+            states: 0,
+            truncated: None,
+        }
+    }
+
+    /// Whether a state `depth` rewrites from the start may be expanded, counting it if so. Once the states, the time or
+    /// the search have run out nothing more may be expanded, but running out of depth only stops the strand at hand.
+    pub fn explore(&mut self, depth: usize) -> bool {
+        if matches!(self.truncated, Some(status) if status != SearchStatus::DepthLimit) {
+            return false
+        }
+
+        let stopped = if self.config.cancellation.as_ref().is_some_and(CancellationToken::is_cancelled) {
+            Some(SearchStatus::Cancelled)
+        } else if self.config.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            Some(SearchStatus::Deadline)
+        } else if self.states >= self.config.max_states {
+            Some(SearchStatus::StateLimit)
+        } else if depth >= self.config.max_depth {
+            Some(SearchStatus::DepthLimit)
+        } else {
+            None
+        };
+
+        if stopped.is_some() {
+            self.truncated = stopped;
+            return false
+        }
+        self.states += 1;
+        true
+    }
+
+    pub fn status(&self) -> SearchStatus {
+        self.truncated.unwrap_or(SearchStatus::Complete)
+    }
+}
@@ -1,37 +1,26 @@
-use crate::algo::{structures::{Expression, Identity}};
-use super::regina::Span;
-
-pub fn expand(expression: &Expression, algebra: &Vec<Identity>) -> Result<Expression, &'static str> {
-
-    let span = Span::init(expression, &algebra, false);
+use solar_bt::CostModel;
 
-    let result = span.saturate().simplest();
+use super::{Polarity, Simplifiable};
+use super::budget::{SearchConfig, SearchOutcome};
+use super::regina::Span;
 
-    if result.len() == 0 {
-        return Err("")
-    }else{
-        Ok(
-            result[0].current.clone()
-        )
-    }
+/// Expand `item` with a breadth first search, as [`super::simplify_breadth_first`] does but after the most costly form
+/// by `model` rather than the cheapest.
+///
+/// Only identities which can make an item more costly are of any use here, such as distributivity applied left to right.
+/// The search stops once `config` runs out, returning the most expanded item found so far.
+pub fn expand<'a, T, C>(item: T, identities: &'a [T::Identity], beam: usize, model: &C, config: &SearchConfig) -> Result<SearchOutcome<T>, &'static str>
+    where T: 'a + Simplifiable<'a> + Clone, C: CostModel<T::Leaf, T::Operator> {
+
+    let index = T::index(identities)?;
+    let span = Span::init(item, &index, model, Polarity::Expand, beam, config)?
+        .saturate()?
+        .simplest()
+        .trim();
+
+    // Strands are only ever replaced by the strands they propagate to, so there is always at least one
+    Ok(SearchOutcome {
+        best: span[0].current.clone(),
+        status: span.status(),
+    })
 }
-
-#[cfg(test)]
-mod tests {
-
-    use crate::algo::{
-        structures::{Expression, algebras::associative_commutative_algebra},
-        regex::Regexable,
-        display::Display,
-    };
-
-    use super::expand;
-
-    #[test]
-    fn test_expand_1() {
-        let simple: Expression = Expression::from_regex(r"*(+(a)(b))(+(c)(d))").unwrap();
-
-        let expanded = expand(&simple, &associative_commutative_algebra()).unwrap();
-        println!("{}", expanded.format())
-    }
-}
\ No newline at end of file
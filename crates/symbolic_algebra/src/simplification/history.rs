@@ -0,0 +1,21 @@
+use std::collections::HashSet;
+
+use solar_bt::{HashCons, TermId};
+
+use super::Simplifiable;
+
+/// The states a search has already visited, interned so that checking for a repeat is constant time.
+#[derive(Default)]
+pub struct History {
+    terms: HashCons,
+    visited: HashSet<TermId>,
+}
+
+impl History {
+
+    /// Record a visit to `state`, returning whether it hadn't been visited before.
+    pub fn visit<'a, T: Simplifiable<'a>>(&mut self, state: &T) -> Result<bool, &'static str> {
+        let id = state.term_id(&mut self.terms)?;
+        Ok(self.visited.insert(id))
+    }
+}
@@ -1,34 +1,71 @@
-// mod regina {
-//     mod span;
-//     mod strand;
-//     mod step;
-//     mod history;
+mod regina {
+    mod span;
+    mod strand;
 
-//     pub use span::Span;
-// }
+    pub use span::Span;
+}
 
 mod simplify;
+mod saturate;
 
 mod winston;
+mod best_first;
+mod history;
+mod expand;
+mod budget;
+
+use solar_bt::{HashCons, Node, TermId, TreeNode};
 
 use crate::manipulation::{Manipulatable, Strand};
 
-pub use simplify::simplify;
+pub use simplify::{simplify, simplify_with, simplify_within, simplify_breadth_first};
+pub use saturate::{saturate, saturate_with};
+pub use expand::expand;
+pub use budget::{SearchConfig, SearchStatus, SearchOutcome, CancellationToken};
+pub use best_first::{simplify_best_first, Heuristic, EditDistanceTo};
+
+/// Whether a search is after simpler or more complex forms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    Simplify,
+    Expand,
+}
+
+impl Polarity {
+
+    /// Whether an item costing `a` is an improvement on one costing `b`.
+    pub fn prefers(&self, a: usize, b: usize) -> bool {
+        match self {
+            Polarity::Simplify => a < b,
+            Polarity::Expand => a > b,
+        }
+    }
+}
 
 pub trait Simplifiable<'a> where Self: Manipulatable<'a> {
 
-    fn simplicity(&self) -> usize;
-    fn uuid(&self) -> Vec<u8>;
+    type Leaf: TreeNode;
+    type Operator: TreeNode;
+
+    /// The tokens a cost model scores this item by.
+    fn nodes(&self) -> &[Node<Self::Leaf, Self::Operator>];
+    /// The id of this item in `terms`, which is shared by exactly those items which are structurally equal.
+    ///
+    /// Fails if the item can't be interned, such as an expression which isn't a well formed tree.
+    fn term_id(&self, terms: &mut HashCons) -> Result<TermId, &'static str>;
 }
 
 impl<'a, T: 'a> Simplifiable<'a> for Strand<'a, T> where T: Simplifiable<'a>, T::Identity: Into<String> {
 
-    fn simplicity(&self) -> usize {
-        self.current.simplicity()
+    type Leaf = T::Leaf;
+    type Operator = T::Operator;
+
+    fn nodes(&self) -> &[Node<Self::Leaf, Self::Operator>] {
+        self.current.nodes()
     }
 
-    fn uuid(&self) -> Vec<u8> {
-        self.current.uuid()
+    fn term_id(&self, terms: &mut HashCons) -> Result<TermId, &'static str> {
+        self.current.term_id(terms)
     }
 
 }
\ No newline at end of file
@@ -1,170 +1,202 @@
-use crate::algo::structures::{Expression, Identity};
-use super::history::History;
+use solar_bt::CostModel;
+
+use crate::simplification::{Polarity, Simplifiable};
+use crate::simplification::budget::{Budget, SearchConfig, SearchStatus};
+use crate::simplification::history::History;
 use super::strand::Strand;
 
-/// A struct to hold a shared reference to all the data needed for a simplification or expansion.
-pub struct Span<'a, 'b> {
-    strands: Vec<Strand<'a>>,
-    algebra: &'b Vec<Identity>,
-    simplify: bool,
+/// A breadth first search, which follows many strands at once and keeps only the `beam` best of them by `model` at each
+/// depth, so that no single long strand can hold up the search.
+pub struct Span<'i, T, I, C> {
+    strands: Vec<Strand<T>>,
+    index: &'i I,
+    model: &'i C,
+    polarity: Polarity,
+    beam: usize,
     history: History,
+    budget: Budget<'i>,
+    /// The number of times the strands have been propagated
+    depth: usize,
 }
 
-impl<'a, 'b> Span<'a, 'b> where 'b: 'a {
-
-    pub fn init(expression: &'a Expression, algebra: &'b Vec<Identity>, simplify: bool) -> Span<'a, 'b> {
-        Span {
-            strands: vec![
-                Strand::init(expression, simplify),
-            ],
-            algebra,
-            simplify,
-            history: History::new(),
-        }
+impl<'a, 'i, T: 'a, I, C> Span<'i, T, I, C> where T: Simplifiable<'a, Index = I> + Clone, C: CostModel<T::Leaf, T::Operator> {
+
+    pub fn init(item: T, index: &'i I, model: &'i C, polarity: Polarity, beam: usize, config: &'i SearchConfig) -> Result<Self, &'static str> {
+        let mut history = History::default();
+        history.visit(&item)?;
+
+        Ok(Self {
+            strands: vec![Strand::init(item, model, config.max_strand_depth)],
+            index,
+            model,
+            polarity,
+            beam: beam.max(1),
+            history,
+            budget: Budget::new(config),
+            depth: 0,
+        })
     }
 
-    pub fn len(&self) -> usize {
-        self.strands.len()
+    /// Why the search stopped, or would stop if it were saturated now.
+    pub fn status(&self) -> SearchStatus {
+        self.budget.status()
     }
 
-    pub fn saturate(mut self) -> Self {
-        // TODO: Should always go down the simplest route at any given time
-        let mut last_simp = self.strands[0].simplicity;
-        loop {
-            let history_len = self.history.len();
+    /// Propagate the strands a depth at a time, until none of them can go any further or the budget runs out.
+    pub fn saturate(mut self) -> Result<Self, &'static str> {
+        while self.iterate()? {}
+        Ok(self)
+    }
 
-            let mut position = 0;
-            let mut min_simpl = self.strands[0].simplicity;
+    /// Propagate every unfinished strand once, as far as the budget allows, then keep the `beam` strands which have found
+    /// the best items, breaking ties by where they are now. Returns whether any strand moved on.
+    fn iterate(&mut self) -> Result<bool, &'static str> {
+        let mut advanced = false;
 
-            for (i, strand) in self.strands.iter().enumerate() {
-                if (strand.simplicity < min_simpl) == self.simplify {
-                    min_simpl = strand.simplicity;
-                    position = i;
-                }
+        for mut strand in std::mem::take(&mut self.strands) {
+            if strand.finished || !self.budget.explore(self.depth) {
+                self.strands.push(strand);
+                continue
             }
 
-            let simplest = self.strands.remove(position);
-            self.strands.append(&mut simplest.propagate(&self.algebra, &mut self.history));
-            
-            if (min_simpl >= last_simp) == self.simplify {
-                break
-            }else{
-                last_simp = min_simpl;
+            let propagated = strand.propagate(self.index, self.model, self.polarity, &mut self.history)?;
+            if propagated.is_empty() {
+                strand.finished = true;
+                self.strands.push(strand);
+            } else {
+                advanced = true;
+                self.strands.extend(propagated);
             }
+        }
 
-            // if self.history.len() == history_len {
-            //     break;
-            // }
-            println!("Strands: {}", self.strands.len());
-        };
-        self
-    }
-
-    fn iterate(mut self) -> Self {
-        let old_strands = Self::pop_strands(&mut self.strands);
-        for strand in old_strands {
-            let mut propagated = strand.propagate(&self.algebra, &mut self.history);
-            self.strands.append(&mut propagated);
-        };
-        self
-    }
-
-    /// Re-initialize strands to an empty vec and return all the old strands
-    fn pop_strands(strands: &mut Vec<Strand<'a>>) -> Vec<Strand<'a>> {
-        let current_number_of_strands = strands.len();
-        // Todo: Optimise this capacity for average number of strands propagated
-        std::mem::replace(strands, Vec::with_capacity(current_number_of_strands))
+        let (model, polarity) = (self.model, self.polarity);
+        self.strands.sort_by(|a, b| match polarity {
+            Polarity::Simplify => a.cost.cmp(&b.cost).then_with(|| model.compare(a.current.nodes(), b.current.nodes())),
+            Polarity::Expand => b.cost.cmp(&a.cost).then_with(|| model.compare(b.current.nodes(), a.current.nodes())),
+        });
+        self.strands.truncate(self.beam);
+        self.depth += 1;
+        Ok(advanced)
     }
 
+    /// Keep only the strands which reached the best cost of any strand.
     pub fn simplest(mut self) -> Self {
-        let capacity = self.strands.len();
-        let old_strands = std::mem::replace(&mut self.strands, Vec::with_capacity(capacity));
-        
-        let filtered_strands = old_strands.into_iter().fold(vec![], |mut accum: Vec<Strand>, strand| {
-            if accum.len() == 0 || self.simplify == (strand.simplicity < accum[0].simplicity) {
-                return vec![strand];
-            }else if strand.simplicity == accum[0].simplicity {
-                accum.push(strand);
-                return accum;
-            }else{
-                return accum;
-            }
-        });
+        let polarity = self.polarity;
+        let best = self.strands.iter()
+            .map(|strand| strand.cost)
+            .reduce(|best, cost| if polarity.prefers(cost, best) { cost } else { best });
 
-        self.strands = filtered_strands;
+        self.strands.retain(|strand| Some(strand.cost) == best);
         self
     }
 
+    /// Rewind every strand to the simplest item along it.
     pub fn trim(mut self) -> Self {
-        let capacity = self.strands.len();
-        let old_strands = std::mem::replace(&mut self.strands, Vec::with_capacity(capacity));
-        for old_strand in old_strands {
-            self.strands.push(old_strand.trim())
-        }
+        self.strands = self.strands.into_iter().map(Strand::trim).collect();
         self
     }
-
 }
 
-impl<'a, 'b, Idx> std::ops::Index<Idx> for Span<'a, 'b> where Idx: std::slice::SliceIndex<[Strand<'a>]> {
+impl<'i, T, I, C, Idx> std::ops::Index<Idx> for Span<'i, T, I, C> where Idx: std::slice::SliceIndex<[Strand<T>]> {
     type Output = Idx::Output;
 
     fn index(&self, index: Idx) -> &Self::Output {
-        &self.strands.index(index)
+        self.strands.index(index)
     }
 }
 
-
 #[cfg(test)]
 mod tests {
 
+    use solar_bt::{CostModel, Node, NodeCount};
+
     use crate::expression;
-    use super::super::{strand::Strand, history::History};
+    use crate::manipulation::Manipulatable;
+    use crate::simplification::{Polarity, SearchConfig};
+    use crate::simplification::history::History;
+    use crate::structures::{Expression, BinaryOperator, Element};
+    use crate::test_utils::commutativity_multiplication;
+    use super::super::strand::Strand;
     use super::Span;
 
+    /// Weighs the left argument of each operator double, so that commuting changes the cost.
+    struct LeftHeavy;
+
+    impl CostModel<Element, BinaryOperator> for LeftHeavy {
+        fn node_cost(&self, _node: &Node<Element, BinaryOperator>, children: &[usize]) -> usize {
+            match children {
+                [left, right] => 1 + 2 * left + right,
+                _ => 1,
+            }
+        }
+    }
+
     #[test]
     fn test_simplest() {
-        let expression = expression!(
-            BinaryOperator {
-                label: b'+'
-            },
-            BinaryOperator {
-                label: b'*'
-            },
-            Element {
-                label: b"a".to_vec()
-            },
-            Element {
-                label: b"b".to_vec()
-            },
-            Element {
-                label: b"c".to_vec()
-            },
-        );
-        let simpler_expression = expression!(
-            BinaryOperator {
-                label: b'+'
-            },
-            Element {
-                label: b"a".to_vec()
-            },
-            Element {
-                label: b"b".to_vec()
-            },
-        );
-
-        let span = Span {
-            strands: vec![
-                Strand::init(&expression, true),
-                Strand::init(&simpler_expression, true)
-            ],
-            algebra: &vec![],
-            simplify: true,
-            history: History::new(),
-        };
+        let plus = BinaryOperator::new(b'+');
+        let times = BinaryOperator::new(b'*');
+        let (a, b, c) = (Element::new(b"a"), Element::new(b"b"), Element::new(b"c"));
+
+        let expression = expression![plus, times, a.clone(), b.clone(), c];
+        let simpler_expression = expression![plus, a, b];
+
+        let index = Expression::index(&[]).unwrap();
+        let config = SearchConfig::default();
+        let mut span = Span::init(expression.clone(), &index, &NodeCount, Polarity::Simplify, 2, &config).unwrap();
+        span.strands.push(Strand::init(simpler_expression.clone(), &NodeCount, config.max_strand_depth));
 
         let simplest = span.simplest();
-        assert_eq!(simplest.len(), 1);
+        assert_eq!(simplest.strands.len(), 1);
         assert_eq!(simplest[0].current, simpler_expression);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_strand_depth_is_configurable() {
+        let times = BinaryOperator::new(b'*');
+        let (a, b, c) = (Element::new(b"a"), Element::new(b"b"), Element::new(b"c"));
+        let identities = [commutativity_multiplication()];
+        let index = Expression::index(&identities).unwrap();
+
+        // Every way of commuting a(a(bc)) costs at least as much as it does
+        let expression = expression![times, a.clone(), times, a, times, b, c];
+
+        let strand = Strand::init(expression.clone(), &LeftHeavy, 3);
+        let propagated = strand.propagate(&index, &LeftHeavy, Polarity::Simplify, &mut History::default()).unwrap();
+        assert_eq!(propagated.len(), 3);
+
+        // A strand which may take only one step must improve with it
+        let strand = Strand::init(expression, &LeftHeavy, 1);
+        let propagated = strand.propagate(&index, &LeftHeavy, Polarity::Simplify, &mut History::default()).unwrap();
+        assert!(propagated.is_empty());
+    }
+
+    #[test]
+    fn test_beam_keeps_strands_which_found_the_best() {
+        let times = BinaryOperator::new(b'*');
+        let (a, b, c, d) = (Element::new(b"a"), Element::new(b"b"), Element::new(b"c"), Element::new(b"d"));
+        let identities = [commutativity_multiplication()];
+        let index = Expression::index(&identities).unwrap();
+
+        // a(a(bc)) costs 10, but commuting it at the root costs 16
+        let expression = expression![times, a.clone(), times, a.clone(), times, b.clone(), c.clone()];
+        let commuted = expression![times, times, a.clone(), times, b.clone(), c.clone(), a.clone()];
+        let wandered = Strand::init(expression, &LeftHeavy, 3)
+            .propagate(&index, &LeftHeavy, Polarity::Simplify, &mut History::default())
+            .unwrap()
+            .into_iter()
+            .find(|strand| strand.current == commuted)
+            .unwrap();
+        assert_eq!(wandered.cost, 10);
+
+        // (ab)(cd) costs 13, which is less than where the other strand is but more than the best it has found
+        let other = expression![times, times, a, b, times, c, d];
+        let empty = Expression::index(&[]).unwrap();
+        let config = SearchConfig::default();
+        let mut span = Span::init(other.clone(), &empty, &LeftHeavy, Polarity::Simplify, 1, &config).unwrap();
+        span.strands.push(wandered);
+
+        span.iterate().unwrap();
+        assert_eq!(span.strands.len(), 1);
+        assert_eq!(span[0].current, commuted);
+    }
+}
@@ -1,101 +1,94 @@
+use std::collections::VecDeque;
 use std::rc::Rc;
-use super::step::{Simplification, Step};
-use super::history::History;
 
-use crate::manipulation::{Strand, Manipulatable};
+use solar_bt::CostModel;
 
+use crate::simplification::{Polarity, Simplifiable};
+use crate::simplification::history::History;
 
+/// A single line of search through a [`Span`](super::Span): the item it has reached, and the best item it passed
+/// through on the way.
+pub struct Strand<T> {
+    pub current: T,
+    /// The best item along the strand, which `trim` rewinds to
+    simplest: Rc<T>,
+    /// The cost of `simplest`
+    pub cost: usize,
+    /// The costs of the last few items along the strand, oldest first
+    recent: VecDeque<usize>,
+    /// The number of steps the strand may take without improving on where it was before it is abandoned
+    max_depth: usize,
+    /// Whether propagating the strand found nothing new, after which it never will
+    pub finished: bool,
+}
 
-/// Given a set of identities, I, generate a new set of strands, each formed by applying a single identity from the set I to the current strand.
-/// 
-/// # Arguments
-/// 
-/// * `identities` - An algebra to use for the propagation
-/// * `History` - A string encoded list of previously found expressions to exclude (to prevent infinite loops)
-/// 
-/// # Returns
-/// 
-/// `Vec<Strand>` - A list of new strands.
-pub fn propagate_strand<'a, 'c, T: Manipulatable>(strand: Strand<'a, T>, identities: &[&T::Instruction], history: &mut History) -> Vec<Strand<'a, T>>{
-    let mut new_strands = vec![];
-    for (_, identity) in identities.iter().enumerate() {
-        let new_trees = replace_identity(&self.current, &identity).unwrap();
-        for (tree, position) in new_trees {
-            if !self.is_valid_with(&tree) {
-            }else{
-                println!("Valid");
-                println!("{}", tree.simplicity());
-            }
-            if history.is_new_state(&tree) && self.is_valid_with(&tree){
-                let new_step = Simplification {
-                    identity: &identity,
-                    direction: true,
-                    position,
-                    simplicity: tree.simplicity(),
-                    parent: Rc::clone(&self.step),
-                };
-                let simplicity = match tree.simplicity() {
-                    x if self.simplify == (x <= self.current.simplicity()) => x,
-                    _ => tree.simplicity(),
-                };
-                new_strands.push(Strand {
-                    step: Rc::new(Step::Simplification(new_step)),
-                    current: tree,
-                    simplify: self.simplify,
-                    simplicity,
-                })
-            } // Else simply drop the strand by doing nothing
+impl<'a, T: 'a> Strand<T> where T: Simplifiable<'a> + Clone {
+
+    pub fn init<C: CostModel<T::Leaf, T::Operator>>(item: T, model: &C, max_depth: usize) -> Self {
+        let cost = model.cost(item.nodes());
+        Self {
+            simplest: Rc::new(item.clone()),
+            current: item,
+            cost,
+            recent: VecDeque::from([cost]),
+            max_depth: max_depth.max(1),
+            finished: false,
         }
-    };
-    if new_strands.len() == 0 {
-        new_strands.push(self)
     }
-    return new_strands;
-}
 
-fn is_valid_with(&self, expression: &Expression) -> bool {
-    match &*self.step {
-        Step::Simplification(simp) => {
-            match simp.backtrack(MAX_DEPTH) {
-                Some(step) => {
-                    match &*step {
-                        Step::Simplification(back_simp) => {
-                            return (expression.simplicity() < back_simp.simplicity) == self.simplify
-                        },
-                        Step::Root(root) => {
-                            return (expression.simplicity() < root.tree.simplicity()) == self.simplify
-                        }
-                    };
-                },
-                None => {
-                    return true;
-                },
-            };
-        },
-        Step::Root(r) => {
-            return (expression.simplicity() < r.tree.simplicity()) == self.simplify;
-        },
-    };
-}
+    /// Apply the identities of `index` to the current item, giving a new strand for each result which hasn't been
+    /// visited before and keeps the strand heading in the direction of `polarity`.
+    pub fn propagate<C>(&self, index: &T::Index, model: &C, polarity: Polarity, history: &mut History) -> Result<Vec<Self>, &'static str>
+        where C: CostModel<T::Leaf, T::Operator> {
 
-pub fn trim(mut self) -> Self {
-    let mut step = self.step;
-    loop {
-        match &*step {
-            Step::Simplification(s) => {
-                if s.simplicity == self.simplicity {
-                    self.step = step;
-                    return self;
-                }else{
-                    // Unsafe, but we know that we will always have a parent 1 element back
-                    step = Rc::clone(&s.parent);
-                    continue;
-                }
-            },
-            _ => {
-                self.step = step;
-                return self;
+        let manipulations = self.current.try_manipulate_indexed(index)?;
+
+        let mut propagated = vec![];
+        for (item, _) in manipulations.into_iter().flat_map(|(_, manipulated)| manipulated) {
+            let cost = model.cost(item.nodes());
+            if self.is_valid_with(cost, polarity) && history.visit(&item)? {
+                propagated.push(self.extend(item, cost, polarity));
             }
         }
+        Ok(propagated)
+    }
+
+    /// A strand may wander for up to `max_depth` steps, but must then improve on where it was that many steps before.
+    fn is_valid_with(&self, cost: usize, polarity: Polarity) -> bool {
+        self.recent.len() < self.max_depth || polarity.prefers(cost, self.recent[0])
+    }
+
+    fn extend(&self, item: T, cost: usize, polarity: Polarity) -> Self {
+        let mut recent = self.recent.clone();
+        if recent.len() == self.max_depth {
+            recent.pop_front();
+        }
+        recent.push_back(cost);
+
+        let (simplest, best) = match polarity.prefers(cost, self.cost) {
+            true => (Rc::new(item.clone()), cost),
+            false => (Rc::clone(&self.simplest), self.cost),
+        };
+
+        Self {
+            current: item,
+            simplest,
+            cost: best,
+            recent,
+            max_depth: self.max_depth,
+            finished: false,
+        }
     }
-}
\ No newline at end of file
+
+    /// Rewind the strand to the best item along it.
+    pub fn trim(self) -> Self {
+        Self {
+            current: T::clone(&self.simplest),
+            simplest: self.simplest,
+            cost: self.cost,
+            recent: VecDeque::from([self.cost]),
+            max_depth: self.max_depth,
+            finished: false,
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use solar_bt::{CostModel, EGraph, Limits, NodeCount, ReplacementError};
+
+use crate::structures::{BinaryOperator, Element, Expression, Identity};
+
+/// Simplify `expression` by equality saturation rather than by searching through rewrites one at a time.
+///
+/// Each identity is applied left to right, as in [`super::simplify`], but every rewritten form is kept, so
+/// commutativity and friends can't cause a blow up. Once the rewriting has saturated, or `limits` are hit, the
+/// simplest equivalent expression is returned.
+pub fn saturate(expression: &Expression, identities: &[Identity], limits: &Limits) -> Result<Expression, ReplacementError> {
+    saturate_with(expression, identities, limits, &NodeCount)
+}
+
+/// Saturate as [`saturate`] does, but extract the cheapest equivalent expression by `model`.
+pub fn saturate_with<C>(expression: &Expression, identities: &[Identity], limits: &Limits, model: &C) -> Result<Expression, ReplacementError>
+    where C: CostModel<Element, BinaryOperator> {
+    let rules = identities
+        .iter()
+        .map(|identity| identity.rule().cloned())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut egraph = EGraph::new();
+    let root = egraph.add_tree(expression).map_err(ReplacementError::InvalidTree)?;
+    egraph.run(&rules, limits)?;
+
+    // The original expression is always in its own class, so there is always something to extract
+    Ok(egraph.extract_with(root, model).map(|(expression, _)| expression).unwrap_or_else(|| expression.clone()))
+}
@@ -1,23 +1,73 @@
-use super::Simplifiable;
-use super::winston::propagate_;
+use solar_bt::{CostModel, NodeCount};
 
-pub fn simplify<'a, T: 'a>(item: T, identities: &'a [T::Identity]) -> Result<T, &'static str> where T: Simplifiable<'a> + Clone {
+use super::{Polarity, Simplifiable};
+use super::budget::{Budget, SearchConfig, SearchOutcome};
+use super::history::History;
+use super::regina::Span;
+use super::winston::{propagate_, Search};
 
-    let mut history = vec![];
-    let simplicity = item.simplicity();
+pub fn simplify<'a, T>(item: T, identities: &'a [T::Identity]) -> Result<T, &'static str> where T: 'a + Simplifiable<'a> + Clone {
+    simplify_with(item, identities, &NodeCount)
+}
+
+/// Simplify `item` as [`simplify`] does, but measuring how simple each form is with `model` rather than by counting
+/// its nodes.
+pub fn simplify_with<'a, T, C>(item: T, identities: &'a [T::Identity], model: &C) -> Result<T, &'static str>
+    where T: 'a + Simplifiable<'a> + Clone, C: CostModel<T::Leaf, T::Operator> {
+    simplify_within(item, identities, model, &SearchConfig::default()).map(|outcome| outcome.best)
+}
+
+/// Simplify `item` as [`simplify_with`] does, but stop once `config` runs out, returning the simplest item found so
+/// far and why the search stopped.
+pub fn simplify_within<'a, T, C>(item: T, identities: &'a [T::Identity], model: &C, config: &SearchConfig) -> Result<SearchOutcome<T>, &'static str>
+    where T: 'a + Simplifiable<'a> + Clone, C: CostModel<T::Leaf, T::Operator> {
+
+    let mut history = History::default();
+    history.visit(&item)?;
+    let mut budget = Budget::new(config);
+    let cost = model.cost(item.nodes());
+    let index = T::index(identities)?;
+    let search = Search {
+        index: &index,
+        model,
+        polarity: Polarity::Simplify,
+        max_strand_depth: config.max_strand_depth,
+    };
 
     let mut simpler = propagate_(
         item,
-        identities, 1,
+        &search,
         &mut history,
-        3,
-        0,
-        simplicity,
-    );
+        &mut budget,
+        cost,
+    )?;
+
+    simpler.sort_by(|a, b| model.compare(a.nodes(), b.nodes()));
+
+    Ok(SearchOutcome {
+        best: simpler.swap_remove(0),
+        status: budget.status(),
+    })
+}
+
+/// Simplify `item` with a breadth first search, which keeps only the `beam` cheapest strands by `model` at each depth
+/// rather than following each strand as far as it goes like [`simplify`] does.
+///
+/// The search stops once `config` runs out, returning the simplest item found so far.
+pub fn simplify_breadth_first<'a, T, C>(item: T, identities: &'a [T::Identity], beam: usize, model: &C, config: &SearchConfig) -> Result<SearchOutcome<T>, &'static str>
+    where T: 'a + Simplifiable<'a> + Clone, C: CostModel<T::Leaf, T::Operator> {
 
-    simpler.sort_by(|a, b|a.simplicity().cmp(&b.simplicity()));
+    let index = T::index(identities)?;
+    let span = Span::init(item, &index, model, Polarity::Simplify, beam, config)?
+        .saturate()?
+        .simplest()
+        .trim();
 
-    return Ok((simpler.first().unwrap()).clone())
+    // Strands are only ever replaced by the strands they propagate to, so there is always at least one
+    Ok(SearchOutcome {
+        best: span[0].current.clone(),
+        status: span.status(),
+    })
 }
 
 // fn find_most_alike_original<'a>(strands: Vec<Strand<'a>>, original: &'a Expression) -> Vec<Strand<'a>> {
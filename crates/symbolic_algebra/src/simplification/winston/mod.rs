@@ -1,89 +1,131 @@
-use super::Simplifiable;
+use std::rc::Rc;
+
+use solar_bt::CostModel;
+
+use super::{Polarity, Simplifiable};
+use super::budget::Budget;
+use super::history::History;
 use log::{debug, info};
 
-pub enum Polarity {
-    Simplify,
-    Expand,
+/// What a depth first search is after, which stays the same however deep it goes.
+pub struct Search<'s, I, C> {
+    pub index: &'s I,
+    pub model: &'s C,
+    pub polarity: Polarity,
+    /// The number of steps a strand may take without improving on the best it has found
+    pub max_strand_depth: usize,
 }
 
-/// A depth first algorithm for propagating strands - be warned, this is a heavily recursive function.
-pub fn propagate_<'a, T: 'a>(
+/// A strand partway through being propagated: the items it rewrites to which are still to be tried, and where it stands
+/// against the simplest item along it.
+struct Frame<T> {
     strand: T,
-    identities: &'a [T::Identity],
-    polarity: isize,
-    history: &mut Vec<Vec<u8>>,
-    max_strand_depth: usize,
+    /// Each rewritten item with the identity which gave it, in the order they are to be tried
+    next: std::vec::IntoIter<(Rc<str>, T)>,
+    depth: usize,
     depth_since_last_simplest: usize,
     last_simplest: usize,
-) -> Vec<T> where T: Simplifiable<'a> {
+    /// Whether any of the rewritten items has been propagated, in which case they take the place of this strand
+    propagated: bool,
+}
+
+impl<'a, T: 'a> Frame<T> where T: Simplifiable<'a> {
+
+    fn new<C>(strand: T, search: &Search<T::Index, C>, depth: usize, depth_since_last_simplest: usize, last_simplest: usize) -> Result<Self, &'static str>
+        where C: CostModel<T::Leaf, T::Operator> {
+
+        // Only the identities which can apply somewhere are tried
+        let manipulations = strand.try_manipulate_indexed(search.index)?;
+
+        let mut next = vec![];
+        for (identity, mut new_strands_with_instructions) in manipulations {
+            let iden_string: Rc<str> = identity.to_string().into();
+
+            new_strands_with_instructions.sort_by(
+                |a, b| match search.polarity {
+                    Polarity::Simplify => search.model.compare(a.0.nodes(), b.0.nodes()),
+                    Polarity::Expand => search.model.compare(b.0.nodes(), a.0.nodes()),
+                });
+            next.extend(new_strands_with_instructions.into_iter().map(|(new_strand, _)| (Rc::clone(&iden_string), new_strand)));
+        }
+
+        Ok(Self {
+            strand,
+            next: next.into_iter(),
+            depth,
+            depth_since_last_simplest,
+            last_simplest,
+            propagated: false,
+        })
+    }
+}
+
+/// A depth first algorithm for propagating strands, returning the items each strand ends at.
+///
+/// The strands are followed with an explicit stack rather than by recursing, so however deep the search goes it can't
+/// overflow the call stack. Fails if an identity can't be applied to some item along the way.
+pub fn propagate_<'a, T, C>(
+    strand: T,
+    search: &Search<T::Index, C>,
+    history: &mut History,
+    budget: &mut Budget,
+    last_simplest: usize,
+) -> Result<Vec<T>, &'static str> where T: 'a + Simplifiable<'a>, C: CostModel<T::Leaf, T::Operator> {
 
     let mut final_strands: Vec<T> = vec![];
+    let mut stack: Vec<Frame<T>> = vec![];
 
-    for identity in identities {
-        let iden_string: String = identity.to_string();
-        let left_string: String = strand.to_string();
-
-        // info!(
-        //     "Trying {}",
-        //     iden_string,
-        // );
-
-        let mut new_strands_with_instructions = strand.try_manipulate(&identity).expect("Error unwrapping strands");
-        new_strands_with_instructions.sort_by(
-            |a, b|{
-                (a.0.simplicity() as isize).cmp(&(polarity * b.0.simplicity() as isize))
-            });
-        
-        for (new_strand, _) in new_strands_with_instructions {
-            let right_string: String = new_strand.to_string();
-
-            if (&history).contains(&new_strand.uuid()) {
-                continue
-            }
-            info!(
-                "{} => {} = {}",
-                iden_string, left_string, right_string
-            );
-
-            history.push(new_strand.uuid());
-
-            // Should we ditch the current strand?
-            if new_strand.simplicity() as isize > polarity * (last_simplest as isize)
-                && depth_since_last_simplest > max_strand_depth {
-                info!("Simplicity depth reached");
-                continue
+    match budget.explore(0) {
+        true => stack.push(Frame::new(strand, search, 0, 0, last_simplest)?),
+        false => final_strands.push(strand),
+    }
+
+    while let Some(frame) = stack.last_mut() {
+        let Some((iden_string, new_strand)) = frame.next.next() else {
+            // Base case
+            let frame = stack.pop().expect("The stack has a last frame");
+            if !frame.propagated {
+                final_strands.push(frame.strand);
             }
+            continue
+        };
 
-            let (new_depth_since_last_simplest, new_last_simplest) =
-                match (new_strand.simplicity() as isize) < polarity * (last_simplest as isize) {
-                    true => {
-                        info!("Simpler");
-                        (0, new_strand.simplicity())
-                    },
-                    false => (depth_since_last_simplest + 1, last_simplest),
-                };
-            
-            debug!("Propagating");
-            let propagated = propagate_(
-                new_strand,
-                identities,
-                polarity,
-                history,
-                max_strand_depth,
-                new_depth_since_last_simplest,
-                new_last_simplest,
-            );
-
-            final_strands.extend(propagated);
+        if !history.visit(&new_strand)? {
+            continue
+        }
+        info!(
+            "{} => {} = {}",
+            iden_string, frame.strand.to_string(), new_strand.to_string()
+        );
+
+        let cost = search.model.cost(new_strand.nodes());
+
+        // Should we ditch the current strand?
+        if search.polarity.prefers(frame.last_simplest, cost)
+            && frame.depth_since_last_simplest > search.max_strand_depth {
+            info!("Simplicity depth reached");
+            continue
         }
-    }
 
-    // Base case
-    if final_strands.len() == 0 {
-        return vec![strand]
-    }else{
-        return final_strands
+        let (new_depth_since_last_simplest, new_last_simplest) =
+            match search.polarity.prefers(cost, frame.last_simplest) {
+                true => {
+                    info!("Simpler");
+                    (0, cost)
+                },
+                false => (frame.depth_since_last_simplest + 1, frame.last_simplest),
+            };
+
+        debug!("Propagating");
+        frame.propagated = true;
+        let depth = frame.depth + 1;
+        match budget.explore(depth) {
+            true => stack.push(Frame::new(new_strand, search, depth, new_depth_since_last_simplest, new_last_simplest)?),
+            false => final_strands.push(new_strand),
+        }
     }
+
+    Ok(final_strands)
 }
 
 // fn should_propagate_strand<'a, T>(
@@ -1,8 +1,11 @@
 use std::ops::Deref;
 
+use solar_bt::{Theory, ReplacementError};
+
 use super::identity::Identity;
 
 /// Utility type. An algebra is a set of two-way identities.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Algebra(Vec<Identity>);
 
 impl Algebra {
@@ -10,6 +13,31 @@ impl Algebra {
     pub fn new(identities: Vec<Identity>) -> Self {
         Self(identities)
     }
+
+    /// Match every identity in the algebra modulo `theory`.
+    pub fn modulo(self, theory: Theory) -> Self {
+        Self(self.0.into_iter().map(|identity| identity.modulo(theory.clone())).collect())
+    }
+
+    /// Drop every identity which another identity in the algebra subsumes, keeping the first of any duplicates.
+    pub fn prune(self) -> Self {
+        let identities = self.0;
+        let redundant: Vec<bool> = identities.iter().enumerate().map(|(j, specific)| {
+            identities.iter().enumerate().any(|(i, general)| {
+                i != j && general.subsumes(specific) && (i < j || !specific.subsumes(general))
+            })
+        }).collect();
+
+        Self(identities.into_iter().zip(redundant).filter(|(_, redundant)| !redundant).map(|(identity, _)| identity).collect())
+    }
+
+    /// Compile every identity up front, rather than on first use, so that a bad identity is reported straight away.
+    pub fn compile(&self) -> Result<(), ReplacementError> {
+        for identity in self.0.iter() {
+            identity.rule()?;
+        }
+        Ok(())
+    }
 }
 
 impl Deref for Algebra {
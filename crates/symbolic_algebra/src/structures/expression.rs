@@ -1,5 +1,7 @@
-use solar_bt::{Tree, Node, try_replace_identity, replace_identity, TreeNode, ReplacementError};
-use crate::manipulation::{Manipulatable};
+use std::fmt;
+
+use solar_bt::{Tree, Node, ReplacementError, Location, DiscriminationTree, HashCons, TermId};
+use crate::manipulation::{Manipulatable, Manipulations, IndexedManipulations};
 use crate::simplification::Simplifiable;
 
 use super::nodes::{Element, BinaryOperator};
@@ -9,55 +11,119 @@ use super::token_tree::TokenTree;
 
 pub type Expression = TokenTree<Element, BinaryOperator>;
 
+#[derive(Debug)]
 pub enum ExpressionManipulationError {
-    Error,
+    /// The identity couldn't be compiled, or the expression or location it was applied at isn't valid
+    Replacement(ReplacementError),
+}
+
+impl ExpressionManipulationError {
+
+    /// A summary of the error, for the parts of the manipulation API which report errors as static strings.
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Self::Replacement(ReplacementError::UnknownWildcard(_)) => "Identity uses a wildcard which is not bound by its pattern",
+            Self::Replacement(ReplacementError::WildcardKindMismatch(_)) => "Identity uses a wildcard as both an operator and a subtree",
+            Self::Replacement(ReplacementError::InvalidTree(_)) => "Expression is not a well formed tree",
+            Self::Replacement(ReplacementError::InvalidLocation(_)) => "Location does not point to a subtree of the expression",
+            Self::Replacement(ReplacementError::InvalidPattern(_) | ReplacementError::InvalidReplacement(_)) => "Identity is not a pair of well formed trees",
+            Self::Replacement(ReplacementError::UnsupportedSequence(_)) => "Identity uses a sequence wildcard, which saturation can't match",
+            Self::Replacement(ReplacementError::Regex(_) | ReplacementError::RegexBackend(_)) => "Unable to apply identity",
+        }
+    }
+}
+
+impl fmt::Display for ExpressionManipulationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Replacement(e) => write!(f, "{}: {e}", self.describe()),
+        }
+    }
+}
+
+impl std::error::Error for ExpressionManipulationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Replacement(e) => Some(e),
+        }
+    }
 }
 
 impl From<ReplacementError> for ExpressionManipulationError {
-    fn from(_: ReplacementError) -> Self {
-        Self::Error
+    fn from(e: ReplacementError) -> Self {
+        Self::Replacement(e)
+    }
+}
+
+impl From<ExpressionManipulationError> for &'static str {
+    fn from(e: ExpressionManipulationError) -> Self {
+        e.describe()
     }
 }
 
 impl<'a> Manipulatable<'a> for Expression {
     type Identity = Identity;
-    type Instruction = (usize, &'a Identity);
+    type Instruction = (Location, &'a Identity);
     type Error = ExpressionManipulationError;
+    /// Each identity alongside its position in the slice the index was built from
+    type Index = DiscriminationTree<(usize, &'a Identity)>;
 
     fn manipulate(&self, instruction: &'a Self::Instruction) -> Result<Option<Self>, Self::Error> {
-        let maybe_result = replace_identity(
-            self,
-            instruction.1.into(),
-            instruction.0,
-        )?;
-        
-        Ok(maybe_result)
+        let rule = instruction.1.rule()?;
+
+        Ok(rule.apply_at(self, &instruction.0)?)
     }
 
     fn try_manipulate(&self, identity: &'a Self::Identity) -> Result<Vec<(Self, Self::Instruction)>, &'static str> {
-        let results = try_replace_identity(self, identity.into()).unwrap();
+        let rule = identity.rule().map_err(ExpressionManipulationError::from)?;
+        let results = rule.apply_all(self).map_err(ExpressionManipulationError::from)?;
 
         Ok(
-            results.into_iter().map(|(expression, position)| (expression, (position, identity))).collect()
+            results.into_iter().map(|(expression, location)| (expression, (location, identity))).collect()
         )
     }
+
+    fn index(identities: &'a [Self::Identity]) -> Result<Self::Index, &'static str> {
+        let mut index = DiscriminationTree::new();
+        for (i, identity) in identities.iter().enumerate() {
+            index.insert_rule(identity.rule().map_err(ExpressionManipulationError::from)?, (i, identity));
+        }
+        Ok(index)
+    }
+
+    fn try_manipulate_indexed(&self, index: &Self::Index) -> Result<IndexedManipulations<'a, Self>, &'static str> {
+        let mut candidates = index.candidates(self);
+        // Group by identity, keeping the locations of each in pre-order as `try_manipulate` would
+        candidates.sort_by_key(|((i, _), location)| (*i, location.index));
+
+        let mut results: Vec<(usize, &'a Identity, Manipulations<'a, Self>)> = vec![];
+        for (&(i, identity), location) in candidates {
+            let rewritten = identity.rule().map_err(ExpressionManipulationError::from)?.apply_each_at(self, &location).map_err(ExpressionManipulationError::from)?;
+            if rewritten.is_empty() {
+                continue
+            }
+
+            if results.last().map(|(last, _, _)| *last) != Some(i) {
+                results.push((i, identity, vec![]));
+            }
+            if let Some((_, _, manipulations)) = results.last_mut() {
+                manipulations.extend(rewritten.into_iter().map(|expression| (expression, (location.clone(), identity))));
+            }
+        }
+        Ok(results.into_iter().map(|(_, identity, manipulations)| (identity, manipulations)).collect())
+    }
 }
 
 impl<'a> Simplifiable<'a> for Expression {
-    fn simplicity(&self) -> usize {
-        self.tokens().len()
+    type Leaf = Element;
+    type Operator = BinaryOperator;
+
+    fn nodes(&self) -> &[Node<Element, BinaryOperator>] {
+        self.tokens()
     }
 
-    fn uuid(&self) -> Vec<u8> {
-        self.tokens().iter()
-        .map(|token| {
-            match token {
-                Node::Internal(x) => x.to_string().as_bytes().to_owned(),
-                Node::Leaf(x) => x.to_string().as_bytes().to_owned(),
-            }
-        })
-        .flatten()
-        .collect()
+    fn term_id(&self, terms: &mut HashCons) -> Result<TermId, &'static str> {
+        terms.intern(self).map_err(|e| ExpressionManipulationError::from(ReplacementError::InvalidTree(e)).describe())
     }
 }
 
@@ -120,7 +186,9 @@ mod test_macros {
 #[cfg(test)]
 mod test_manipulation {
 
-    use crate::structures::{Element, BinaryOperator, Identity, Expression};
+    use solar_bt::{Condition, Location, Node, ReplacementError, Tree};
+    use crate::structures::{Element, BinaryOperator, Identity, Expression, Algebra};
+    use super::ExpressionManipulationError;
     use crate::manipulation::Manipulatable;
 
     use crate::structures::identity::identity_expression;
@@ -151,7 +219,7 @@ mod test_manipulation {
             c.clone(),
         ];
 
-        let identity = Identity (
+        let identity = Identity::new(
             identity_expression![
                 plus,
                 times,
@@ -225,7 +293,7 @@ mod test_manipulation {
             d.clone(),
         ];
 
-        let identity = Identity (
+        let identity = Identity::new(
             identity_expression![
                 times,
                 PatternLeaf::Subtree("x".to_owned()),
@@ -248,16 +316,199 @@ mod test_manipulation {
 
         assert!(try_simplified.contains(&simplified));
     }
+
+    #[test]
+    fn test_manipulation_with_compiled_algebra() {
+        let plus: BinaryOperator = BinaryOperator::new(b'+');
+        let times: BinaryOperator = BinaryOperator::new(b'*');
+        let a: Element = Element::new(b"a");
+        let b: Element = Element::new(b"b");
+
+        let commutativity = |operator: BinaryOperator| Identity::new(
+            identity_expression![
+                operator,
+                PatternLeaf::Subtree("x".to_owned()),
+                PatternLeaf::Subtree("y".to_owned()),
+            ],
+            identity_expression![
+                operator,
+                PatternLeaf::Subtree("y".to_owned()),
+                PatternLeaf::Subtree("x".to_owned()),
+            ],
+        );
+
+        let algebra = Algebra::new(vec![commutativity(plus), commutativity(times)]);
+        algebra.compile().unwrap();
+
+        // a+(a*b)
+        let expression = expression![plus, a.clone(), times, a.clone(), b.clone()];
+
+        let first = expression.try_manipulate(&algebra[0]).unwrap();
+        let second = expression.try_manipulate(&algebra[0]).unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].0, second[0].0);
+        assert_eq!(first[0].0, expression![plus, times, a.clone(), b.clone(), a.clone()]);
+
+        // The reported location replays the same manipulation
+        let (manipulated, instruction) = &expression.try_manipulate(&algebra[1]).unwrap()[0];
+        assert_eq!(instruction.0.path, vec![1]);
+        assert_eq!(expression.manipulate(instruction).ok().flatten().as_ref(), Some(manipulated));
+        assert_eq!(*manipulated, expression![plus, a.clone(), times, b.clone(), a.clone()]);
+
+        // A replacement using a wildcard the pattern doesn't bind fails to compile
+        let unbound = Identity::new(
+            identity_expression![plus, PatternLeaf::Subtree("x".to_owned()), PatternLeaf::Subtree("y".to_owned())],
+            identity_expression![PatternLeaf::Subtree("z".to_owned())],
+        );
+        assert_eq!(expression.try_manipulate(&unbound).err(), Some("Identity uses a wildcard which is not bound by its pattern"));
+        let instruction = (Location::root(), &unbound);
+        let error = expression.manipulate(&instruction).err().unwrap();
+        assert!(matches!(&error, ExpressionManipulationError::Replacement(ReplacementError::UnknownWildcard(name)) if name == "z"));
+        assert_eq!(error.to_string(), "Identity uses a wildcard which is not bound by its pattern: wildcard `z` is not bound by the pattern");
+        assert!(Algebra::new(vec![unbound]).compile().is_err());
+    }
+
+    #[test]
+    fn test_prune_subsumed_identities() {
+        let plus: BinaryOperator = BinaryOperator::new(b'+');
+        let times: BinaryOperator = BinaryOperator::new(b'*');
+        let a: Element = Element::new(b"a");
+
+        let commutativity = |x: &str, y: &str| Identity::new(
+            identity_expression![
+                times,
+                PatternLeaf::Subtree(x.to_owned()),
+                PatternLeaf::Subtree(y.to_owned()),
+            ],
+            identity_expression![
+                times,
+                PatternLeaf::Subtree(y.to_owned()),
+                PatternLeaf::Subtree(x.to_owned()),
+            ],
+        );
+        // ay = ya
+        let commutativity_with_a = Identity::new(
+            identity_expression![times, PatternLeaf::Literal(a.clone()), PatternLeaf::Subtree("y".to_owned())],
+            identity_expression![times, PatternLeaf::Subtree("y".to_owned()), PatternLeaf::Literal(a.clone())],
+        );
+        // x+y = y+x isn't an instance of anything else
+        let commutativity_addition = Identity::new(
+            identity_expression![plus, PatternLeaf::Subtree("x".to_owned()), PatternLeaf::Subtree("y".to_owned())],
+            identity_expression![plus, PatternLeaf::Subtree("y".to_owned()), PatternLeaf::Subtree("x".to_owned())],
+        );
+        // ay = a isn't an instance of commutativity, despite the left sides matching
+        let absorbing = Identity::new(
+            identity_expression![times, PatternLeaf::Literal(a.clone()), PatternLeaf::Subtree("y".to_owned())],
+            identity_expression![PatternLeaf::Literal(a.clone())],
+        );
+
+        let algebra = Algebra::new(vec![
+            commutativity_with_a,
+            commutativity("x", "y"),
+            commutativity_addition,
+            commutativity("u", "v"),
+            absorbing,
+        ]).prune();
+
+        assert_eq!(algebra.len(), 3);
+        assert_eq!(algebra[0].to_string(), commutativity("x", "y").to_string());
+        assert!(algebra[1].left().tokens().contains(&Node::Internal(plus)));
+        assert_eq!(algebra[2].right().tokens().len(), 1);
+    }
+
+    #[test]
+    fn test_indexed_manipulation_matches_trying_each_identity() {
+        let plus: BinaryOperator = BinaryOperator::new(b'+');
+        let times: BinaryOperator = BinaryOperator::new(b'*');
+        let minus: BinaryOperator = BinaryOperator::new(b'-');
+        let a: Element = Element::new(b"a");
+        let b: Element = Element::new(b"b");
+
+        let commutativity = |operator: BinaryOperator| Identity::new(
+            identity_expression![
+                operator,
+                PatternLeaf::Subtree("x".to_owned()),
+                PatternLeaf::Subtree("y".to_owned()),
+            ],
+            identity_expression![
+                operator,
+                PatternLeaf::Subtree("y".to_owned()),
+                PatternLeaf::Subtree("x".to_owned()),
+            ],
+        );
+        // x+x = 2x, which the index can't rule out by shape alone
+        let doubling = Identity::new(
+            identity_expression![plus, PatternLeaf::Subtree("x".to_owned()), PatternLeaf::Subtree("x".to_owned())],
+            identity_expression![times, PatternLeaf::Literal(Element::new(b"2")), PatternLeaf::Subtree("x".to_owned())],
+        );
+
+        let identities = vec![commutativity(minus), commutativity(plus), doubling, commutativity(times)];
+        let index = Expression::index(&identities).unwrap();
+
+        // (a*b)+(b*a)
+        let expression = expression![plus, times, a.clone(), b.clone(), times, b.clone(), a.clone()];
+
+        let indexed = expression.try_manipulate_indexed(&index).unwrap();
+        let each: Vec<_> = identities.iter()
+            .map(|identity| (identity, expression.try_manipulate(identity).unwrap()))
+            .filter(|(_, manipulations)| !manipulations.is_empty())
+            .collect();
+
+        assert_eq!(indexed.len(), 2);
+        assert_eq!(indexed.len(), each.len());
+        for ((identity, manipulations), (expected_identity, expected)) in indexed.iter().zip(each.iter()) {
+            assert!(std::ptr::eq(*identity, *expected_identity));
+            let expressions = |manipulations: &Vec<(Expression, _)>| manipulations.iter().map(|(e, _)| e.clone()).collect::<Vec<_>>();
+            assert_eq!(expressions(manipulations), expressions(expected));
+        }
+    }
+
+    #[test]
+    fn test_guarded_manipulation() {
+        let plus: BinaryOperator = BinaryOperator::new(b'+');
+        let a: Element = Element::new(b"a");
+        let b: Element = Element::new(b"b");
+
+        // x+y = y+x, only when y < x
+        let ordered_commutativity = Identity::new(
+            identity_expression![
+                plus,
+                PatternLeaf::Subtree("x".to_owned()),
+                PatternLeaf::Subtree("y".to_owned()),
+            ],
+            identity_expression![
+                plus,
+                PatternLeaf::Subtree("y".to_owned()),
+                PatternLeaf::Subtree("x".to_owned()),
+            ],
+        ).when(Condition::Ordered("y".to_owned(), "x".to_owned()));
+
+        let sorted = expression![plus, a.clone(), b.clone()];
+        let unsorted = expression![plus, b.clone(), a.clone()];
+
+        assert!(sorted.try_manipulate(&ordered_commutativity).unwrap().is_empty());
+
+        let manipulated: Vec<Expression> = unsorted
+            .try_manipulate(&ordered_commutativity)
+            .unwrap()
+            .into_iter()
+            .map(|(expression, _)| expression)
+            .collect();
+        assert_eq!(manipulated, vec![sorted]);
+    }
 }
 
 
 #[cfg(test)]
 mod test_simplification {
     
-    use crate::structures::{Element, BinaryOperator, Identity};
-    use crate::simplification::simplify;
+    use solar_bt::{Theory, Limits, Node, NodeCount, Tree};
+    use crate::structures::{Element, BinaryOperator, Identity, Expression};
+    use crate::simplification::{simplify, simplify_within, simplify_breadth_first, simplify_best_first, saturate, expand, EditDistanceTo};
+    use crate::simplification::{SearchConfig, SearchStatus, CancellationToken};
 
     use crate::structures::identity::identity_expression;
+    use crate::test_utils::{multiplication_over_addition, commutativity_multiplication};
 
     fn init() {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -272,7 +523,7 @@ mod test_simplification {
         let b: Element = Element::new(b"b");
         let c: Element = Element::new(b"c");
 
-        let multiplication_over_addition = Identity (
+        let multiplication_over_addition = Identity::new(
             identity_expression![
                 plus,
                 times,
@@ -324,7 +575,7 @@ mod test_simplification {
         let c: Element = Element::new(b"c");
         let d: Element = Element::new(b"d");
 
-        let multiplication_over_addition = Identity (
+        let multiplication_over_addition = Identity::new(
             identity_expression![
                 plus,
                 times,
@@ -343,7 +594,7 @@ mod test_simplification {
             ],
         );
 
-        let commutativity_multiplication = Identity (
+        let commutativity_multiplication = Identity::new(
             identity_expression![
                 times,
                 PatternLeaf::Subtree("x".to_owned()),
@@ -394,6 +645,367 @@ mod test_simplification {
         assert_eq!(simplified, expected_simplified);
     }
 
+    #[test]
+    fn test_saturate_factorises_double_bracket() {
+        let plus: BinaryOperator = BinaryOperator::new(b'+');
+        let times: BinaryOperator = BinaryOperator::new(b'*');
+        let a: Element = Element::new(b"a");
+        let b: Element = Element::new(b"b");
+        let c: Element = Element::new(b"c");
+        let d: Element = Element::new(b"d");
+
+        let multiplication_over_addition = multiplication_over_addition();
+
+        let commutativity_multiplication = commutativity_multiplication();
+
+        // ac + ad + bc + bd, with the products written every which way
+        let expression = expression![
+            plus,
+            plus,
+            times,
+            c.clone(),
+            a.clone(),
+            times,
+            a.clone(),
+            d.clone(),
+            plus,
+            times,
+            b.clone(),
+            c.clone(),
+            times,
+            d.clone(),
+            b.clone(),
+        ];
+
+        let simplified = saturate(
+            &expression,
+            &[multiplication_over_addition, commutativity_multiplication],
+            &Limits::default(),
+        ).unwrap();
+
+        // (a+b)(c+d) and (c+d)(a+b) are equally simple
+        let candidates = [
+            expression![times, plus, a.clone(), b.clone(), plus, c.clone(), d.clone()],
+            expression![times, plus, c.clone(), d.clone(), plus, a.clone(), b.clone()],
+        ];
+        assert!(candidates.contains(&simplified));
+    }
+
+    #[test]
+    fn test_breadth_first_factorises_double_bracket() {
+        let plus: BinaryOperator = BinaryOperator::new(b'+');
+        let times: BinaryOperator = BinaryOperator::new(b'*');
+        let a: Element = Element::new(b"a");
+        let b: Element = Element::new(b"b");
+        let c: Element = Element::new(b"c");
+        let d: Element = Element::new(b"d");
+
+        let multiplication_over_addition = multiplication_over_addition();
+
+        let commutativity_multiplication = commutativity_multiplication();
+
+        // ac + ad + bc + bd, with the products written every which way
+        let expression = expression![
+            plus,
+            plus,
+            times,
+            c.clone(),
+            a.clone(),
+            times,
+            a.clone(),
+            d.clone(),
+            plus,
+            times,
+            b.clone(),
+            c.clone(),
+            times,
+            d.clone(),
+            b.clone(),
+        ];
+
+        let identities = [multiplication_over_addition, commutativity_multiplication];
+        let outcome = simplify_breadth_first(expression.clone(), &identities, 16, &NodeCount, &SearchConfig::default()).unwrap();
+        assert_eq!(outcome.status, SearchStatus::Complete);
+
+        // (a+b)(c+d) and (c+d)(a+b) are equally simple
+        let candidates = [
+            expression![times, plus, a.clone(), b.clone(), plus, c.clone(), d.clone()],
+            expression![times, plus, c.clone(), d.clone(), plus, a.clone(), b.clone()],
+        ];
+        assert!(candidates.contains(&outcome.best));
+
+        let cancellation = CancellationToken::new();
+        let config = SearchConfig { cancellation: Some(cancellation.clone()), ..SearchConfig::default() };
+        cancellation.cancel();
+        let outcome = simplify_breadth_first(expression.clone(), &identities, 16, &NodeCount, &config).unwrap();
+        assert_eq!(outcome.status, SearchStatus::Cancelled);
+        assert_eq!(outcome.best, expression);
+
+        let config = SearchConfig { deadline: Some(std::time::Instant::now()), ..SearchConfig::default() };
+        let outcome = simplify_breadth_first(expression.clone(), &identities, 16, &NodeCount, &config).unwrap();
+        assert_eq!(outcome.status, SearchStatus::Deadline);
+        assert_eq!(outcome.best, expression);
+    }
+
+    #[test]
+    fn test_best_first_factorises_double_bracket() {
+        let plus: BinaryOperator = BinaryOperator::new(b'+');
+        let times: BinaryOperator = BinaryOperator::new(b'*');
+        let a: Element = Element::new(b"a");
+        let b: Element = Element::new(b"b");
+        let c: Element = Element::new(b"c");
+        let d: Element = Element::new(b"d");
+
+        let multiplication_over_addition = multiplication_over_addition();
+
+        let commutativity_multiplication = commutativity_multiplication();
+        let identities = [multiplication_over_addition, commutativity_multiplication];
+
+        // ac + ad + bc + bd, with the products written every which way
+        let expression = expression![
+            plus,
+            plus,
+            times,
+            c.clone(),
+            a.clone(),
+            times,
+            a.clone(),
+            d.clone(),
+            plus,
+            times,
+            b.clone(),
+            c.clone(),
+            times,
+            d.clone(),
+            b.clone(),
+        ];
+
+        // With no heuristic the search is ordered by simplicity alone
+        let config = SearchConfig::default();
+        let outcome = simplify_best_first(expression.clone(), &identities, &|_: &Expression| 0, &NodeCount, &config).unwrap();
+        assert_eq!(outcome.status, SearchStatus::Complete);
+        let candidates = [
+            expression![times, plus, a.clone(), b.clone(), plus, c.clone(), d.clone()],
+            expression![times, plus, c.clone(), d.clone(), plus, a.clone(), b.clone()],
+        ];
+        assert!(candidates.contains(&outcome.best));
+
+        // Aiming for one of the two gets exactly that one
+        for target in candidates {
+            let outcome = simplify_best_first(expression.clone(), &identities, &EditDistanceTo(target.clone()), &NodeCount, &config).unwrap();
+            assert_eq!(outcome.best, target);
+        }
+
+        // Only the original expression is ever expanded within a budget of one state
+        let config = SearchConfig { max_states: 1, ..SearchConfig::default() };
+        let outcome = simplify_best_first(expression.clone(), &identities, &|_: &Expression| 0, &NodeCount, &config).unwrap();
+        assert_eq!(outcome.status, SearchStatus::StateLimit);
+        assert!(!outcome.is_optimal());
+
+        let cancellation = CancellationToken::new();
+        let config = SearchConfig { cancellation: Some(cancellation.clone()), ..SearchConfig::default() };
+        cancellation.cancel();
+        let outcome = simplify_best_first(expression.clone(), &identities, &|_: &Expression| 0, &NodeCount, &config).unwrap();
+        assert_eq!(outcome.status, SearchStatus::Cancelled);
+        assert_eq!(outcome.best, expression);
+
+        let config = SearchConfig { deadline: Some(std::time::Instant::now()), ..SearchConfig::default() };
+        let outcome = simplify_best_first(expression.clone(), &identities, &|_: &Expression| 0, &NodeCount, &config).unwrap();
+        assert_eq!(outcome.status, SearchStatus::Deadline);
+        assert_eq!(outcome.best, expression);
+    }
+
+    #[test]
+    fn test_expand_double_bracket() {
+        let plus: BinaryOperator = BinaryOperator::new(b'+');
+        let times: BinaryOperator = BinaryOperator::new(b'*');
+        let a: Element = Element::new(b"a");
+        let b: Element = Element::new(b"b");
+        let c: Element = Element::new(b"c");
+        let d: Element = Element::new(b"d");
+
+        let left_distributivity = Identity::new(
+            identity_expression![
+                times,
+                PatternLeaf::Subtree("x".to_owned()),
+                plus,
+                PatternLeaf::Subtree("y".to_owned()),
+                PatternLeaf::Subtree("z".to_owned()),
+            ],
+            identity_expression![
+                plus,
+                times,
+                PatternLeaf::Subtree("x".to_owned()),
+                PatternLeaf::Subtree("y".to_owned()),
+                times,
+                PatternLeaf::Subtree("x".to_owned()),
+                PatternLeaf::Subtree("z".to_owned()),
+            ],
+        );
+
+        let right_distributivity = Identity::new(
+            identity_expression![
+                times,
+                plus,
+                PatternLeaf::Subtree("y".to_owned()),
+                PatternLeaf::Subtree("z".to_owned()),
+                PatternLeaf::Subtree("x".to_owned()),
+            ],
+            identity_expression![
+                plus,
+                times,
+                PatternLeaf::Subtree("y".to_owned()),
+                PatternLeaf::Subtree("x".to_owned()),
+                times,
+                PatternLeaf::Subtree("z".to_owned()),
+                PatternLeaf::Subtree("x".to_owned()),
+            ],
+        );
+
+        // (a+b)(c+d)
+        let expression = expression![times, plus, a.clone(), b.clone(), plus, c.clone(), d.clone()];
+
+        let identities = [left_distributivity, right_distributivity];
+        let outcome = expand(expression.clone(), &identities, 16, &NodeCount, &SearchConfig::default()).unwrap();
+        assert!(outcome.is_optimal());
+        let expanded = outcome.best;
+
+        // ac + ad + bc + bd, in whichever order the brackets were multiplied out
+        assert_eq!(expanded.tokens().len(), 15);
+        assert_eq!(expanded.tokens().iter().filter(|token| matches!(token, Node::Internal(operator) if *operator == times)).count(), 4);
+
+        // With only one step allowed, the best so far is only half expanded
+        let config = SearchConfig { max_depth: 1, ..SearchConfig::default() };
+        let outcome = expand(expression, &identities, 16, &NodeCount, &config).unwrap();
+        assert_eq!(outcome.status, SearchStatus::DepthLimit);
+        assert_eq!(outcome.best.tokens().len(), 11);
+    }
+
+    #[test]
+    fn test_simplify_within_budget() {
+        let plus: BinaryOperator = BinaryOperator::new(b'+');
+        let times: BinaryOperator = BinaryOperator::new(b'*');
+        let a: Element = Element::new(b"a");
+        let b: Element = Element::new(b"b");
+        let c: Element = Element::new(b"c");
+
+        let multiplication_over_addition = multiplication_over_addition();
+        let identities = [multiplication_over_addition];
+
+        // ab + ac
+        let expression = expression![plus, times, a.clone(), b.clone(), times, a.clone(), c.clone()];
+        let factorised = expression![times, a.clone(), plus, b.clone(), c.clone()];
+
+        let outcome = simplify_within(expression.clone(), &identities, &NodeCount, &SearchConfig::default()).unwrap();
+        assert_eq!(outcome.status, SearchStatus::Complete);
+        assert_eq!(outcome.best, factorised);
+
+        // Expanding the original expression uses up the whole budget
+        let config = SearchConfig { max_states: 1, ..SearchConfig::default() };
+        let outcome = simplify_within(expression.clone(), &identities, &NodeCount, &config).unwrap();
+        assert_eq!(outcome.status, SearchStatus::StateLimit);
+        assert_eq!(outcome.best, factorised);
+
+        let cancellation = CancellationToken::new();
+        let config = SearchConfig { cancellation: Some(cancellation.clone()), ..SearchConfig::default() };
+        cancellation.cancel();
+        let outcome = simplify_within(expression.clone(), &identities, &NodeCount, &config).unwrap();
+        assert_eq!(outcome.status, SearchStatus::Cancelled);
+        assert!(!outcome.is_optimal());
+        assert_eq!(outcome.best, expression);
+
+        let config = SearchConfig { deadline: Some(std::time::Instant::now()), ..SearchConfig::default() };
+        let outcome = simplify_within(expression.clone(), &identities, &NodeCount, &config).unwrap();
+        assert_eq!(outcome.status, SearchStatus::Deadline);
+        assert_eq!(outcome.best, expression);
+    }
+
+    #[test]
+    fn test_malformed_expressions_are_errors() {
+        let plus: BinaryOperator = BinaryOperator::new(b'+');
+        let a: Element = Element::new(b"a");
+        let identities = [multiplication_over_addition()];
+
+        // A sum with only one argument
+        let malformed = Expression::new(vec![Node::Internal(plus), Node::Leaf(a)]);
+
+        assert!(simplify(malformed.clone(), &identities).is_err());
+        assert!(simplify_breadth_first(malformed.clone(), &identities, 4, &NodeCount, &SearchConfig::default()).is_err());
+        assert!(simplify_best_first(malformed.clone(), &identities, &|_: &Expression| 0, &NodeCount, &SearchConfig::default()).is_err());
+        assert!(expand(malformed, &identities, 4, &NodeCount, &SearchConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_simplify_deep_search() {
+        let plus: BinaryOperator = BinaryOperator::new(b'+');
+        let a: Element = Element::new(b"a");
+        let zero: Element = Element::new(b"0");
+
+        let additive_identity = Identity::new(
+            identity_expression![plus, PatternLeaf::Subtree("x".to_owned()), PatternLeaf::Literal(zero.clone())],
+            identity_expression![PatternLeaf::Subtree("x".to_owned())],
+        );
+
+        // (((a + 0) + 0) + ...) + 0, which takes a rewrite per zero to simplify
+        let zeros = 100;
+        let mut tokens = vec![Node::Internal(plus); zeros];
+        tokens.push(Node::Leaf(a.clone()));
+        tokens.extend(vec![Node::Leaf(zero); zeros]);
+
+        // The search goes as deep as there are zeros, even on a small stack
+        let outcome = std::thread::Builder::new()
+            .stack_size(64 * 1024)
+            .spawn(move || {
+                let config = SearchConfig { max_depth: usize::MAX, ..SearchConfig::default() };
+                simplify_within(Expression::new(tokens), &[additive_identity], &NodeCount, &config).unwrap()
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(outcome.status, SearchStatus::Complete);
+        assert_eq!(outcome.best, expression![a]);
+    }
+
+    #[test]
+    fn test_factorise_double_bracket_modulo_commutativity() {
+        let plus: BinaryOperator = BinaryOperator::new(b'+');
+        let times: BinaryOperator = BinaryOperator::new(b'*');
+        let a: Element = Element::new(b"a");
+        let b: Element = Element::new(b"b");
+        let c: Element = Element::new(b"c");
+        let d: Element = Element::new(b"d");
+
+        // No commutativity identity, multiplication is declared commutative instead
+        let multiplication_over_addition = multiplication_over_addition().modulo(Theory::new().commutative(&times));
+
+        let expression = expression![
+            plus,
+            plus,
+            times,
+            a.clone(),
+            c.clone(),
+            times,
+            a.clone(),
+            d.clone(),
+            plus,
+            times,
+            b.clone(),
+            c.clone(),
+            times,
+            b.clone(),
+            d.clone(),
+        ];
+
+        let simplified = simplify(expression, &[multiplication_over_addition]).unwrap();
+
+        // (a+b)(c+d) and (c+d)(a+b) are equally simple
+        let candidates = [
+            expression![times, plus, a.clone(), b.clone(), plus, c.clone(), d.clone()],
+            expression![times, plus, c.clone(), d.clone(), plus, a.clone(), b.clone()],
+        ];
+        assert!(candidates.contains(&simplified));
+    }
+
     #[test]
     fn test_factorise_triple_bracket() {
         init();
@@ -406,7 +1018,7 @@ mod test_simplification {
         let e: Element = Element::new(b"e");
         let f: Element = Element::new(b"f");
 
-        let multiplication_over_addition = Identity (
+        let multiplication_over_addition = Identity::new(
             identity_expression![
                 plus,
                 times,
@@ -425,7 +1037,7 @@ mod test_simplification {
             ],
         );
 
-        let multiplication_over_addition_2 = Identity (
+        let multiplication_over_addition_2 = Identity::new(
             identity_expression![
                 plus,
                 times,
@@ -530,7 +1142,7 @@ mod test_simplification {
         let e: Element = Element::new(b"e");
 
         // xy + xz = x(y+z)
-        let multiplication_over_addition = Identity (
+        let multiplication_over_addition = Identity::new(
             identity_expression![
                 plus,
                 times,
@@ -550,7 +1162,7 @@ mod test_simplification {
         );
 
         // ab = d
-        let ab_to_d = Identity(
+        let ab_to_d = Identity::new(
             identity_expression![
                 times,
                 PatternLeaf::Literal(a.clone()),
@@ -562,7 +1174,7 @@ mod test_simplification {
         );
 
         // ac = e
-        let ac_to_e = Identity(
+        let ac_to_e = Identity::new(
             identity_expression![
                 times,
                 PatternLeaf::Literal(a.clone()),
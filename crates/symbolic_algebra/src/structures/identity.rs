@@ -1,4 +1,6 @@
-use solar_bt::{Node, PatternLeaf};
+use std::sync::OnceLock;
+
+use solar_bt::{Node, PatternLeaf, Theory, Guard, Condition, Rule, ReplacementError, Tree, specialisation};
 use super::nodes::BinaryOperator;
 use super::nodes::Element;
 
@@ -16,33 +18,132 @@ impl<Idx> std::ops::Index<Idx> for ExpressionPattern where Idx: std::slice::Slic
 
 /// An identity contains two patterns, one is the matcher and the other the replacement, respectively.
 ///
-/// Identities are assumed to be reversible.
+/// Identities are assumed to be reversible. The patterns are matched modulo the identity's theory, which is empty
+/// (purely syntactic) unless set with [`Identity::modulo`], and only applied where the guard holds.
+///
+/// The identity is compiled into a [`Rule`] the first time it's applied, and the rule is reused from then on.
 #[derive(Debug, Clone)]
-pub struct Identity(pub ExpressionPattern, pub ExpressionPattern);
+pub struct Identity {
+    left: ExpressionPattern,
+    right: ExpressionPattern,
+    theory: Theory,
+    guard: Guard<Element, BinaryOperator>,
+    rule: OnceLock<Result<Rule<Element, BinaryOperator>, ReplacementError>>,
+}
 
 impl Identity {
+    pub fn new(left: ExpressionPattern, right: ExpressionPattern) -> Self {
+        Self::from_parts(left, right, Theory::new(), Guard::new())
+    }
+
+    fn from_parts(left: ExpressionPattern, right: ExpressionPattern, theory: Theory, guard: Guard<Element, BinaryOperator>) -> Self {
+        Self {
+            left,
+            right,
+            theory,
+            guard,
+            rule: OnceLock::new(),
+        }
+    }
+
+    /// Match this identity modulo the associativity and commutativity declared in `theory`.
+    pub fn modulo(self, theory: Theory) -> Self {
+        Self::from_parts(self.left, self.right, theory, self.guard)
+    }
+
+    /// Only apply this identity where `condition` holds for the wildcards it binds.
+    pub fn when(self, condition: Condition<Element, BinaryOperator>) -> Self {
+        Self::from_parts(self.left, self.right, self.theory, self.guard.when(condition))
+    }
+
+    /// The guard is kept as is, so it should only refer to wildcards which both sides bind.
     pub fn invert(&self) -> Self {
-        Identity(self.1.clone(), self.0.clone())
+        Self::from_parts(self.right.clone(), self.left.clone(), self.theory.clone(), self.guard.clone())
+    }
+
+    pub fn left(&self) -> &ExpressionPattern {
+        &self.left
+    }
+
+    pub fn right(&self) -> &ExpressionPattern {
+        &self.right
+    }
+
+    pub fn theory(&self) -> &Theory {
+        &self.theory
+    }
+
+    pub fn guard(&self) -> &Guard<Element, BinaryOperator> {
+        &self.guard
+    }
+
+    /// True if this identity makes `other` redundant: `other` is an instance of it, obtained by substituting for its
+    /// wildcards on both sides at once. Guarded identities only ever subsume themselves.
+    pub fn subsumes(&self, other: &Identity) -> bool {
+        if self.theory != other.theory || !self.guard.is_empty() || !other.guard.is_empty() {
+            return false;
+        }
+
+        match specialisation(&self.left, &other.left) {
+            Some(unifier) => unifier.apply(&self.right).tokens() == other.right.tokens(),
+            None => false,
+        }
+    }
+
+    /// The compiled rule rewriting the left side to the right, compiling it if this is the first use.
+    pub fn rule(&self) -> Result<&Rule<Element, BinaryOperator>, ReplacementError> {
+        self.rule
+            .get_or_init(|| Rule::compile_with(self.left.clone(), self.right.clone(), self.theory.clone(), self.guard.clone()))
+            .as_ref()
+            .map_err(Clone::clone)
+    }
+}
+
+/// The parts of an identity which are stored when it's serialized.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerializedIdentity<P, T> {
+    left: P,
+    right: P,
+    theory: T,
+}
+
+/// Guards can hold arbitrary predicates, so only unguarded identities can be serialized.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Identity {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !self.guard.is_empty() {
+            return Err(serde::ser::Error::custom("guarded identities can't be serialized"));
+        }
+        SerializedIdentity { left: &self.left, right: &self.right, theory: &self.theory }.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Identity {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let SerializedIdentity { left, right, theory } = SerializedIdentity::<ExpressionPattern, Theory>::deserialize(deserializer)?;
+        Ok(Self::from_parts(left, right, theory, Guard::new()))
     }
 }
 
 impl ToString for Identity {
     fn to_string(&self) -> String {
-        let left: String = self.0.to_string();
-        let right: String = self.1.to_string();
+        let left: String = self.left.to_string();
+        let right: String = self.right.to_string();
         format!("{} = {}", left, right)
     }
 }
 
 impl Into<(ExpressionPattern, ExpressionPattern)> for Identity {
     fn into(self) -> (ExpressionPattern, ExpressionPattern) {
-        (self.0, self.1)
+        (self.left, self.right)
     }
 }
 
 impl<'a> Into<(&'a ExpressionPattern, &'a ExpressionPattern)> for &'a Identity {
     fn into(self) -> (&'a ExpressionPattern, &'a ExpressionPattern) {
-        (&self.0, &self.1)
+        (&self.left, &self.right)
     }
 }
 
@@ -102,4 +203,32 @@ mod test_macros {
             Node::Leaf(PatternLeaf::Subtree(b)),
         ]);
     }
-}
\ No newline at end of file
+}
+#[cfg(all(test, feature = "serde"))]
+mod test_serde {
+
+    use crate::structures::{Algebra, BinaryOperator, Identity};
+    use solar_bt::{Condition, Theory};
+
+    #[test]
+    fn test_identity_round_trip() {
+        let plus = BinaryOperator::new(b'+');
+        let commutativity = Identity::new(
+            identity_expression![plus, PatternLeaf::Subtree("x".to_string()), PatternLeaf::Literal(Element::new(b"0"))],
+            identity_expression![PatternLeaf::Subtree("x".to_string())],
+        ).modulo(Theory::new().associative(&plus).commutative(&plus));
+
+        let json = serde_json::to_string(&commutativity).unwrap();
+        let loaded: Identity = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.left(), commutativity.left());
+        assert_eq!(loaded.right(), commutativity.right());
+        assert_eq!(loaded.theory(), commutativity.theory());
+        assert!(loaded.rule().is_ok());
+
+        let algebra: Algebra = serde_json::from_str(&serde_json::to_string(&Algebra::new(vec![commutativity.clone()])).unwrap()).unwrap();
+        assert_eq!(algebra.len(), 1);
+
+        let guarded = commutativity.when(Condition::IsLeaf("x".to_string()));
+        assert!(serde_json::to_string(&guarded).is_err());
+    }
+}
@@ -1,6 +1,7 @@
 use solar_bt::{TreeNode, ParsableTreeNode};
 
 #[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Element {
     pub label: Vec<u8>
 }
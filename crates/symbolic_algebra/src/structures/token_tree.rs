@@ -5,6 +5,7 @@ use solar_bt::{Tree, Node, TreeNode};
 ///
 /// Implements the tree trait in SOLaRBT for pattern matching.
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TokenTree<L: TreeNode, I: TreeNode> {
     pub tokens: Vec<Node<L, I>>,
 }
@@ -0,0 +1,43 @@
+use crate::structures::{BinaryOperator, Identity};
+use crate::identity_expression;
+
+/// xy + xz = x(y+z)
+pub fn multiplication_over_addition() -> Identity {
+    let plus = BinaryOperator::new(b'+');
+    let times = BinaryOperator::new(b'*');
+    Identity::new(
+        identity_expression![
+            plus,
+            times,
+            PatternLeaf::Subtree("x".to_owned()),
+            PatternLeaf::Subtree("y".to_owned()),
+            times,
+            PatternLeaf::Subtree("x".to_owned()),
+            PatternLeaf::Subtree("z".to_owned()),
+        ],
+        identity_expression![
+            times,
+            PatternLeaf::Subtree("x".to_owned()),
+            plus,
+            PatternLeaf::Subtree("y".to_owned()),
+            PatternLeaf::Subtree("z".to_owned()),
+        ],
+    )
+}
+
+/// xy = yx
+pub fn commutativity_multiplication() -> Identity {
+    let times = BinaryOperator::new(b'*');
+    Identity::new(
+        identity_expression![
+            times,
+            PatternLeaf::Subtree("x".to_owned()),
+            PatternLeaf::Subtree("y".to_owned()),
+        ],
+        identity_expression![
+            times,
+            PatternLeaf::Subtree("y".to_owned()),
+            PatternLeaf::Subtree("x".to_owned()),
+        ],
+    )
+}